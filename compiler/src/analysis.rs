@@ -1,6 +1,9 @@
+use std::collections::HashMap;
 use std::fmt;
 
-use crate::isa::{Inst, Label, Operand};
+use crate::elf::{Section, Symbol};
+use crate::error::Error;
+use crate::isa::{Inst, Label, Opcode, Operand};
 
 
 #[derive(Debug, Clone, PartialEq, Hash, Eq)]
@@ -20,13 +23,15 @@ impl fmt::Display for Dep {
 pub struct DepInst {
     pub inst: Inst,
     pub false_deps: Vec<Dep>,
+    // earlier loads/stores that may touch the same memory, reg is their base register
+    pub mem_deps: Vec<Dep>,
     pub src1: Option<Dep>,
     pub src2: Option<Dep>
 }
 
 impl DepInst {
-    pub fn all_deps<'a>(&'a self) -> Vec<&'a Dep> {
-        let mut deps: Vec<&'a Dep> = Vec::new();
+    pub fn all_deps(&self) -> Vec<&Dep> {
+        let mut deps: Vec<&Dep> = Vec::new();
         if let Some(src1) = &self.src1 {
             deps.push(src1);
         }
@@ -36,6 +41,9 @@ impl DepInst {
         for dep in self.false_deps.iter() {
             deps.push(dep);
         }
+        for dep in self.mem_deps.iter() {
+            deps.push(dep);
+        }
         deps
     }
 }
@@ -49,6 +57,10 @@ impl fmt::Display for DepInst {
         for false_dep in self.false_deps.iter() {
             write!(f, "{} ", false_dep)?;
         }
+        write!(f, "; MD: ")?;
+        for mem_dep in self.mem_deps.iter() {
+            write!(f, "{} ", mem_dep)?;
+        }
         write!(f, "; TD: ")?;
         if let Some(src1) = &self.src1 {
             write!(f, "{} ", src1)?;
@@ -56,7 +68,7 @@ impl fmt::Display for DepInst {
         if let Some(src2) = &self.src2 {
             write!(f, "{} ", src2)?;
         }
-        write!(f, "]\n")
+        writeln!(f, "]")
     }
 }
 
@@ -79,14 +91,25 @@ impl fmt::Display for AnalyzedBasicBlock {
 
 }
 
-#[derive(Clone)]
+impl AnalyzedBasicBlock {
+    /// Source address of the first instruction.
+    pub fn addr(&self) -> usize {
+        self.insns.first().or(self.cf_insn.as_ref()).unwrap().inst.addr
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct AnalyzedProgram {
-    pub bbs: Vec<AnalyzedBasicBlock>
+    pub bbs: Vec<AnalyzedBasicBlock>,
+    /// Block starts reached through code pointers rather than a branch or jump
+    pub indirect_targets: Vec<usize>,
+    /// Profiled (taken, not taken) counts of conditional branches, by source address
+    pub branch_weights: HashMap<usize, (u64, u64)>,
 }
 
 impl fmt::Display for AnalyzedProgram {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for (i,bb) in (&self.bbs).iter().enumerate() {
+        for (i,bb) in self.bbs.iter().enumerate() {
             write!(f, "BasicBlock {}:\n{}", i, bb)?;
         }
         Ok(())
@@ -94,13 +117,95 @@ impl fmt::Display for AnalyzedProgram {
 }
 
 
-pub fn trace_to_basicblocks(trace: Vec<Inst>) -> Vec<Vec<Inst>> {
+/// Code addresses that escape into registers or memory and can be reached by a
+/// jalr: auipc/addi pairs computing a .text address, and the jump-table words
+/// that indirect jumps load from the data sections.
+#[derive(Debug, Default)]
+pub struct CodePointers {
+    pub targets: Vec<usize>,
+    // addresses of the data words holding a code address
+    pub data_words: Vec<usize>,
+}
+
+/// The address an auipc/addi pair computes, in the source address space, if both have immediates.
+pub fn auipc_target(auipc: &Inst, addi: &Inst) -> Option<i64> {
+    let (Operand::Immediate(hi), Operand::Immediate(lo)) = (auipc.src2, addi.src2) else { return None };
+    Some(auipc.addr as i64 + ((hi << 12) as i32) as i64 + lo)
+}
+
+fn read_word(sections: &[Section], addr: usize) -> Option<u32> {
+    sections.iter()
+        .find(|s| addr >= s.addr && addr + 4 <= s.addr + s.data.len())
+        .map(|s| {
+            let w = &s.data[addr - s.addr..addr - s.addr + 4];
+            u32::from_le_bytes([w[0], w[1], w[2], w[3]])
+        })
+}
+
+/// Where the data object starting at `addr` ends, as far as the symbols tell: its own size
+/// if the symbol has one, or the next symbol.
+fn object_end(symbols: &[Symbol], addr: usize) -> usize {
+    let sized = symbols.iter().find(|s| s.addr == addr && s.size > 0).map(|s| addr + s.size);
+    let next = symbols.iter().map(|s| s.addr).filter(|a| *a > addr).min();
+    sized.or(next).unwrap_or(usize::MAX)
+}
+
+pub fn find_code_pointers(trace: &[Inst], sections: &[Section], symbols: &[Symbol]) -> CodePointers {
+    let text_end = trace.len() * 4;
+    let is_code = |addr: i64| addr >= 0 && (addr as usize) < text_end && addr % 4 == 0;
+    let mut pointers = CodePointers::default();
+    for (i, pair) in trace.windows(2).enumerate() {
+        if pair[0].opcode != Opcode::AUIPC || pair[1].opcode != Opcode::ADDI {
+            continue;
+        }
+        let Some(target) = auipc_target(&pair[0], &pair[1]) else { continue };
+        if is_code(target) {
+            pointers.targets.push(target as usize);
+            continue;
+        }
+        // a data address feeding a jalr in the same block is a jump table (or an array
+        // of function pointers): every following word of the object that looks like code
+        // is an entry
+        let feeds_jalr = trace[i..].iter()
+            .find(|inst| inst.opcode.is_control_flow())
+            .is_some_and(|inst| inst.opcode == Opcode::JALR);
+        if !feeds_jalr || target < 0 {
+            continue;
+        }
+        let mut addr = target as usize;
+        let end = object_end(symbols, addr);
+        while let Some(word) = read_word(sections, addr).filter(|w| addr + 4 <= end && is_code(*w as i64)) {
+            pointers.data_words.push(addr);
+            pointers.targets.push(word as usize);
+            addr += 4;
+        }
+    }
+    pointers.targets.sort();
+    pointers.targets.dedup();
+    pointers
+}
+
+/// Splits the trace at branch targets, after control flow and at `extra_starts`,
+/// the targets of indirect jumps. Err if a target isn't an instruction of the trace.
+pub fn trace_to_basicblocks(trace: Vec<Inst>, extra_starts: &[usize]) -> Result<Vec<Vec<Inst>>, Error> {
     let mut bb_starts: Vec<usize> = vec![0];
+    for start in extra_starts {
+        if *start >= trace.len()*4 || start % 4 != 0 {
+            return Err(Error::new(format!("Indirect jump target {:#x} is not an instruction", start)));
+        }
+        if !bb_starts.contains(start) {
+            bb_starts.push(*start);
+        }
+    }
     for inst in trace.iter() {
         if let Label::SrcAddrSpace(l) = inst.label {
             if inst.opcode.is_control_flow() {
-                assert!(l < trace.len()*4);
-                assert!(l % 4 == 0);
+                if l >= trace.len()*4 {
+                    return Err(Error::new(format!("Branch target {:#x} is past the end of the code", l)).at(inst));
+                }
+                if l % 4 != 0 {
+                    return Err(Error::new(format!("Branch target {:#x} is not word aligned", l)).at(inst));
+                }
                 if !bb_starts.contains(&l) {
                     bb_starts.push(l);
                 }
@@ -135,7 +240,7 @@ pub fn trace_to_basicblocks(trace: Vec<Inst>) -> Vec<Vec<Inst>> {
         }
     }
     assert!(bb_starts.is_empty());
-    bbs
+    Ok(bbs)
 }
 
 fn match_deps(new_da: &mut DepInst, old_inst: &Inst) {
@@ -156,40 +261,876 @@ fn match_deps(new_da: &mut DepInst, old_inst: &Inst) {
     // False dependency
     // We are writing to a location either used or written to by a previous instruction
     // With no register renaming, we must be careful to schedule this instruction after that one
-    if let Operand::Gpr(new_dest) = new_inst.dest {
-        let dep = Dep { addr: old_inst.addr, reg: new_dest };
-        if old_inst.src1.is_some() && new_dest == old_inst.src1.unwrap() {
-            new_da.false_deps.push(dep);
-        } else if let Operand::Gpr(src2) = old_inst.src2 {
-            if src2 == new_dest { new_da.false_deps.push(dep); }
-        } else if let Operand::Gpr(old_dest) = old_inst.dest {
-            if old_dest == new_dest { new_da.false_deps.push(dep); }
+    if let Some(new_dest) = reg_write(&new_inst) {
+        if reg_reads(old_inst).contains(&new_dest) || reg_write(old_inst) == Some(new_dest) {
+            new_da.false_deps.push(Dep { addr: old_inst.addr, reg: new_dest });
         }
     }
 }
 
-pub fn dep_analysis(basicblock: Vec<Inst>) -> AnalyzedBasicBlock {
+/// Registers an instruction reads, x0 excluded.
+pub fn reg_reads(inst: &Inst) -> Vec<u32> {
+    let mut regs = Vec::new();
+    if let Some(src1) = inst.src1 {
+        regs.push(src1);
+    }
+    if let Operand::Gpr(src2) = inst.src2 {
+        regs.push(src2);
+    }
+    regs.retain(|r| *r != 0);
+    regs
+}
+
+/// Register an instruction writes, x0 excluded.
+pub fn reg_write(inst: &Inst) -> Option<u32> {
+    match inst.dest {
+        Operand::Gpr(rd) if rd != 0 => Some(rd),
+        _ => None,
+    }
+}
+
+const STACK_POINTER: u32 = 2;
+
+fn is_mmio(addr: i64) -> bool {
+    (addr as u32) >> 29 == 7
+}
+
+/// What a block knows about the address a load or store touches.
+struct MemAccess {
+    addr: usize,
+    is_store: bool,
+    base: u32,
+    // bumped every time the base register is written, so equal versions mean equal base values
+    version: usize,
+    offset: i64,
+    width: i64,
+    // absolute address, when the base is a constant or a global (auipc) address
+    known: Option<i64>,
+}
+
+impl MemAccess {
+    fn new(inst: &Inst, versions: &HashMap<u32, usize>, values: &HashMap<u32, i64>) -> Option<Self> {
+        let (is_store, base, width) = match inst.opcode {
+            Opcode::LB | Opcode::LBU => (false, inst.src1?, 1),
+            Opcode::LH | Opcode::LHU => (false, inst.src1?, 2),
+            Opcode::LW => (false, inst.src1?, 4),
+            Opcode::SB => (true, inst.src2.unwrap_gpr(), 1),
+            Opcode::SH => (true, inst.src2.unwrap_gpr(), 2),
+            Opcode::SW => (true, inst.src2.unwrap_gpr(), 4),
+            // fences order everything, like an MMIO access
+            Opcode::FENCE => return Some(MemAccess {
+                addr: inst.addr, is_store: true, base: 0, version: 0, offset: 0, width: 0, known: Some(0xF000_0000),
+            }),
+            _ => return None,
+        };
+        let offset = inst.offset.unwrap_or(0);
+        Some(MemAccess {
+            addr: inst.addr,
+            is_store,
+            base,
+            version: versions.get(&base).copied().unwrap_or(0),
+            offset,
+            width,
+            known: values.get(&base).map(|v| v + offset),
+        })
+    }
+
+    fn may_alias(&self, other: &MemAccess) -> bool {
+        let overlap = |a: i64, b: i64| a < b + other.width && b < a + self.width;
+        if self.known.is_some_and(is_mmio) || other.known.is_some_and(is_mmio) {
+            return true;
+        }
+        match (self.known, other.known) {
+            (Some(a), Some(b)) => overlap(a, b),
+            // the stack never holds globals
+            (Some(_), None) => other.base != STACK_POINTER,
+            (None, Some(_)) => self.base != STACK_POINTER,
+            (None, None) if self.base == other.base && self.version == other.version => overlap(self.offset, other.offset),
+            (None, None) => true,
+        }
+    }
+}
+
+/// Tracks constant register values through a block, enough to resolve lui/auipc/addi
+/// address computations. Code addresses (labelled lui) are left unknown.
+fn track_value(inst: &Inst, values: &mut HashMap<u32, i64>) {
+    let Operand::Gpr(rd) = inst.dest else { return };
+    let imm = match inst.src2 { Operand::Immediate(i) => Some(i), _ => None };
+    let value = match (inst.opcode, inst.label, imm) {
+        (Opcode::LUI, Label::None, Some(i)) => Some(((i << 12) as i32) as i64),
+        (Opcode::AUIPC, _, Some(i)) => Some(inst.addr as i64 + ((i << 12) as i32) as i64),
+        (Opcode::ADDI, _, Some(i)) => inst.src1
+            .and_then(|rs| if rs == 0 { Some(0) } else { values.get(&rs).copied() })
+            .map(|v| v + i),
+        _ => None,
+    };
+    match value {
+        Some(v) if rd != 0 => { values.insert(rd, v); }
+        _ => { values.remove(&rd); }
+    }
+}
+
+/// Dependences of a straight-line run of instructions, control flow included, so a
+/// superblock can be analyzed as one sequence.
+pub fn dep_graph(insts: Vec<Inst>) -> Vec<DepInst> {
     let mut da_table: Vec<DepInst> = Vec::new();
-    let mut cf_insn: Option<DepInst> = None;
-    for inst in basicblock {
+    let mut mem_table: Vec<MemAccess> = Vec::new();
+    let mut versions: HashMap<u32, usize> = HashMap::new();
+    let mut values: HashMap<u32, i64> = HashMap::new();
+    for inst in insts {
         let mut dep_inst = DepInst {
-            inst: inst,
+            inst,
             false_deps: Vec::new(),
+            mem_deps: Vec::new(),
             src1: None,
             src2: None
         };
         for da_entry in da_table.iter() {
             match_deps(&mut dep_inst, &da_entry.inst);
         }
-
-        if inst.opcode.is_control_flow() {
-            cf_insn = Some(dep_inst);
-        } else {
-            da_table.push(dep_inst);
+        // store->load, load->store and store->store ordering
+        if let Some(access) = MemAccess::new(&inst, &versions, &values) {
+            for earlier in mem_table.iter() {
+                if (earlier.is_store || access.is_store) && access.may_alias(earlier) {
+                    dep_inst.mem_deps.push(Dep { addr: earlier.addr, reg: earlier.base });
+                }
+            }
+            mem_table.push(access);
         }
+        if let Operand::Gpr(rd) = inst.dest {
+            *versions.entry(rd).or_insert(0) += 1;
+        }
+        track_value(&inst, &mut values);
+        da_table.push(dep_inst);
     }
+    da_table
+}
+
+pub fn dep_analysis(basicblock: Vec<Inst>) -> AnalyzedBasicBlock {
+    let mut insns = dep_graph(basicblock);
+    // control flow only ever ends a block
+    let cf_insn = if insns.last().is_some_and(|i| i.inst.opcode.is_control_flow()) {
+        insns.pop()
+    } else {
+        None
+    };
     AnalyzedBasicBlock {
-        insns: da_table,
+        insns,
         cf_insn
     }
-}
\ No newline at end of file
+}
+
+pub const ALL_REGS: u32 = !1;
+/// Registers a call leaves alone: sp, gp, tp and s0-s11.
+const PRESERVED: u32 = (0b111 << 2) | (0b11 << 8) | (0x3ff << 18);
+/// What a callee may read: a0-a7 and the preserved registers.
+const CALL_LIVE: u32 = (0xff << 10) | PRESERVED;
+/// What the caller may still read after `ret`: a0-a1 and the preserved registers.
+const RETURN_LIVE: u32 = (0b11 << 10) | PRESERVED;
+
+/// How control gets from one block to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Fall,
+    Branch,
+    Jump,
+    // from a call to the block after it, once the callee returns
+    Return,
+}
+
+/// Where control goes after a block, by block index.
+pub struct Exits {
+    // next block in the layout, reached by falling through or returning from a call
+    pub fall: Option<usize>,
+    // branch or jump target
+    pub taken: Option<usize>,
+    pub conditional: bool,
+    // registers live when control leaves for code we can't see (calls, returns, indirect
+    // jumps, the end of the program)
+    pub escape: Option<u32>,
+    // ends in a call, which follows the ABI: only the preserved registers live through it
+    pub call: bool,
+}
+
+impl Exits {
+    pub fn successors(&self) -> impl Iterator<Item = (usize, Edge)> {
+        let fall = if self.call { Edge::Return } else { Edge::Fall };
+        let taken = if self.conditional { Edge::Branch } else { Edge::Jump };
+        self.fall.map(|f| (f, fall)).into_iter().chain(self.taken.map(|t| (t, taken)))
+    }
+}
+
+/// Control-flow graph of a program, by block index.
+pub struct Cfg {
+    pub exits: Vec<Exits>,
+    pub preds: Vec<Vec<(usize, Edge)>>,
+    // entered other than along an edge: the program entry, code pointer targets and callees
+    pub external: Vec<bool>,
+}
+
+impl Cfg {
+    pub fn new(prog: &AnalyzedProgram) -> Self {
+        let n = prog.bbs.len();
+        let index: HashMap<usize, usize> = prog.bbs.iter().enumerate().map(|(b, bb)| (bb.addr(), b)).collect();
+        let target = |inst: &Inst| match inst.label {
+            Label::SrcAddrSpace(l) => index.get(&l).copied(),
+            _ => None,
+        };
+        let exits: Vec<Exits> = prog.bbs.iter().enumerate().map(|(b, bb)| {
+            let next = (b + 1 < n).then_some(b + 1);
+            let (fall, taken, conditional, escape) = match bb.cf_insn.as_ref().map(|c| c.inst) {
+                None => (next, None, false, None),
+                Some(cf) if cf.opcode.inverted_branch().is_some() => (next, target(&cf), true, None),
+                Some(cf) if matches!(cf.opcode, Opcode::J | Opcode::JAL) && reg_write(&cf).is_none() => (None, target(&cf), false, None),
+                // calls come back to the next bundle
+                Some(cf) if reg_write(&cf).is_some() => (next, None, false, Some(CALL_LIVE)),
+                Some(cf) if matches!(cf.opcode, Opcode::ECALL | Opcode::EBREAK) => (next, None, false, Some(ALL_REGS)),
+                Some(cf) if cf.src1 == Some(1) => (None, None, false, Some(RETURN_LIVE)),
+                Some(_) => (None, None, false, Some(ALL_REGS)),
+            };
+            let call = bb.cf_insn.as_ref().is_some_and(|cf| reg_write(&cf.inst).is_some());
+            // falling off the end of the code
+            let escape = escape.or((next.is_none() && (bb.cf_insn.is_none() || conditional)).then_some(ALL_REGS));
+            Exits { fall, taken, conditional, escape, call }
+        }).collect();
+
+        let mut preds = vec![Vec::new(); n];
+        for (b, e) in exits.iter().enumerate() {
+            for (s, edge) in e.successors() {
+                preds[s].push((b, edge));
+            }
+        }
+
+        let mut external = vec![false; n];
+        if n > 0 {
+            external[0] = true;
+        }
+        let labels = prog.bbs.iter()
+            .flat_map(|bb| bb.insns.iter().chain(bb.cf_insn.as_ref()))
+            // branches and jumps are edges, pc-relative data pairs don't point at code
+            .filter(|i| !(i.inst.opcode.is_control_flow() && reg_write(&i.inst).is_none()) && i.inst.offset.is_none())
+            .filter_map(|i| match i.inst.label {
+                Label::SrcAddrSpace(l) => Some(l),
+                _ => None,
+            });
+        for addr in labels.chain(prog.indirect_targets.iter().copied()) {
+            if let Some(b) = index.get(&addr) {
+                external[*b] = true;
+            }
+        }
+        Cfg { exits, preds, external }
+    }
+
+    pub fn len(&self) -> usize {
+        self.exits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exits.is_empty()
+    }
+}
+
+/// A data-flow problem over a `Cfg`, solved by `solve`. Facts flow along edges and are
+/// combined with `meet`; blocks connected to code we can't see start from `boundary`.
+pub trait DataFlow {
+    type Fact: Clone + PartialEq;
+    /// Whether facts flow along edges, or against them.
+    const FORWARD: bool;
+
+    /// Initial fact of every block.
+    fn init(&self) -> Self::Fact;
+    /// What flows in from outside the graph: at external entries going forward, at escaping
+    /// exits going backward. None if nothing does.
+    fn boundary(&self, cfg: &Cfg, b: usize) -> Option<Self::Fact>;
+    fn meet(&self, a: &Self::Fact, b: &Self::Fact) -> Self::Fact;
+    /// Effect of block `b`, from the fact where the flow enters it to where it leaves.
+    fn transfer(&self, b: usize, fact: &Self::Fact) -> Self::Fact;
+    /// Effect of taking an edge.
+    fn edge(&self, _edge: Edge, fact: &Self::Fact) -> Self::Fact {
+        fact.clone()
+    }
+}
+
+/// Facts at the entry and exit of each block, in program order whatever the direction.
+pub struct Solution<F> {
+    pub entry: Vec<F>,
+    pub exit: Vec<F>,
+}
+
+/// Iterates `problem` over `cfg` to a fixed point.
+pub fn solve<D: DataFlow>(cfg: &Cfg, problem: &D) -> Solution<D::Fact> {
+    let n = cfg.len();
+    let forward = D::FORWARD;
+    // facts where each block is entered and left in the direction of the problem
+    let mut before = vec![problem.init(); n];
+    let mut after = vec![problem.init(); n];
+    let order: Vec<usize> = if forward { (0..n).collect() } else { (0..n).rev().collect() };
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in order.iter().copied() {
+            let incoming: Vec<(usize, Edge)> = if forward {
+                cfg.preds[b].clone()
+            } else {
+                cfg.exits[b].successors().collect()
+            };
+            let fact = incoming.iter()
+                .map(|(other, edge)| problem.edge(*edge, &after[*other]))
+                .chain(problem.boundary(cfg, b))
+                .reduce(|a, f| problem.meet(&a, &f))
+                .unwrap_or_else(|| problem.init());
+            let out = problem.transfer(b, &fact);
+            before[b] = fact;
+            if out != after[b] {
+                after[b] = out;
+                changed = true;
+            }
+        }
+    }
+    if forward {
+        Solution { entry: before, exit: after }
+    } else {
+        Solution { entry: after, exit: before }
+    }
+}
+
+/// Registers live at each block boundary, as bit masks.
+pub struct Liveness {
+    uses: Vec<u32>,
+    defs: Vec<u32>,
+}
+
+impl Liveness {
+    pub fn new(prog: &AnalyzedProgram) -> Self {
+        let (uses, defs) = prog.bbs.iter().map(|bb| {
+            let (mut uses, mut defs) = (0u32, 0u32);
+            for inst in bb.insns.iter().chain(bb.cf_insn.as_ref()) {
+                for r in reg_reads(&inst.inst) {
+                    uses |= (1 << r) & !defs;
+                }
+                if let Some(r) = reg_write(&inst.inst) {
+                    defs |= 1 << r;
+                }
+            }
+            (uses, defs)
+        }).unzip();
+        Liveness { uses, defs }
+    }
+}
+
+impl DataFlow for Liveness {
+    type Fact = u32;
+    const FORWARD: bool = false;
+
+    fn init(&self) -> u32 {
+        0
+    }
+
+    fn boundary(&self, cfg: &Cfg, b: usize) -> Option<u32> {
+        cfg.exits[b].escape
+    }
+
+    fn meet(&self, a: &u32, b: &u32) -> u32 {
+        a | b
+    }
+
+    fn transfer(&self, b: usize, live_out: &u32) -> u32 {
+        self.uses[b] | (live_out & !self.defs[b])
+    }
+
+    fn edge(&self, edge: Edge, live: &u32) -> u32 {
+        match edge {
+            Edge::Return => live & PRESERVED,
+            _ => *live,
+        }
+    }
+}
+
+/// Registers live on entry to (`entry`) and on the way out of (`exit`) each block.
+pub fn liveness(prog: &AnalyzedProgram, cfg: &Cfg) -> Solution<u32> {
+    solve(cfg, &Liveness::new(prog))
+}
+
+/// Registers live right after the instruction at `addr`, None if no block holds it.
+pub fn live_after(prog: &AnalyzedProgram, live: &Solution<u32>, addr: usize) -> Option<u32> {
+    prog.bbs.iter().enumerate().find_map(|(b, bb)| {
+        let insts: Vec<&Inst> = bb.insns.iter().chain(bb.cf_insn.iter()).map(|i| &i.inst).collect();
+        let at = insts.iter().position(|inst| inst.addr == addr)?;
+        Some(insts[at + 1..].iter().rev().fold(live.exit[b], |live, inst| {
+            let live = reg_write(inst).map_or(live, |r| live & !(1 << r));
+            reg_reads(inst).iter().fold(live, |live, r| live | (1 << r))
+        }))
+    })
+}
+
+/// Which blocks dominate each block, as a forward problem: everything, until a path from an
+/// external entry says otherwise.
+struct Dominance {
+    n: usize,
+}
+
+impl DataFlow for Dominance {
+    type Fact = Vec<bool>;
+    const FORWARD: bool = true;
+
+    fn init(&self) -> Vec<bool> {
+        vec![true; self.n]
+    }
+
+    fn boundary(&self, cfg: &Cfg, b: usize) -> Option<Vec<bool>> {
+        cfg.external[b].then(|| vec![false; self.n])
+    }
+
+    fn meet(&self, a: &Vec<bool>, b: &Vec<bool>) -> Vec<bool> {
+        a.iter().zip(b.iter()).map(|(a, b)| *a && *b).collect()
+    }
+
+    fn transfer(&self, b: usize, doms: &Vec<bool>) -> Vec<bool> {
+        let mut doms = doms.clone();
+        doms[b] = true;
+        doms
+    }
+}
+
+/// Dominator tree of a `Cfg`, rooted at its external entries.
+pub struct Dominators {
+    // None for roots and blocks no entry reaches
+    pub idom: Vec<Option<usize>>,
+}
+
+impl Dominators {
+    pub fn new(cfg: &Cfg) -> Self {
+        let n = cfg.len();
+        let mut reached = cfg.external.clone();
+        let mut work: Vec<usize> = (0..n).filter(|b| reached[*b]).collect();
+        while let Some(b) = work.pop() {
+            for (s, _) in cfg.exits[b].successors() {
+                if !reached[s] {
+                    reached[s] = true;
+                    work.push(s);
+                }
+            }
+        }
+        let doms = solve(cfg, &Dominance { n }).exit;
+        // strict dominators form a chain, the immediate one is dominated by all the others
+        let idom = (0..n).map(|b| {
+            if !reached[b] {
+                return None;
+            }
+            (0..n).filter(|d| *d != b && doms[b][*d])
+                .max_by_key(|d| doms[*d].iter().filter(|x| **x).count())
+        }).collect();
+        Dominators { idom }
+    }
+
+    /// Whether every path from an external entry to `b` passes through `a`.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        let mut cur = Some(b);
+        while let Some(c) = cur {
+            if c == a {
+                return true;
+            }
+            cur = self.idom[c];
+        }
+        false
+    }
+}
+
+/// A loop counted by a register stepped once per iteration and compared against a loop
+/// invariant bound by the branch back to the header.
+#[derive(Debug, Clone)]
+pub struct TripCount {
+    pub iv: u32,
+    pub step: i64,
+    // x0 for a comparison against zero
+    pub bound: u32,
+    // times the back branch is reached, when the start and the bound are known constants
+    pub count: Option<u64>,
+}
+
+impl fmt::Display for TripCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "x{} += {} against x{}", self.iv, self.step, self.bound)?;
+        match self.count {
+            Some(count) => write!(f, ", {} iterations", count),
+            None => write!(f, ", unknown iterations"),
+        }
+    }
+}
+
+/// A natural loop: the blocks that reach a back edge without passing through its header.
+/// Back edges sharing a header make one loop.
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub header: usize,
+    // sources of the back edges
+    pub latches: Vec<usize>,
+    // in layout order, header included
+    pub blocks: Vec<usize>,
+    // blocks outside the loop it branches, jumps or falls to
+    pub exits: Vec<usize>,
+    // 1 for an outermost loop
+    pub depth: usize,
+    pub trip_count: Option<TripCount>,
+}
+
+impl fmt::Display for Loop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "loop at block {}: {} blocks, depth {}, latches {:?}, exits {:?}",
+            self.header, self.blocks.len(), self.depth, self.latches, self.exits)?;
+        if let Some(tc) = &self.trip_count {
+            write!(f, ", {}", tc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds the natural loops of a program, by header.
+pub fn find_loops(prog: &AnalyzedProgram, cfg: &Cfg) -> Vec<Loop> {
+    let doms = Dominators::new(cfg);
+    let mut loops: Vec<Loop> = (0..cfg.len()).filter_map(|h| {
+        let latches: Vec<usize> = cfg.preds[h].iter()
+            .filter(|(p, edge)| *edge != Edge::Return && doms.dominates(h, *p))
+            .map(|(p, _)| *p)
+            .collect();
+        if latches.is_empty() {
+            return None;
+        }
+        let mut in_loop = vec![false; cfg.len()];
+        in_loop[h] = true;
+        let mut work = latches.clone();
+        while let Some(b) = work.pop() {
+            if !in_loop[b] {
+                in_loop[b] = true;
+                work.extend(cfg.preds[b].iter().map(|(p, _)| *p));
+            }
+        }
+        let blocks: Vec<usize> = (0..cfg.len()).filter(|b| in_loop[*b]).collect();
+        let mut exits: Vec<usize> = blocks.iter()
+            .flat_map(|b| cfg.exits[*b].successors())
+            .map(|(s, _)| s)
+            .filter(|s| !in_loop[*s])
+            .collect();
+        exits.sort();
+        exits.dedup();
+        Some(Loop { header: h, latches, blocks, exits, depth: 0, trip_count: None })
+    }).collect();
+    let nests: Vec<usize> = loops.iter()
+        .map(|l| loops.iter().filter(|outer| l.blocks.iter().all(|b| outer.blocks.contains(b))).count())
+        .collect();
+    for (l, depth) in loops.iter_mut().zip(nests) {
+        l.depth = depth;
+        l.trip_count = trip_count(prog, cfg, &doms, l);
+    }
+    loops
+}
+
+/// Recognizes a loop whose only latch ends in an `addi`-stepped register compared against an
+/// invariant one, the way GCC emits counted loops.
+fn trip_count(prog: &AnalyzedProgram, cfg: &Cfg, doms: &Dominators, l: &Loop) -> Option<TripCount> {
+    let [latch] = l.latches[..] else { return None };
+    if cfg.exits[latch].taken != Some(l.header) || !cfg.exits[latch].conditional {
+        return None;
+    }
+    let branch = prog.bbs[latch].cf_insn.as_ref()?.inst;
+    let insts = |b: usize| prog.bbs[b].insns.iter().chain(prog.bbs[b].cf_insn.as_ref()).map(|d| d.inst);
+    let writes = |r: u32| l.blocks.iter().flat_map(|b| insts(*b).map(move |i| (*b, i))).filter(move |(_, i)| reg_write(i) == Some(r));
+    // stepped exactly once, on every path through the loop
+    let step = |r: u32| {
+        let mut defs = writes(r);
+        match (defs.next(), defs.next()) {
+            (Some((b, i)), None) if i.opcode == Opcode::ADDI && i.src1 == Some(r) && doms.dominates(b, latch) => match i.src2 {
+                Operand::Immediate(step) => Some(step),
+                _ => None,
+            },
+            _ => None,
+        }
+    };
+    // branches are stored flipped, see parse_i_r_b_format_inst
+    let (lhs, rhs) = (branch.src2.unwrap_gpr(), branch.src1?);
+    // constants the latch sets up for its branch, like a bound reloaded every iteration
+    let mut at_branch = HashMap::new();
+    for inst in insts(latch) {
+        track_value(&inst, &mut at_branch);
+    }
+    let invariant = |r: u32| r == 0 || writes(r).next().is_none() || at_branch.contains_key(&r);
+    let (iv, bound, step, iv_lhs) = if let Some(s) = step(lhs).filter(|_| invariant(rhs)) {
+        (lhs, rhs, s, true)
+    } else {
+        (rhs, lhs, step(rhs).filter(|_| invariant(lhs))?, false)
+    };
+
+    // the start and the bound as left by the block entering the loop
+    let mut outside = cfg.preds[l.header].iter().filter(|(p, _)| !l.blocks.contains(p));
+    let count = match (outside.next(), outside.next()) {
+        (Some((pre, _)), None) => {
+            let mut values = HashMap::new();
+            for inst in insts(*pre) {
+                track_value(&inst, &mut values);
+            }
+            let value = |r: u32| if r == 0 { Some(0) } else { values.get(&r).copied() };
+            value(iv).zip(at_branch.get(&bound).copied().or(value(bound)))
+                .and_then(|(init, b)| iterations(branch.opcode, iv_lhs, init, b, step))
+        }
+        _ => None,
+    };
+    Some(TripCount { iv, step, bound, count })
+}
+
+/// Times a do-while loop runs when its induction register starts at `init`, is stepped before
+/// the test, and the loop goes on while `op` holds between it and `bound` (operands in that
+/// order if `iv_lhs`).
+fn iterations(op: Opcode, iv_lhs: bool, init: i64, bound: i64, step: i64) -> Option<u64> {
+    let unsigned = matches!(op, Opcode::BLTU | Opcode::BGEU | Opcode::BEQ | Opcode::BNE);
+    let (min, max) = if unsigned { (0, u32::MAX as i64) } else { (i32::MIN as i64, i32::MAX as i64) };
+    let wrap = |v: i64| if unsigned { v as u32 as i64 } else { v as i32 as i64 };
+    let (i, b) = (wrap(init), wrap(bound));
+    let stays = |v: i64| {
+        let (x, y) = if iv_lhs { (v, b) } else { (b, v) };
+        match op {
+            Opcode::BEQ => x == y,
+            Opcode::BNE => x != y,
+            Opcode::BLT | Opcode::BLTU => x < y,
+            _ => x >= y,
+        }
+    };
+    if !stays(wrap(i + step)) {
+        return Some(1);
+    }
+    let k = match (op, iv_lhs) {
+        // the register wraps around to the bound
+        (Opcode::BNE, _) => {
+            let d = if step > 0 { wrap(b - i) } else { wrap(i - b) };
+            let s = step.abs();
+            return (s != 0 && d % s == 0).then_some((d / s) as u64);
+        }
+        (Opcode::BEQ, _) => return None,
+        // counting up to the bound, or down to it
+        (Opcode::BLT | Opcode::BLTU, true) if step > 0 => (b - i + step - 1) / step,
+        (Opcode::BLT | Opcode::BLTU, false) if step < 0 => (i - b - step - 1) / -step,
+        (Opcode::BGE | Opcode::BGEU, true) if step < 0 => (i - b) / -step + 1,
+        (Opcode::BGE | Opcode::BGEU, false) if step > 0 => (b - i) / step + 1,
+        _ => return None,
+    };
+    let last = i + k * step;
+    (k >= 1 && (min..=max).contains(&last)).then_some(k as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dep_analysis, find_code_pointers, find_loops, iterations, live_after, liveness, solve, trace_to_basicblocks};
+    use super::{Cfg, DataFlow, Dominators, Edge};
+    use crate::isa::Opcode;
+    use crate::parser::parse_asm;
+    use crate::testutil::analyze;
+
+    const SRC: &str = "
+            beq  a0, x0, 1f
+            addi a1, a1, 1
+            call f
+            j    2f
+        1:  li   t0, 3
+        2:  add  a0, a1, s0
+            ret
+        f:  add  a0, a0, t1
+            ret
+    ";
+
+    /// Blocks reached from the program entry along edges.
+    struct Reachable;
+
+    impl DataFlow for Reachable {
+        type Fact = bool;
+        const FORWARD: bool = true;
+
+        fn init(&self) -> bool {
+            false
+        }
+
+        fn boundary(&self, _cfg: &Cfg, b: usize) -> Option<bool> {
+            (b == 0).then_some(true)
+        }
+
+        fn meet(&self, a: &bool, b: &bool) -> bool {
+            *a || *b
+        }
+
+        fn transfer(&self, _b: usize, reached: &bool) -> bool {
+            *reached
+        }
+    }
+
+    #[test]
+    fn test_cfg_edges() {
+        let prog = analyze(SRC);
+        let cfg = Cfg::new(&prog);
+        let succs: Vec<Vec<(usize, Edge)>> = cfg.exits.iter().map(|e| e.successors().collect()).collect();
+        assert_eq!(succs, [
+            vec![(1, Edge::Fall), (3, Edge::Branch)],
+            vec![(2, Edge::Return)],
+            vec![(4, Edge::Jump)],
+            vec![(4, Edge::Fall)],
+            vec![],
+            vec![],
+        ]);
+        assert_eq!(cfg.preds[4], [(2, Edge::Jump), (3, Edge::Fall)]);
+        // the callee is entered by the call, which isn't an edge
+        assert_eq!(cfg.external, [true, false, false, false, false, true]);
+        assert!(cfg.exits[4].escape.is_some() && cfg.exits[1].call);
+    }
+
+    #[test]
+    fn test_liveness() {
+        let prog = analyze(SRC);
+        let cfg = Cfg::new(&prog);
+        let live = liveness(&prog, &cfg);
+        let mask = |regs: &[u32]| regs.iter().fold(0u32, |m, r| m | (1 << r));
+        // a call reads the argument registers, not the temporaries its callee happens to read
+        assert_eq!(live.exit[1] & mask(&[5, 6, 10, 11]), mask(&[10, 11]));
+        assert_eq!(live.entry[2] & mask(&[8, 11]), mask(&[8, 11]));
+        // t0 is dead after its only def, s0 is read at the join
+        assert_eq!(live.exit[3] & mask(&[5, 8]), mask(&[8]));
+        assert_eq!(live.entry[0] & mask(&[5, 8, 10, 11]), mask(&[8, 10, 11]));
+        // the add at the join overwrites a0, ret reads it
+        assert_eq!(live_after(&prog, &live, 0x10).unwrap() & mask(&[10, 11]), mask(&[11]));
+        assert_eq!(live_after(&prog, &live, 0x14).unwrap() & mask(&[5, 10]), mask(&[10]));
+        assert!(live_after(&prog, &live, 0x100).is_none());
+    }
+
+    #[test]
+    fn test_forward_solve() {
+        let prog = analyze("
+                j    1f
+                li   a0, 1
+            1:  ret
+        ");
+        let cfg = Cfg::new(&prog);
+        let reached = solve(&cfg, &Reachable);
+        assert_eq!(reached.entry, [true, false, true]);
+        assert_eq!(reached.exit, [true, false, true]);
+    }
+
+    #[test]
+    fn test_jump_table_targets() {
+        let src = "
+        1:  auipc a5, %pcrel_hi(table)
+            addi  a5, a5, %pcrel_lo(1b)
+            lw    a5, 0(a5)
+            jr    a5
+        first:  li a0, 1
+        second: li a0, 2
+        2:  auipc a1, %pcrel_hi(third)
+            addi  a1, a1, %pcrel_lo(2b)
+        third:  ret
+            .section .rodata
+        table: .word first, second
+        size:  .word 3
+        ";
+        let (trace, image) = parse_asm(src).unwrap();
+        let pointers = find_code_pointers(&trace, &image.sections, &image.symbols);
+        assert_eq!(pointers.targets, [0x10, 0x14, 0x20]);
+        assert_eq!(pointers.data_words, [0x24, 0x28]);
+        let bbs = trace_to_basicblocks(trace, &pointers.targets).unwrap();
+        let lens: Vec<usize> = bbs.iter().map(|bb| bb.len()).collect();
+        assert_eq!(lens, [4, 1, 3, 1]);
+    }
+
+    #[test]
+    fn test_jump_table_end() {
+        // words after the table that happen to look like code addresses are data
+        let src = "
+        1:  auipc a5, %pcrel_hi(table)
+            addi  a5, a5, %pcrel_lo(1b)
+            lw    a5, 0(a5)
+            jr    a5
+        case0:  li a0, 1
+        case1:  li a0, 2
+            .section .rodata
+        table: .word case0, case1
+        arr:   .word 8, 12, 0
+        ";
+        let (trace, image) = parse_asm(src).unwrap();
+        let pointers = find_code_pointers(&trace, &image.sections, &image.symbols);
+        assert_eq!(pointers.targets, [0x10, 0x14]);
+        assert_eq!(pointers.data_words, [0x18, 0x1c]);
+        // or as far as the table's own size says
+        let mut symbols = image.symbols.clone();
+        symbols.retain(|s| s.name != "arr");
+        symbols.iter_mut().find(|s| s.name == "table").unwrap().size = 4;
+        let pointers = find_code_pointers(&trace, &image.sections, &symbols);
+        assert_eq!(pointers.data_words, [0x18]);
+    }
+
+    #[test]
+    fn test_memory_deps() {
+        let src = "
+            sw   a0, 0(a1)
+            lw   a2, 4(a1)
+            lw   a3, 0(a1)
+            sw   a0, 8(sp)
+            lui  t0, 0xf0010
+            sw   a0, -16(t0)
+        1:  auipc t1, %pcrel_hi(global)
+            addi t1, t1, %pcrel_lo(1b)
+            lw   a4, 0(t1)
+            addi a1, a1, 4
+            lw   a5, 0(a1)
+            .data
+        global: .word 0
+        ";
+        let (trace, _) = parse_asm(src).unwrap();
+        let bb = dep_analysis(trace);
+        let mem_deps = |addr: usize| -> Vec<usize> {
+            let inst = bb.insns.iter().find(|i| i.inst.addr == addr).unwrap();
+            inst.mem_deps.iter().map(|d| d.addr).collect()
+        };
+        // different offsets from the same base don't alias, the same offset does
+        assert_eq!(mem_deps(0x4), [0usize; 0]);
+        assert_eq!(mem_deps(0x8), [0x0]);
+        // unknown pointers may point into the stack
+        assert_eq!(mem_deps(0xc), [0x0, 0x4, 0x8]);
+        // MMIO is ordered with everything
+        assert_eq!(mem_deps(0x14), [0x0, 0x4, 0x8, 0xc]);
+        // globals don't alias the stack, but may alias other pointers
+        assert_eq!(mem_deps(0x20), [0x0, 0x14]);
+        // a1 was redefined, so its offsets can't be compared anymore
+        assert_eq!(mem_deps(0x28), [0x0, 0xc, 0x14]);
+    }
+
+    #[test]
+    fn test_loop_nest() {
+        let prog = analyze("
+                li   s0, 0
+            1:  li   s1, 10
+            2:  addi s1, s1, -1
+                bne  s1, x0, 2b
+                addi s0, s0, 1
+                li   a5, 4
+                blt  s0, a5, 1b
+                ret
+        ");
+        let cfg = Cfg::new(&prog);
+        let doms = Dominators::new(&cfg);
+        assert_eq!(doms.idom, [None, Some(0), Some(1), Some(2), Some(3)]);
+        assert!(doms.dominates(1, 3) && !doms.dominates(3, 1));
+
+        let loops = find_loops(&prog, &cfg);
+        let shape: Vec<_> = loops.iter().map(|l| (l.header, l.blocks.clone(), l.latches.clone(), l.exits.clone(), l.depth)).collect();
+        assert_eq!(shape, [(1, vec![1, 2, 3], vec![3], vec![4], 1), (2, vec![2], vec![2], vec![3], 2)]);
+        // the outer bound is reloaded by the latch, the inner loop counts down to zero
+        let counts: Vec<_> = loops.iter().map(|l| l.trip_count.as_ref().map(|t| (t.iv, t.step, t.bound, t.count))).collect();
+        assert_eq!(counts, [Some((8, 1, 15, Some(4))), Some((9, -1, 0, Some(10)))]);
+    }
+
+    #[test]
+    fn test_iterations() {
+        assert_eq!(iterations(Opcode::BLT, true, 0, 10, 3), Some(4));
+        assert_eq!(iterations(Opcode::BGE, true, 10, 0, -2), Some(6));
+        assert_eq!(iterations(Opcode::BLTU, false, 8, 0, -1), Some(8));
+        // the test fails the first time round
+        assert_eq!(iterations(Opcode::BLT, true, 0, 1, 5), Some(1));
+        // stepping past the bound of a bne never stops, counting away from a blt bound wraps
+        assert_eq!(iterations(Opcode::BNE, true, 0, 10, 3), None);
+        assert_eq!(iterations(Opcode::BLT, true, 0, 10, -1), None);
+    }
+}
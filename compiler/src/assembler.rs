@@ -1,100 +1,141 @@
-use crate::{analysis::{AnalyzedBasicBlock, AnalyzedProgram, DepInst}, isa::{Inst, InstParseFormat, Label, Opcode, Operand}, scheduling::{schedule_program, ScheduledProgram}};
+use std::collections::BTreeMap;
+
+use crate::{analysis::AnalyzedProgram, elf::Section, error::Error, isa::{Inst, InstParseFormat, Label, Opcode, Operand}, scheduling::ScheduledProgram};
+
+// the operands a format encodes, checked since passes build instructions too
+fn gpr(op: Operand, what: &str) -> Result<u32, String> {
+    match op {
+        Operand::Gpr(r) if r < 32 => Ok(r),
+        _ => Err(format!("{} should be a register, got {}", what, op)),
+    }
+}
+
+fn src1(inst: &Inst) -> Result<u32, String> {
+    inst.src1.ok_or_else(|| String::from("src1 is missing"))
+}
+
+/// `value` as a `bits` wide two's complement field.
+fn signed(value: i64, bits: u32, what: &str) -> Result<u32, String> {
+    let half = 1 << (bits - 1);
+    if (-half..half).contains(&value) {
+        Ok(value as u32 & ((1 << bits) - 1))
+    } else {
+        Err(format!("{} {} doesn't fit in {} bits", what, value, bits))
+    }
+}
+
+fn offset(inst: &Inst) -> Result<u32, String> {
+    let offset = inst.offset.ok_or_else(|| String::from("offset is missing"))?;
+    signed(offset, 12, "offset")
+}
 
 fn parse_i_format(inst: &Inst) -> Result<u32, String> {
     let mut word = 0x0;
     let Operand::Immediate(imm) = inst.src2 else {return Err(String::from("I format should have immediate in src2")); };
-    let imm = imm as i32;
-    
+    let imm = match inst.opcode {
+        Opcode::SLLI | Opcode::SRLI | Opcode::SRAI if !(0..32).contains(&imm) => return Err(format!("shift amount {} is not between 0 and 31", imm)),
+        Opcode::SLLI | Opcode::SRLI | Opcode::SRAI => imm as u32,
+        _ => signed(imm, 12, "immediate")?,
+    };
+
     word |= inst.opcode.opcode_bits();
-    word |= inst.dest.unwrap_gpr() << 7;
+    word |= gpr(inst.dest, "dest")? << 7;
     word |= inst.opcode.funct3() << 12;
-    word |= inst.src1.unwrap() << 15;
-    word |= (imm as u32) << 20;
+    word |= src1(inst)? << 15;
+    word |= imm << 20;
+    // srai keeps its shift type in the upper immediate bits
+    word |= inst.opcode.funct7() << 25;
     Ok(word)
 }
 
 fn bits(word: u32, start: u32, end: u32) -> u32 {
-    return (word & ((1 << (end+1)) - 1)) >> start
+    (word & ((1 << (end+1)) - 1)) >> start
 }
 
-fn get_offset_from_label(label: &Label, addr: usize) -> Result<i32,String> {
-    match label {
-        Label::DstAddrSpace(label) => {
-            Ok(((*label as i32) - (addr as i32)))
-        }
-        Label::SrcAddrSpace(label) => {
-            Ok((*label as i32) - (addr as i32))
-        }
-        _ => Err(String::from("jump instruction should have address in destination address space"))
+/// Distance from `addr` to the label of a branch or jump whose field is `bits` wide.
+fn get_offset_from_label(label: &Label, addr: usize, bits: u32) -> Result<u32,String> {
+    let target = match label {
+        Label::DstAddrSpace(label) | Label::SrcAddrSpace(label) => *label as i64,
+        _ => return Err(String::from("jump instruction should have address in destination address space"))
+    };
+    let offset = target - addr as i64;
+    if offset % 2 != 0 {
+        return Err(format!("target {:#x} is not halfword aligned", target));
     }
+    signed(offset, bits, "target offset")
 }
 
-fn assemble_insn(inst: &Inst, addr: usize) -> Result<u32, String> {
+pub fn assemble_insn(inst: &Inst, addr: usize) -> Result<u32, String> {
     let mut word = 0x0;
     match inst.opcode.parse_format() {
         InstParseFormat::R => {
             word |= inst.opcode.opcode_bits();
-            word |= inst.dest.unwrap_gpr() << 7;
+            word |= gpr(inst.dest, "dest")? << 7;
             word |= inst.opcode.funct3() << 12;
-            word |= inst.src1.unwrap() << 15;
-            word |= inst.src2.unwrap_gpr() << 20;
+            word |= src1(inst)? << 15;
+            word |= gpr(inst.src2, "src2")? << 20;
             word |= inst.opcode.funct7() << 25;
         },
         InstParseFormat::I => return parse_i_format(inst),
         InstParseFormat::S => {
             word |= inst.opcode.opcode_bits();
-            let imm = inst.offset.unwrap() as u32;
+            let imm = offset(inst)?;
             word |= (imm & 31) << 7;
             word |= inst.opcode.funct3() << 12;
-            word |= inst.src2.unwrap_gpr() << 15;
-            word |= inst.src1.unwrap() << 20;
+            word |= gpr(inst.src2, "src2")? << 15;
+            word |= src1(inst)? << 20;
             word |= (imm >> 5) << 25;
         }
         InstParseFormat::L => {
             word |= inst.opcode.opcode_bits();
-            word |= inst.dest.unwrap_gpr() << 7;
+            word |= gpr(inst.dest, "dest")? << 7;
             word |= inst.opcode.funct3() << 12;
-            word |= inst.src1.unwrap() << 15;
-            let imm = inst.offset.unwrap() as u32;
-            word |= (imm as u32) << 20;
+            word |= src1(inst)? << 15;
+            let imm = offset(inst)?;
+            word |= imm << 20;
         }
         InstParseFormat::B => {
             word |= inst.opcode.opcode_bits();
-            let label = get_offset_from_label(&inst.label, addr)? as u32;
-            //if label > ((1<<13)-1) { return Err(format!("label too large: {}", label)) }
-            word |= (label & 30 | ((label >> 11) & 0x1)) << 7;
+            let label = get_offset_from_label(&inst.label, addr, 13)?;
             word |= (label & 30 | ((label >> 11) & 0x1)) << 7;
             word |= inst.opcode.funct3() << 12;
-            word |= inst.src2.unwrap_gpr() << 15;
-            word |= inst.src1.unwrap() << 20;
+            word |= gpr(inst.src2, "src2")? << 15;
+            word |= src1(inst)? << 20;
             word |= (bits(label, 5, 10) 
                 | (bits(label, 12, 12) << 6)) << 25;
         }
         InstParseFormat::J => {
-            let label = get_offset_from_label(&inst.label, addr)? as u32;
-            //if label > ((1<<21)-1) { return Err(format!("label too large: {}", label))}
+            let label = get_offset_from_label(&inst.label, addr, 21)?;
             word |= inst.opcode.opcode_bits();
-            word |= inst.dest.unwrap_gpr() << 7;
+            word |= gpr(inst.dest, "dest")? << 7;
             word |= (bits(label, 20, 20) << 19 
                 | bits(label, 1, 10) << 9
                 | bits(label, 11, 11) << 8
                 | bits(label, 12, 19)) << 12;
         }
-        _ => { 
+        InstParseFormat::SYS => {
+            word |= inst.opcode.opcode_bits();
+            word |= inst.opcode.funct3() << 12;
             match inst.opcode {
-                Opcode::RET => { word = 0x00008067; },
-                Opcode::LI => { return parse_i_format(inst)},
-                Opcode::MOV => {
-                    let mut inst2 = inst.clone();
-                    inst2.src1 = Some(inst2.src2.unwrap_gpr());
-                    inst2.src2 = Operand::Immediate(0);
-                    return parse_i_format(&inst2)
+                Opcode::FENCE => {
+                    let Operand::Immediate(ordering) = inst.src2 else {return Err(String::from("fence should have its ordering in src2"))};
+                    word |= (ordering as u32 & 0xff) << 20;
                 }
+                Opcode::EBREAK => { word |= 1 << 20; }
+                _ => {}
+            }
+        }
+        _ => { 
+            match inst.opcode {
+                Opcode::MOV => return Err(format!("{} has no RV32I encoding", inst)),
                 Opcode::AUIPC | Opcode::LUI => {
                     word |= inst.opcode.opcode_bits();
-                    word |= inst.dest.unwrap_gpr() << 7;
+                    word |= gpr(inst.dest, "dest")? << 7;
                     let Operand::Immediate(imm) = inst.src2 else {return Err(String::from("auipc should have immediate offset"))};
-                    word |= (imm as u32) << 12; 
+                    if !(0..1 << 20).contains(&imm) {
+                        return Err(format!("upper immediate {:#x} is not between 0 and 0xfffff", imm));
+                    }
+                    word |= (imm as u32) << 12;
                 },
                 _ => {unreachable!()}
             }
@@ -112,41 +153,107 @@ fn le_word(word: u32) -> String {
     output
 }
 
-pub fn assemble (sp: &ScheduledProgram, orig_size: usize, bytes_hex: bool) -> String { 
+/// How `assemble` writes the image for `$readmemh`: one word per line, its bytes, or lines
+/// of several words with the first word rightmost and `@` addresses counted in lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HexFormat {
+    Words,
+    Bytes,
+    // words per line, dividing the 16 of a line of hw/mem so the data starts a line
+    Lines(usize),
+}
+
+/// Writes words by word address, with an `@` at each of `starts` and wherever the addresses
+/// skip. In lines, the words missing from a line are zero.
+fn write_hex(words: &BTreeMap<usize, u32>, starts: &[usize], format: HexFormat) -> String {
     let mut output = String::new();
+    let n = match format {
+        HexFormat::Lines(n) => n,
+        _ => 1,
+    };
+    let mut lines: BTreeMap<usize, Vec<u32>> = BTreeMap::new();
+    for (addr, word) in words.iter() {
+        lines.entry(addr / n).or_insert_with(|| vec![0; n])[addr % n] = *word;
+    }
+    let mut next = None;
+    for (line, line_words) in lines.iter() {
+        if next != Some(*line) || starts.iter().any(|s| s / n == *line) {
+            output.push_str(format!("@{:x}\n", line).as_str());
+        }
+        next = Some(line + 1);
+        match format {
+            HexFormat::Words => output.push_str(format!("{:08x}\n", line_words[0]).as_str()),
+            HexFormat::Bytes => output.push_str(&le_word(line_words[0])),
+            HexFormat::Lines(_) => {
+                for word in line_words.iter().rev() {
+                    output.push_str(format!("{:08x}", word).as_str());
+                }
+                output.push('\n');
+            }
+        }
+    }
+    output
+}
 
-    let aligned_end = sp.aligned_end();
-    let offset = aligned_end*4 - (orig_size as i32);
-    assert!(offset > 0);
-    output.push_str(format!("@0\n{:x}\n0\n0\n0\n", offset).as_str());
+/// Bytes in a memory line, the unit `$readmemh` addresses are converted to for `hw/mem`.
+const LINE_BYTES: usize = 64;
+
+/// Original address the data image starts at: the first data section past the code, rounded
+/// down to a line so data keeps its alignment within lines.
+pub fn data_base(data: &[Section], orig_size: usize) -> usize {
+    data.iter()
+        .filter(|s| s.addr >= orig_size && s.size > 0)
+        .map(|s| s.addr)
+        .min()
+        .unwrap_or(orig_size) & !(LINE_BYTES - 1)
+}
+
+/// Complete memory image: the header bundle, the schedule and, from `aligned_end` on, the
+/// data sections where the ELF put them relative to `data_base`, with .bss and the gaps
+/// between sections zero-filled up to a whole line. The header word is the offset the memory
+/// unit adds to every data access to get there. Err for data the ELF put inside the code.
+pub fn assemble (sp: &ScheduledProgram, orig_size: usize, data: &[Section], format: HexFormat) -> Result<String, Error> { 
+    if let Some(s) = data.iter().find(|s| s.name != ".text" && s.size > 0 && s.addr < orig_size) {
+        return Err(Error::new(format!("{} at {:#x} overlaps the code, which ends at {:#x}", s.name, s.addr, orig_size)));
+    }
+    let mut words = BTreeMap::new();
+
+    let aligned_end = sp.aligned_end() as usize;
+    let base = data_base(data, orig_size);
+    // the header fills the first bundle
+    words.insert(0, (aligned_end * 4).wrapping_sub(base) as u32);
+    for i in 1..sp.model.width() {
+        words.insert(i, 0);
+    }
     for bundle in sp.schedule.iter() {
-        for inst in bundle.insts() {
+        for inst in sp.model.issue_order().iter().map(|i| &bundle.slots[*i]) {
             let word = if let Some(inst) = inst {
-                match assemble_insn(&inst.inst, bundle.addr) {
-                    Ok(w) => w,
-                    Err(e) => panic!("Can't assemble instruction {}:\n {}", &inst.inst, e),
-                }
+                assemble_insn(&inst.inst, bundle.addr)
+                    .map_err(|e| Error::new(format!("Can't assemble instruction: {}", e)).at(&inst.inst))?
             } else {
                 0
             };
-            if bytes_hex {
-                output.push_str(&le_word(word));
-            } else {
-                output.push_str(format!("{:08x}\n", word).as_str());
-            }
+            words.insert(words.len(), word);
         }
     }
-    
-    output.push_str(format!("@{:x}", aligned_end).as_str());
-    output
+
+    let sections: Vec<&Section> = data.iter().filter(|s| s.addr >= orig_size && s.size > 0).collect();
+    if let Some(end) = sections.iter().map(|s| s.addr + s.size).max() {
+        let mut image = vec![0; (end - base).div_ceil(LINE_BYTES) * LINE_BYTES];
+        for section in sections {
+            image[section.addr - base..section.addr - base + section.data.len()].copy_from_slice(&section.data);
+        }
+        for (i, w) in image.chunks(4).enumerate() {
+            words.insert(aligned_end + i, u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+        }
+    }
+    Ok(write_hex(&words, &[0, aligned_end], format))
 }
 
 
-pub fn assemble_ap_single(inst: &Inst, bytes_hex: bool, disassembly: bool, output: &mut String) {
-    let word = match assemble_insn(&inst, inst.addr) {
-        Ok(w) => w,
-        Err(e) => panic!("Can't assemble instruction {}:\n {}", &inst, e),
-    };
+pub fn assemble_ap_single(inst: &Inst, bytes_hex: bool, disassembly: bool, output: &mut String) -> Result<(), Error> {
+    let word = assemble_insn(inst, inst.addr)
+        .map_err(|e| Error::new(format!("Can't assemble instruction: {}", e)).at(inst))?;
     if disassembly {
         inst.print_fill(output, 22);
     }
@@ -155,27 +262,33 @@ pub fn assemble_ap_single(inst: &Inst, bytes_hex: bool, disassembly: bool, outpu
     } else {
         output.push_str(format!("{:08x}\n", word).as_str());
     }
+    Ok(())
 }
 
-pub fn assemble_ap (ap: &AnalyzedProgram, bytes_hex: bool, disassembly: bool) -> String { 
+pub fn assemble_ap (ap: &AnalyzedProgram, bytes_hex: bool, disassembly: bool) -> Result<String, Error> { 
     let mut output = String::new();
 
     for bb in ap.bbs.iter() {
         for inst in bb.insns.iter() {
-            assemble_ap_single(&inst.inst, bytes_hex, disassembly, &mut output);
+            assemble_ap_single(&inst.inst, bytes_hex, disassembly, &mut output)?;
         }
         if let Some(cf_insn) = &bb.cf_insn {
-            assemble_ap_single(&cf_insn.inst, bytes_hex, disassembly, &mut output);
+            assemble_ap_single(&cf_insn.inst, bytes_hex, disassembly, &mut output)?;
         }
     }
 
-    output
+    Ok(output)
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::isa::Inst;
+    use crate::disassembler::{disassemble, read_hex_words};
+    use crate::isa::{Inst, Label, Opcode, Operand};
+    use crate::scheduling::ScheduledProgram;
+    use crate::sim::{Core, Memory};
+    use crate::{analyze, parse, relocate, schedule, CompileOptions, Image};
 
-    use super::assemble_insn;
+    use super::{assemble, assemble_insn, data_base, HexFormat};
 
     #[test]
     fn test_j_format() {
@@ -191,9 +304,120 @@ mod tests {
         assert!(assemble_insn(&inst, 0x0) == Ok(0x00001fb7));        
     }
 
+    #[test]
+    fn test_field_ranges() {
+        // fields at their limits, then one past them
+        let cases = [
+            ("addi a0, a0, 1", 2047, 2048),
+            ("slli a0, a0, 1", 31, 40),
+            ("lw a0, 0(a1)", -2048, 4000),
+            ("sw a0, 0(a1)", 2047, -2049),
+            ("lui a0, 0", 0xfffff, 0x100000),
+            ("beq a0, a1, 0", 4094, 4096),
+            ("jal 0", -0x100000, -0x100002),
+        ];
+        for (src, fits, overflows) in cases {
+            let inst = Inst::from_str(src, 0).unwrap();
+            let with = |value: i64| {
+                let mut inst = inst;
+                match inst.opcode {
+                    Opcode::LW | Opcode::SW => inst.offset = Some(value),
+                    Opcode::BEQ | Opcode::JAL => inst.label = Label::DstAddrSpace((0x200000 + value) as usize),
+                    _ => inst.src2 = Operand::Immediate(value),
+                }
+                assemble_insn(&inst, 0x200000)
+            };
+            assert!(with(fits).is_ok(), "{} {}", src, fits);
+            assert!(with(overflows).is_err(), "{} {}", src, overflows);
+        }
+        let mut branch = Inst::from_str("beq a0, a1, 0", 0).unwrap();
+        branch.label = Label::DstAddrSpace(3);
+        assert!(assemble_insn(&branch, 0).is_err());
+    }
+
     /*#[test]
     fn test_branch_format() {
         let inst = Inst::from_str("bne	t6,t6,-1366", 0).unwrap();
         assert!(assemble_insn(&inst, 0x0) == Ok(0x01ff9463));
     }*/
-}
\ No newline at end of file
+
+    const DATA: &str = "
+            li   t0, 0xf000fff0
+            la   a0, val
+            lw   a1, 0(a0)
+            lw   a2, 12(a0)
+            add  a1, a1, a2
+            sw   a1, 8(t0)
+        1:  j    1b
+            .rodata
+        val: .word 0x11
+            .data
+            .align 4
+            .word 0x22
+            .bss
+        buf: .zero 8
+    ";
+
+    fn compiled(src: &str) -> (ScheduledProgram, Image) {
+        let opts = CompileOptions::default();
+        let (trace, elf) = parse(src.as_bytes()).unwrap();
+        let (ap, mut image) = analyze(trace, elf, &opts).unwrap();
+        let (mut sp, _) = schedule(ap, &opts);
+        relocate(&mut sp, &mut image).unwrap();
+        (sp, image)
+    }
+
+    #[test]
+    fn test_data_image() {
+        let (sp, image) = compiled(DATA);
+        let hex = assemble(&sp, image.orig_size, &image.elf.sections, HexFormat::Words).unwrap();
+        let words = read_hex_words(&hex).unwrap();
+
+        // .rodata right after the 9 instructions, .data aligned to 16, .bss after it
+        let sections = &image.elf.sections;
+        assert_eq!(sections.iter().map(|s| s.addr).collect::<Vec<_>>(), vec![0x24, 0x30, 0x34]);
+        let base = data_base(sections, image.orig_size);
+        assert_eq!(base, 0);
+        let end = sp.aligned_end() as usize;
+        assert_eq!(words[&0], (end * 4 - base) as u32);
+        assert_eq!((words[&(end + 0x24 / 4)], words[&(end + 0x30 / 4)]), (0x11, 0x22));
+        // one whole line, .bss and the padding included
+        assert_eq!(words.range(end..).count(), 16);
+        assert_eq!(words.range(end..).map(|(_, w)| w).sum::<u32>(), 0x33);
+
+        let mut core = Core::new(Memory::from_hex(&hex).unwrap(), &sp.model);
+        assert_eq!(core.run(10_000).unwrap().code, 0x33);
+
+        // data the ELF put inside the code has nowhere to go
+        let mut sections = image.elf.sections.clone();
+        sections[0].addr = 0x10;
+        assert!(assemble(&sp, image.orig_size, &sections, HexFormat::Words).is_err());
+    }
+
+    #[test]
+    fn test_line_format() {
+        let (sp, image) = compiled(DATA);
+        let words = read_hex_words(&assemble(&sp, image.orig_size, &image.elf.sections, HexFormat::Words).unwrap()).unwrap();
+        let hex = assemble(&sp, image.orig_size, &image.elf.sections, HexFormat::Lines(16)).unwrap();
+        let end = sp.aligned_end() as usize;
+        // 512-bit lines, the header word rightmost, up to the last line of data
+        let lines: Vec<&str> = hex.lines().collect();
+        assert_eq!(lines[0], "@0");
+        assert!(lines[1].len() == 128 && lines[1].ends_with(&format!("{:08x}", words[&0])));
+        assert_eq!(lines.iter().filter(|l| !l.starts_with('@')).count(), end / 16 + 1);
+        assert!(lines.contains(&format!("@{:x}", end / 16).as_str()));
+        // reads back as the same words, zero-filled to whole lines
+        let lines = read_hex_words(&hex).unwrap();
+        assert!(words.iter().all(|(a, w)| lines[a] == *w));
+        assert!(lines.iter().all(|(a, w)| words.get(a) == Some(w) || *w == 0));
+        assert_eq!(lines.len() % 16, 0);
+
+        let model = &sp.model;
+        let words_hex = assemble(&sp, image.orig_size, &image.elf.sections, HexFormat::Words).unwrap();
+        assert_eq!(disassemble(&hex, model).unwrap(), disassemble(&words_hex, model).unwrap());
+        let bundles = read_hex_words(&assemble(&sp, image.orig_size, &image.elf.sections, HexFormat::Lines(4)).unwrap()).unwrap();
+        assert!(words.iter().all(|(a, w)| bundles[a] == *w));
+        let mut core = Core::new(Memory::from_hex(&hex).unwrap(), &sp.model);
+        assert_eq!(core.run(10_000).unwrap().code, 0x33);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::isa::{Inst, Label, Opcode, Operand};
+
+fn bits(word: u32, start: u32, end: u32) -> u32 {
+    (word >> start) & ((1 << (end - start + 1)) - 1)
+}
+
+fn sign_extend(value: u32, width: u32) -> i64 {
+    let shift = 32 - width;
+    (((value << shift) as i32) >> shift) as i64
+}
+
+pub fn imm_i(word: u32) -> i64 {
+    sign_extend(bits(word, 20, 31), 12)
+}
+
+pub fn imm_s(word: u32) -> i64 {
+    sign_extend(bits(word, 25, 31) << 5 | bits(word, 7, 11), 12)
+}
+
+pub fn imm_b(word: u32) -> i64 {
+    sign_extend(bits(word, 31, 31) << 12
+        | bits(word, 7, 7) << 11
+        | bits(word, 25, 30) << 5
+        | bits(word, 8, 11) << 1, 13)
+}
+
+pub fn imm_j(word: u32) -> i64 {
+    sign_extend(bits(word, 31, 31) << 20
+        | bits(word, 12, 19) << 12
+        | bits(word, 20, 20) << 11
+        | bits(word, 21, 30) << 1, 21)
+}
+
+fn target(addr: usize, offset: i64) -> Result<Label, String> {
+    let target = addr as i64 + offset;
+    if target < 0 {
+        return Err(format!("branch target {} is before the start of the program", target));
+    }
+    Ok(Label::SrcAddrSpace(target as usize))
+}
+
+fn decode_r(word: u32) -> Result<Opcode, String> {
+    let funct3 = bits(word, 12, 14);
+    let funct7 = bits(word, 25, 31);
+    match (funct7, funct3) {
+        (0x00, 0x0) => Ok(Opcode::ADD),
+        (0x20, 0x0) => Ok(Opcode::SUB),
+        (0x00, 0x1) => Ok(Opcode::SLL),
+        (0x00, 0x2) => Ok(Opcode::SLT),
+        (0x00, 0x3) => Ok(Opcode::SLTU),
+        (0x00, 0x4) => Ok(Opcode::XOR),
+        (0x00, 0x5) => Ok(Opcode::SRL),
+        (0x20, 0x5) => Ok(Opcode::SRA),
+        (0x00, 0x6) => Ok(Opcode::OR),
+        (0x00, 0x7) => Ok(Opcode::AND),
+        (0x01, 0x0) => Ok(Opcode::MUL),
+        (0x01, 0x1) => Ok(Opcode::MULH),
+        (0x01, 0x2) => Ok(Opcode::MULHSU),
+        (0x01, 0x3) => Ok(Opcode::MULHU),
+        (0x01, 0x4) => Ok(Opcode::DIV),
+        (0x01, 0x5) => Ok(Opcode::DIVU),
+        (0x01, 0x6) => Ok(Opcode::REM),
+        (0x01, 0x7) => Ok(Opcode::REMU),
+        _ => Err(format!("Unrecognized R-format instruction: {:08x}", word)),
+    }
+}
+
+fn decode_i(word: u32) -> Result<(Opcode, i64), String> {
+    let funct3 = bits(word, 12, 14);
+    let funct7 = bits(word, 25, 31);
+    let shamt = bits(word, 20, 24) as i64;
+    match funct3 {
+        0x0 => Ok((Opcode::ADDI, imm_i(word))),
+        0x2 => Ok((Opcode::SLTI, imm_i(word))),
+        0x3 => Ok((Opcode::SLTIU, imm_i(word))),
+        0x4 => Ok((Opcode::XORI, imm_i(word))),
+        0x6 => Ok((Opcode::ORI, imm_i(word))),
+        0x7 => Ok((Opcode::ANDI, imm_i(word))),
+        0x1 if funct7 == 0x00 => Ok((Opcode::SLLI, shamt)),
+        0x5 if funct7 == 0x00 => Ok((Opcode::SRLI, shamt)),
+        0x5 if funct7 == 0x20 => Ok((Opcode::SRAI, shamt)),
+        _ => Err(format!("Unrecognized I-format instruction: {:08x}", word)),
+    }
+}
+
+/// Inverse of `assembler::assemble_insn`: turns a machine word found at `addr`
+/// back into an `Inst`, with branch and jump targets in the source address space.
+pub fn decode_insn(word: u32, addr: usize) -> Result<Inst, String> {
+    let rd = bits(word, 7, 11);
+    let rs1 = bits(word, 15, 19);
+    let rs2 = bits(word, 20, 24);
+    let mut inst = Inst::new(Opcode::ADDI);
+    inst.addr = addr;
+    match bits(word, 0, 6) {
+        0b0110011 => {
+            inst.opcode = decode_r(word)?;
+            inst.dest = Operand::Gpr(rd);
+            inst.src1 = Some(rs1);
+            inst.src2 = Operand::Gpr(rs2);
+        }
+        0b0010011 => {
+            let (opcode, imm) = decode_i(word)?;
+            inst.opcode = opcode;
+            inst.dest = Operand::Gpr(rd);
+            inst.src1 = Some(rs1);
+            inst.src2 = Operand::Immediate(imm);
+        }
+        0b0000011 => {
+            inst.opcode = match bits(word, 12, 14) {
+                0x0 => Opcode::LB,
+                0x1 => Opcode::LH,
+                0x2 => Opcode::LW,
+                0x4 => Opcode::LBU,
+                0x5 => Opcode::LHU,
+                _ => return Err(format!("Unrecognized load: {:08x}", word)),
+            };
+            inst.dest = Operand::Gpr(rd);
+            inst.src1 = Some(rs1);
+            inst.offset = Some(imm_i(word));
+        }
+        0b0100011 => {
+            inst.opcode = match bits(word, 12, 14) {
+                0x0 => Opcode::SB,
+                0x1 => Opcode::SH,
+                0x2 => Opcode::SW,
+                _ => return Err(format!("Unrecognized store: {:08x}", word)),
+            };
+            // stores keep the data register in src1 and the base in src2
+            inst.src1 = Some(rs2);
+            inst.src2 = Operand::Gpr(rs1);
+            inst.offset = Some(imm_s(word));
+        }
+        0b1100011 => {
+            inst.opcode = match bits(word, 12, 14) {
+                0x0 => Opcode::BEQ,
+                0x1 => Opcode::BNE,
+                0x4 => Opcode::BLT,
+                0x5 => Opcode::BGE,
+                0x6 => Opcode::BLTU,
+                0x7 => Opcode::BGEU,
+                _ => return Err(format!("Unrecognized branch: {:08x}", word)),
+            };
+            // branches are stored flipped, see parse_i_r_b_format_inst
+            inst.src1 = Some(rs2);
+            inst.src2 = Operand::Gpr(rs1);
+            inst.label = target(addr, imm_b(word))?;
+        }
+        0b1101111 => {
+            inst.opcode = if rd == 0 { Opcode::J } else { Opcode::JAL };
+            inst.dest = Operand::Gpr(rd);
+            inst.label = target(addr, imm_j(word))?;
+        }
+        0b1100111 => {
+            if bits(word, 12, 14) != 0 {
+                return Err(format!("Unrecognized jalr: {:08x}", word));
+            }
+            inst.opcode = Opcode::JALR;
+            inst.dest = Operand::Gpr(rd);
+            inst.src1 = Some(rs1);
+            inst.offset = Some(imm_i(word));
+        }
+        0b0110111 | 0b0010111 => {
+            inst.opcode = if bits(word, 0, 6) == 0b0110111 { Opcode::LUI } else { Opcode::AUIPC };
+            inst.dest = Operand::Gpr(rd);
+            inst.src1 = Some(0);
+            inst.src2 = Operand::Immediate(bits(word, 12, 31) as i64);
+        }
+        0b0001111 => {
+            // only plain fences, fence.tso and fence.i are not supported
+            if rd != 0 || rs1 != 0 || bits(word, 12, 14) != 0 || bits(word, 28, 31) != 0 {
+                return Err(format!("Unrecognized fence: {:08x}", word));
+            }
+            inst.opcode = Opcode::FENCE;
+            inst.src2 = Operand::Immediate(bits(word, 20, 27) as i64);
+        }
+        0b1110011 => {
+            inst.opcode = match word {
+                0x00000073 => Opcode::ECALL,
+                0x00100073 => Opcode::EBREAK,
+                _ => return Err(format!("Unsupported system instruction: {:08x}", word)),
+            };
+        }
+        _ => return Err(format!("Unrecognized instruction: {:08x}", word)),
+    }
+    Ok(inst)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::assemble_insn;
+    use crate::isa::{Inst, Label, Opcode, Operand};
+
+    use super::decode_insn;
+
+    fn round_trip(word: u32, addr: usize) {
+        let inst = decode_insn(word, addr).unwrap();
+        assert_eq!(assemble_insn(&inst, addr), Ok(word), "{:08x} decoded as {}", word, inst);
+        // the listing syntax must parse back to the same encoding as well
+        if let Label::None = inst.label {
+            let reparsed = Inst::from_str(&format!("{}", inst), addr).unwrap();
+            assert_eq!(assemble_insn(&reparsed, addr), Ok(word), "{:08x} printed as {}", word, inst);
+        }
+    }
+
+    #[test]
+    fn test_decode_branch() {
+        // bne x15, x14, -8
+        let inst = decode_insn(0xfee79ce3, 0x20).unwrap();
+        assert_eq!(inst.opcode, Opcode::BNE);
+        assert_eq!(inst.src1, Some(14));
+        assert!(matches!(inst.src2, Operand::Gpr(15)));
+        assert!(matches!(inst.label, Label::SrcAddrSpace(0x18)));
+    }
+
+    #[test]
+    fn test_round_trip_formats() {
+        let words = [
+            0x00b50533, // add x10, x10, x11
+            0x40b50533, // sub x10, x10, x11
+            0x41f55593, // srai x11, x10, 31
+            0xffc52503, // lw x10, -4(x10)
+            0x00a12e23, // sw x10, 28(x2)
+            0xfee79ce3, // bne x15, x14, -8
+            0x02b50533, // mul x10, x10, x11
+            0x02b57533, // remu x10, x10, x11
+            0x0400006f, // j 64
+            0x555550ef, // jal 0x55d68
+            0x00008067, // ret
+            0x004780e7, // jalr x1, 4(x15)
+            0xfffffeb7, // lui x29, 0xfffff
+            0x00000517, // auipc x10, 0
+            0x0ff0000f, // fence iorw, iorw
+            0x00000073, // ecall
+            0x00100073, // ebreak
+        ];
+        for word in words {
+            round_trip(word, 0x100);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_random() {
+        // every word the decoder accepts must assemble back to itself
+        let mut state: u32 = 0x2545f491;
+        let mut decoded = 0;
+        for _ in 0..200000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if decode_insn(state, 0x1000).is_ok() {
+                round_trip(state, 0x1000);
+                decoded += 1;
+            }
+        }
+        assert!(decoded > 1000);
+    }
+
+    #[test]
+    fn test_decode_jal() {
+        let inst = decode_insn(0x555550ef, 0x14).unwrap();
+        assert_eq!(inst.opcode, Opcode::JAL);
+        assert!(matches!(inst.label, Label::SrcAddrSpace(0x55d68)));
+        assert_eq!(inst.to_string(), "jal 0x55d68");
+        // a link register other than ra is printed
+        let inst = decode_insn(0x55555fef, 0x14).unwrap();
+        assert_eq!(inst.to_string(), "jal x31, 0x55d68");
+        assert_eq!(Inst::from_str(&inst.to_string(), 0x14).unwrap().dest, Operand::Gpr(31));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::analysis::DepInst;
+use crate::decoder::decode_insn;
+use crate::isa::Label;
+use crate::machine::MachineModel;
+use crate::scheduling::{Bundle, ScheduledProgram};
+
+/// Word-addressed contents of a `$readmemh` image, as written by `assembler::assemble`.
+pub fn read_hex_words(hex: &str) -> Result<BTreeMap<usize, u32>, String> {
+    let mut words = BTreeMap::new();
+    let mut addr = 0;
+    for (i, line) in hex.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(new_addr) = line.strip_prefix('@') {
+            addr = usize::from_str_radix(new_addr, 16)
+                .map_err(|e| format!("line {}: bad address {}: {}", i + 1, line, e))?;
+            continue;
+        }
+        let bytes: Vec<&str> = line.split_whitespace().collect();
+        let row = if bytes.len() == 4 {
+            // bytes_hex output, little endian
+            bytes.iter().rev().try_fold(0u32, |word, b| {
+                u8::from_str_radix(b, 16).map(|b| word << 8 | b as u32)
+            }).map(|word| vec![word])
+        } else if line.len() > 8 && line.len() % 8 == 0 {
+            // a line of words, the first one rightmost, and `@` addresses in lines
+            (0..line.len() / 8).rev()
+                .map(|w| u32::from_str_radix(&line[8 * w..8 * w + 8], 16))
+                .collect()
+        } else {
+            u32::from_str_radix(line, 16).map(|word| vec![word])
+        }.map_err(|e| format!("line {}: bad word {}: {}", i + 1, line, e))?;
+        for (w, word) in row.iter().enumerate() {
+            words.insert(addr * row.len() + w, *word);
+        }
+        addr += 1;
+    }
+    Ok(words)
+}
+
+fn word_at(words: &BTreeMap<usize, u32>, addr: usize) -> u32 {
+    *words.get(&addr).unwrap_or(&0)
+}
+
+/// Rebuilds a scheduled program from a hex image: word 0 holds the data offset,
+/// followed by one bundle per `model.width()` words, in issue order, up to the
+/// data section, which starts at the second `@` marker.
+pub fn disassemble(hex: &str, model: &MachineModel) -> Result<String, String> {
+    let words = read_hex_words(hex)?;
+    // `@` addresses count lines of words in line images
+    let per_line = hex.lines()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with('@'))
+        .filter(|l| l.len() > 8 && l.len() % 8 == 0)
+        .map_or(1, |l| l.len() / 8);
+    let data_start = hex.lines()
+        .filter_map(|l| l.trim().strip_prefix('@'))
+        .filter_map(|a| usize::from_str_radix(a, 16).ok())
+        .find(|a| *a != 0)
+        .map(|a| a * per_line)
+        .unwrap_or_else(|| words.keys().last().map_or(0, |a| a + 1));
+
+    let mut sp = ScheduledProgram {
+        model: model.clone(),
+        schedule: Vec::new(),
+        bb_starts: Vec::new(),
+        starts: HashMap::new(),
+        entries: HashMap::new(),
+        pipelined: Vec::new(),
+        traces: Vec::new(),
+    };
+    let mut targets = vec![0];
+    // padding up to the data section is not part of the image
+    let code_end = words.range(..data_start).next_back().map_or(0, |(a, _)| a + 1);
+    // the header occupies the first bundle; the core's PC for a bundle is its memory address minus one bundle
+    let width = model.width();
+    for (i, mem_addr) in (width..code_end).step_by(width).enumerate() {
+        let addr = i * model.bundle_bytes();
+        let mut bundle = Bundle::new(addr, width);
+        for (j, slot) in model.issue_order().iter().enumerate() {
+            let word = word_at(&words, mem_addr + j);
+            if word == 0 {
+                continue;
+            }
+            let mut inst = decode_insn(word, addr)
+                .map_err(|e| format!("bundle {:x}, slot {}: {}", addr, j, e))?;
+            if let Label::SrcAddrSpace(target) = inst.label {
+                inst.label = Label::DstAddrSpace(target);
+                targets.push(target / model.bundle_bytes());
+            }
+            if inst.opcode.is_control_flow() {
+                targets.push(i + 1);
+            }
+            bundle.slots[*slot] = Some(DepInst {
+                inst,
+                false_deps: Vec::new(),
+                mem_deps: Vec::new(),
+                src1: None,
+                src2: None,
+            });
+        }
+        sp.schedule.push(bundle);
+    }
+    targets.sort();
+    targets.dedup();
+    sp.bb_starts = targets.into_iter().filter(|t| *t < sp.schedule.len()).collect();
+
+    let data_words = words.range(data_start..).count();
+    Ok(format!("Data offset: {:x}\nData: {} words @ {:x}\n{}", word_at(&words, 0), data_words, data_start, sp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::disassemble;
+    use crate::machine::MachineModel;
+
+    #[test]
+    fn test_disassemble_bundles() {
+        let hex = "@0\n60\n0\n0\n0\n\
+                   00000000\n00000000\n00000513\n00a00593\n\
+                   00a12023\nfe0008e3\n00000000\n00000000\n\
+                   @10\ndeadbeef\n";
+        let listing = disassemble(hex, &MachineModel::default()).unwrap();
+        assert!(listing.starts_with("Data offset: 60\nData: 1 words @ 10\n"));
+        assert!(listing.contains("addi x10, x0, 0"));
+        assert!(listing.contains("sw x10, 0(x2)"));
+        // the branch in bundle 0x10 jumps back to bundle 0
+        assert!(listing.contains("beq x0, x0, 0"));
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::decoder::decode_insn;
+use crate::isa::Inst;
+
+const EM_RISCV: u16 = 243;
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHF_ALLOC: u32 = 0x2;
+const STT_FUNC: u8 = 2;
+const STT_OBJECT: u8 = 1;
+
+/// Sections we care about when building the memory image. Everything else
+/// (debug info, comments, attributes) is dropped while parsing.
+const LOADED_SECTIONS: [&str; 4] = [".text", ".rodata", ".data", ".bss"];
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub addr: usize,
+    pub size: usize,
+    // empty for .bss, which only occupies memory at runtime
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Func,
+    Object,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: usize,
+    pub size: usize,
+    pub kind: SymbolKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: usize,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    // source line of each instruction address, when the input was assembly
+    pub lines: HashMap<usize, usize>,
+}
+
+struct SectionHeader {
+    name: u32,
+    sh_type: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> Result<u16, String> {
+    bytes.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| format!("ELF truncated at offset {:#x}", at))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, String> {
+    bytes.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| format!("ELF truncated at offset {:#x}", at))
+}
+
+fn read_str(bytes: &[u8], at: usize) -> Result<String, String> {
+    let tail = bytes.get(at..).ok_or_else(|| format!("String offset {:#x} out of range", at))?;
+    let end = tail.iter().position(|b| *b == 0).unwrap_or(tail.len());
+    Ok(String::from_utf8_lossy(&tail[..end]).into_owned())
+}
+
+fn slice(bytes: &[u8], offset: u32, size: u32) -> Result<&[u8], String> {
+    offset.checked_add(size)
+        .and_then(|end| bytes.get(offset as usize..end as usize))
+        .ok_or_else(|| format!("Section contents at {:#x} (+{:#x}) out of range", offset, size))
+}
+
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"\x7fELF")
+}
+
+impl Elf {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if !is_elf(bytes) {
+            return Err(String::from("Not an ELF file"));
+        }
+        // EI_CLASS = ELFCLASS32, EI_DATA = little endian
+        if bytes.get(4) != Some(&1) || bytes.get(5) != Some(&1) {
+            return Err(String::from("Only little-endian ELF32 (RV32) files are supported"));
+        }
+        let machine = read_u16(bytes, 18)?;
+        if machine != EM_RISCV {
+            return Err(format!("ELF machine is {}, expected RISC-V ({})", machine, EM_RISCV));
+        }
+        let entry = read_u32(bytes, 24)? as usize;
+        let shoff = read_u32(bytes, 32)? as usize;
+        let shentsize = read_u16(bytes, 46)? as usize;
+        let shnum = read_u16(bytes, 48)? as usize;
+        let shstrndx = read_u16(bytes, 50)? as usize;
+
+        let mut headers = Vec::new();
+        for i in 0..shnum {
+            let at = shoff + i * shentsize;
+            headers.push(SectionHeader {
+                name: read_u32(bytes, at)?,
+                sh_type: read_u32(bytes, at + 4)?,
+                flags: read_u32(bytes, at + 8)?,
+                addr: read_u32(bytes, at + 12)?,
+                offset: read_u32(bytes, at + 16)?,
+                size: read_u32(bytes, at + 20)?,
+                link: read_u32(bytes, at + 24)?,
+            });
+        }
+        let shstrtab = headers.get(shstrndx)
+            .ok_or_else(|| String::from("Missing section name string table"))?
+            .offset as usize;
+
+        let mut sections = Vec::new();
+        let mut symbols = Vec::new();
+        for header in headers.iter() {
+            let name = read_str(bytes, shstrtab + header.name as usize)?;
+            if header.sh_type == SHT_SYMTAB {
+                let strtab = headers.get(header.link as usize)
+                    .ok_or_else(|| String::from("Symbol table without a string table"))?
+                    .offset as usize;
+                for entry in slice(bytes, header.offset, header.size)?.chunks_exact(16) {
+                    let info = entry[12];
+                    let kind = match info & 0xf {
+                        STT_FUNC => SymbolKind::Func,
+                        STT_OBJECT => SymbolKind::Object,
+                        _ => SymbolKind::Other,
+                    };
+                    let name = read_str(bytes, strtab + read_u32(entry, 0)? as usize)?;
+                    if name.is_empty() {
+                        continue;
+                    }
+                    symbols.push(Symbol {
+                        name,
+                        addr: read_u32(entry, 4)? as usize,
+                        size: read_u32(entry, 8)? as usize,
+                        kind,
+                    });
+                }
+            } else if header.flags & SHF_ALLOC != 0 && LOADED_SECTIONS.contains(&name.as_str()) {
+                let data = if header.sh_type == SHT_NOBITS {
+                    Vec::new()
+                } else {
+                    slice(bytes, header.offset, header.size)?.to_vec()
+                };
+                sections.push(Section {
+                    name,
+                    addr: header.addr as usize,
+                    size: header.size as usize,
+                    data,
+                });
+            }
+        }
+
+        Ok(Elf { entry, sections, symbols, lines: HashMap::new() })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// Decodes `.text` into the same instruction trace `parse_asm` builds from assembly.
+    pub fn trace(&self) -> Result<Vec<Inst>, String> {
+        let text = self.section(".text").ok_or_else(|| String::from("ELF has no .text section"))?;
+        // the core starts fetching at 0 and the trace addresses are offsets into .text
+        if text.addr != 0 || self.entry != 0 {
+            return Err(format!(".text and the entry point must be at address 0, found {:#x} and {:#x}", text.addr, self.entry));
+        }
+        text.data.chunks_exact(4)
+            .enumerate()
+            .map(|(i, w)| {
+                let addr = text.addr + i * 4;
+                let word = u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+                decode_insn(word, addr).map_err(|e| format!("{} (at {:#x})", e, addr))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Elf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Sections:")?;
+        for section in self.sections.iter() {
+            writeln!(f, "{:<8} {:<8x} {:x}", section.name, section.addr, section.size)?;
+        }
+        writeln!(f, "Symbols:")?;
+        for symbol in self.symbols.iter() {
+            let kind = match symbol.kind {
+                SymbolKind::Func => "F",
+                SymbolKind::Object => "O",
+                SymbolKind::Other => " ",
+            };
+            writeln!(f, "{:<8x} {} {:<6x} {}", symbol.addr, kind, symbol.size, symbol.name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Elf, SymbolKind};
+    use crate::isa::Opcode;
+
+    fn push_u16(v: &mut Vec<u8>, x: u16) { v.extend_from_slice(&x.to_le_bytes()); }
+    fn push_u32(v: &mut Vec<u8>, x: u32) { v.extend_from_slice(&x.to_le_bytes()); }
+
+    // .text with two instructions, .bss, a symbol table and the string tables
+    fn tiny_elf() -> Vec<u8> {
+        let text: Vec<u8> = [0x00a00513u32, 0x00008067].iter().flat_map(|w| w.to_le_bytes()).collect();
+        let shstrtab = b"\0.text\0.bss\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+        let strtab = b"\0main\0".to_vec();
+        let mut symtab = vec![0u8; 16];
+        push_u32(&mut symtab, 1);
+        push_u32(&mut symtab, 0);
+        push_u32(&mut symtab, 8);
+        symtab.extend_from_slice(&[0x12, 0]);
+        push_u16(&mut symtab, 1);
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0];
+        elf.resize(16, 0);
+        push_u16(&mut elf, 2);
+        push_u16(&mut elf, 243);
+        push_u32(&mut elf, 1);
+        push_u32(&mut elf, 0);
+        push_u32(&mut elf, 0);
+        let shoff_at = elf.len();
+        push_u32(&mut elf, 0);
+        push_u32(&mut elf, 0);
+        push_u16(&mut elf, 52);
+        push_u16(&mut elf, 0);
+        push_u16(&mut elf, 0);
+        push_u16(&mut elf, 40);
+        push_u16(&mut elf, 6);
+        push_u16(&mut elf, 5);
+
+        let place = |elf: &mut Vec<u8>, data: &[u8]| { let at = elf.len(); elf.extend_from_slice(data); at as u32 };
+        let text_off = place(&mut elf, &text);
+        let symtab_off = place(&mut elf, &symtab);
+        let strtab_off = place(&mut elf, &strtab);
+        let shstrtab_off = place(&mut elf, &shstrtab);
+        let shoff = elf.len() as u32;
+        elf[shoff_at..shoff_at + 4].copy_from_slice(&shoff.to_le_bytes());
+
+        let headers: [[u32; 10]; 6] = [
+            [0; 10],
+            [1, 1, 0x6, 0, text_off, text.len() as u32, 0, 0, 4, 0],
+            [7, 8, 0x3, 0x100, 0, 0x40, 0, 0, 4, 0],
+            [12, 2, 0, 0, symtab_off, symtab.len() as u32, 4, 1, 4, 16],
+            [20, 3, 0, 0, strtab_off, strtab.len() as u32, 0, 0, 1, 0],
+            [28, 3, 0, 0, shstrtab_off, shstrtab.len() as u32, 0, 0, 1, 0],
+        ];
+        for header in headers.iter() {
+            for field in header.iter() {
+                push_u32(&mut elf, *field);
+            }
+        }
+        elf
+    }
+
+    #[test]
+    fn test_parse_sections_and_symbols() {
+        let elf = Elf::parse(&tiny_elf()).unwrap();
+        let bss = elf.section(".bss").unwrap();
+        assert_eq!((bss.addr, bss.size, bss.data.len()), (0x100, 0x40, 0));
+        let main = elf.symbols.iter().find(|s| s.name == "main").unwrap();
+        assert_eq!((main.addr, main.size, main.kind), (0, 8, SymbolKind::Func));
+    }
+
+    #[test]
+    fn test_trace_from_text() {
+        let trace = Elf::parse(&tiny_elf()).unwrap().trace().unwrap();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].opcode, Opcode::ADDI);
+        assert_eq!(trace[1].opcode, Opcode::JALR);
+        assert_eq!(trace[1].addr, 4);
+    }
+
+    #[test]
+    fn test_section_out_of_range() {
+        let mut elf = tiny_elf();
+        // .text size, in the second section header, reaching past 4 GiB
+        let shoff = u32::from_le_bytes([elf[32], elf[33], elf[34], elf[35]]) as usize;
+        elf[shoff + 40 + 20..shoff + 40 + 24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Elf::parse(&elf).unwrap_err().contains("out of range"));
+    }
+}
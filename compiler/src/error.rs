@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::isa::Inst;
+
+/// A failure in any stage, with where in the input it comes from as far as the stage knows:
+/// the source line for assembly, the original address and text of the instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub msg: String,
+    pub line: Option<usize>,
+    pub addr: Option<usize>,
+    pub inst: Option<String>,
+}
+
+impl Error {
+    pub fn new(msg: impl Into<String>) -> Self {
+        Error { msg: msg.into(), line: None, addr: None, inst: None }
+    }
+
+    pub fn at_line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    /// Points at `inst`, by its original address.
+    pub fn at(mut self, inst: &Inst) -> Self {
+        self.addr = Some(inst.addr);
+        self.inst = Some(inst.to_string());
+        self
+    }
+
+    /// Fills in the line from the source lines of each address, as `parse_asm` records them.
+    pub fn with_lines(mut self, lines: &HashMap<usize, usize>) -> Self {
+        if self.line.is_none() {
+            self.line = self.addr.and_then(|a| lines.get(&a)).copied();
+        }
+        self
+    }
+}
+
+impl From<String> for Error {
+    fn from(msg: String) -> Self {
+        Error::new(msg)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        match (&self.inst, self.addr) {
+            (Some(inst), Some(addr)) => write!(f, "{} (at {:#x}): ", inst, addr)?,
+            (None, Some(addr)) => write!(f, "at {:#x}: ", addr)?,
+            _ => {}
+        }
+        write!(f, "{}", self.msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Error;
+    use crate::isa::Inst;
+
+    #[test]
+    fn test_error_locations() {
+        let inst = Inst::from_str("addi x5, x5, 4", 0x8).unwrap();
+        let err = Error::new("Expected ADDI right after AUIPC").at(&inst);
+        assert_eq!(err.to_string(), "addi x5, x5, 4 (at 0x8): Expected ADDI right after AUIPC");
+        let lines = HashMap::from([(0x8, 12)]);
+        assert_eq!(err.with_lines(&lines).to_string(), "line 12: addi x5, x5, 4 (at 0x8): Expected ADDI right after AUIPC");
+        // the parser knows the line already
+        let err = Error::new("Unsupported directive .foo").at_line(3).with_lines(&lines);
+        assert_eq!(err.to_string(), "line 3: Unsupported directive .foo");
+    }
+}
@@ -0,0 +1,290 @@
+use std::any::Any;
+use std::fmt;
+use std::ops::Range;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::isa::{hi20, lo12, Opcode, Operand};
+use crate::parser::parse_asm;
+use crate::{compare, parse, CompileOptions};
+
+/// Registers the generated instructions read and write. s0 and s3 point into the scratch
+/// region, s1 and s2 count loop iterations, s4 and s5 hold the MMIO addresses.
+const REGS: [&str; 12] = ["t0", "t1", "t2", "t3", "t4", "t5", "a0", "a1", "a2", "a3", "a4", "a5"];
+const POINTERS: [&str; 2] = ["s0", "s3"];
+const COUNTERS: [&str; 2] = ["s1", "s2"];
+/// Where loads and stores go: pointers land in the first quarter, offsets reach another
+/// quarter, the register dump goes in the second half. Well past the end of any program.
+const SCRATCH: i64 = 0x2000;
+const SCRATCH_BYTES: i64 = 256;
+/// The pointer and register setup at the top of every program, which minimizing keeps.
+const PROLOGUE: usize = 2 * POINTERS.len() + REGS.len();
+/// The loop after the exit store, which minimizing keeps too: falling off the end would
+/// leave every register live after the exit.
+const TRAILER: usize = 2;
+
+const ALU_OPS: [&str; 18] = [
+    "add", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and",
+    "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu",
+];
+const IMM_OPS: [&str; 6] = ["addi", "slti", "sltiu", "xori", "ori", "andi"];
+const SHIFT_OPS: [&str; 3] = ["slli", "srli", "srai"];
+// with the access size, for alignment
+const LOADS: [(&str, i64); 5] = [("lw", 4), ("lh", 2), ("lhu", 2), ("lb", 1), ("lbu", 1)];
+const STORES: [(&str, i64); 3] = [("sw", 4), ("sh", 2), ("sb", 1)];
+const BRANCHES: [&str; 6] = ["beq", "bne", "blt", "bge", "bltu", "bgeu"];
+
+/// How a program fares against the reference.
+pub enum Verdict {
+    Same,
+    Differs(Vec<String>),
+    // the program doesn't parse or doesn't exit on the reference
+    Invalid,
+}
+
+/// A generated program the check fails, minimized.
+pub struct Failure {
+    pub seed: u64,
+    pub problems: Vec<String>,
+    pub program: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "seed {}: {}\n{}", self.seed, self.problems.join("; "), self.program)
+    }
+}
+
+/// Builds a program one line per instruction, so the address of a line is known while
+/// generating it.
+struct Generator {
+    rng: SmallRng,
+    lines: Vec<String>,
+    insts: usize,
+}
+
+impl Generator {
+    fn emit(&mut self, line: String) {
+        self.lines.push(line);
+        self.insts += 1;
+    }
+
+    fn reg(&mut self) -> &'static str {
+        REGS[self.rng.gen_range(0..REGS.len())]
+    }
+
+    /// An auipc/addi pair pointing `reg` at the scratch region.
+    fn pointer(&mut self, reg: &str) {
+        let target = SCRATCH + self.rng.gen_range(0..SCRATCH_BYTES / 16) * 4;
+        let delta = target - self.insts as i64 * 4;
+        self.emit(format!("auipc {}, {}", reg, hi20(delta)));
+        self.emit(format!("addi {}, {}, {}", reg, reg, lo12(delta)));
+    }
+
+    fn access(&mut self, size: i64) -> String {
+        let base = POINTERS[self.rng.gen_range(0..POINTERS.len())];
+        let offset = self.rng.gen_range(0..SCRATCH_BYTES / 4 / size) * size;
+        format!("{}({})", offset, base)
+    }
+
+    /// One ALU instruction, load or store.
+    fn simple(&mut self) {
+        let line = match self.rng.gen_range(0..10) {
+            0..=3 => {
+                let op = ALU_OPS[self.rng.gen_range(0..ALU_OPS.len())];
+                format!("{} {}, {}, {}", op, self.reg(), self.reg(), self.reg())
+            }
+            4 | 5 => {
+                let op = IMM_OPS[self.rng.gen_range(0..IMM_OPS.len())];
+                format!("{} {}, {}, {}", op, self.reg(), self.reg(), self.rng.gen_range(-2048..2048))
+            }
+            6 => {
+                let op = SHIFT_OPS[self.rng.gen_range(0..SHIFT_OPS.len())];
+                format!("{} {}, {}, {}", op, self.reg(), self.reg(), self.rng.gen_range(0..32))
+            }
+            7 => format!("lui {}, {}", self.reg(), self.rng.gen_range(0..0x100000)),
+            8 => {
+                let (op, size) = LOADS[self.rng.gen_range(0..LOADS.len())];
+                format!("{} {}, {}", op, self.reg(), self.access(size))
+            }
+            _ => {
+                let (op, size) = STORES[self.rng.gen_range(0..STORES.len())];
+                format!("{} {}, {}", op, self.reg(), self.access(size))
+            }
+        };
+        self.emit(line);
+    }
+
+    /// Straight-line code with forward branches over a few instructions, repointed s3,
+    /// and counted loops nested up to two deep.
+    fn block(&mut self, depth: usize, len: usize) {
+        for _ in 0..len {
+            match self.rng.gen_range(0..12) {
+                0 if depth < COUNTERS.len() => {
+                    let counter = COUNTERS[depth];
+                    let label = 5 + depth;
+                    let trips = self.rng.gen_range(1..5);
+                    self.emit(format!("addi {}, x0, {}", counter, trips));
+                    self.lines.push(format!("{}:", label));
+                    let len = self.rng.gen_range(1..6);
+                    self.block(depth + 1, len);
+                    self.emit(format!("addi {}, {}, -1", counter, counter));
+                    self.emit(format!("bnez {}, {}b", counter, label));
+                }
+                1 | 2 => {
+                    let op = BRANCHES[self.rng.gen_range(0..BRANCHES.len())];
+                    let line = format!("{} {}, {}, 3f", op, self.reg(), self.reg());
+                    self.emit(line);
+                    for _ in 0..self.rng.gen_range(1..5) {
+                        self.simple();
+                    }
+                    self.lines.push(String::from("3:"));
+                }
+                3 => self.pointer(POINTERS[1]),
+                _ => self.simple(),
+            }
+        }
+    }
+}
+
+/// A random program for `seed`: registers set to random values, random code over them
+/// (`Generator::block`), then every register stored to scratch, a character derived from
+/// a0 sent to putchar and a1 stored to the exit address. It always exits: every loop is
+/// counted and every branch goes forward.
+pub fn generate(seed: u64) -> Vec<String> {
+    let mut gen = Generator { rng: SmallRng::seed_from_u64(seed), lines: Vec::new(), insts: 0 };
+    for pointer in POINTERS.iter() {
+        gen.pointer(pointer);
+    }
+    for reg in REGS.iter() {
+        let value = gen.rng.gen_range(-2048..2048);
+        gen.emit(format!("addi {}, x0, {}", reg, value));
+    }
+    let len = gen.rng.gen_range(4..16);
+    gen.block(0, len);
+    for (i, reg) in REGS.iter().enumerate() {
+        gen.emit(format!("sw {}, {}(s0)", reg, SCRATCH_BYTES / 2 + 4 * i as i64));
+    }
+    // putchar and exit go through different base registers, which keeps them in order
+    gen.emit(String::from("lui s4, 0xf0010"));
+    gen.emit(String::from("andi t0, a0, 63"));
+    gen.emit(String::from("addi t0, t0, 48"));
+    gen.emit(String::from("sw t0, -16(s4)"));
+    gen.emit(String::from("addi s5, s4, -8"));
+    gen.emit(String::from("sw a1, 0(s5)"));
+    gen.lines.push(String::from("9:"));
+    gen.emit(String::from("j 9b"));
+    gen.lines
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload.downcast_ref::<String>().cloned()
+        .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| String::from("unknown panic"))
+}
+
+/// Runs `check` on a program that parses and keeps every auipc next to the addi on the
+/// same register (as `label_auipc` expects), with a panic in it reported as a difference.
+fn judge(lines: &[String], check: &dyn Fn(&str) -> Verdict) -> Verdict {
+    let src = lines.join("\n");
+    let Ok((trace, _)) = parse_asm(&src) else { return Verdict::Invalid };
+    let paired = trace.iter().enumerate()
+        .filter(|(_, inst)| inst.opcode == Opcode::AUIPC)
+        .all(|(i, inst)| trace.get(i + 1).is_some_and(|next| {
+            next.opcode == Opcode::ADDI && next.dest == inst.dest && next.src1.map(Operand::Gpr) == Some(inst.dest)
+        }));
+    if !paired {
+        return Verdict::Invalid;
+    }
+    match catch_unwind(AssertUnwindSafe(|| check(&src))) {
+        Ok(verdict) => verdict,
+        Err(payload) => Verdict::Differs(vec![format!("panicked: {}", panic_message(payload))]),
+    }
+}
+
+/// Drops lines between the prologue and the trailer from a failing program, halves first and single lines
+/// last, as long as it keeps failing. Returns the smaller program and how it fails.
+pub fn minimize(mut lines: Vec<String>, mut problems: Vec<String>, check: &dyn Fn(&str) -> Verdict) -> (Vec<String>, Vec<String>) {
+    let mut chunk = lines.len().saturating_sub(PROLOGUE + TRAILER).div_ceil(2);
+    while chunk > 0 {
+        let mut i = PROLOGUE;
+        while i + TRAILER < lines.len() {
+            let mut candidate = lines.clone();
+            candidate.drain(i..(i + chunk).min(lines.len() - TRAILER));
+            match judge(&candidate, check) {
+                Verdict::Differs(p) => {
+                    lines = candidate;
+                    problems = p;
+                }
+                _ => i += chunk,
+            }
+        }
+        chunk /= 2;
+    }
+    (lines, problems)
+}
+
+/// How a program fares in `compare`.
+pub fn check(src: &str, opts: &CompileOptions, max_cycles: u64) -> Verdict {
+    let Ok((trace, elf)) = parse(src.as_bytes()) else { return Verdict::Invalid };
+    match compare(trace, elf, opts, max_cycles) {
+        Err(_) => Verdict::Invalid,
+        Ok((problems, _)) if problems.is_empty() => Verdict::Same,
+        Ok((problems, _)) => Verdict::Differs(problems),
+    }
+}
+
+/// Runs `check` on the programs generated from `seeds`, minimizing the ones that fail.
+pub fn fuzz(seeds: Range<u64>, check: &dyn Fn(&str) -> Verdict) -> Vec<Failure> {
+    let mut failures = Vec::new();
+    for seed in seeds {
+        let lines = generate(seed);
+        let (lines, problems) = match judge(&lines, check) {
+            Verdict::Same => continue,
+            Verdict::Differs(problems) => minimize(lines, problems, check),
+            Verdict::Invalid => (lines, vec![String::from("the generated program doesn't run on the reference")]),
+        };
+        failures.push(Failure { seed, problems, program: lines.join("\n") });
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, fuzz, generate, minimize, Verdict, PROLOGUE, TRAILER};
+    use crate::scheduling::Scheduler;
+    use crate::CompileOptions;
+
+    #[test]
+    fn test_fuzz_schedulers() {
+        for scheduler in [Scheduler::Asap, Scheduler::List, Scheduler::Modulo, Scheduler::Superblock] {
+            let opts = CompileOptions { scheduler, unroll: 2, ..Default::default() };
+            let failures = fuzz(0..8, &|src| check(src, &opts, 100_000));
+            assert!(failures.is_empty(), "{:?}: {}", scheduler, failures[0]);
+        }
+        // the generator is deterministic
+        assert_eq!(generate(3), generate(3));
+    }
+
+    #[test]
+    fn test_minimize() {
+        let mut lines = generate(1);
+        let has_mul = |src: &str| if src.contains("mul ") {
+            Verdict::Differs(vec![String::from("mul")])
+        } else {
+            Verdict::Same
+        };
+        lines.insert(PROLOGUE, String::from("mul a0, a1, a2"));
+        let (min, problems) = minimize(lines, Vec::new(), &has_mul);
+        assert_eq!(min.len(), PROLOGUE + 1 + TRAILER);
+        assert!(min[PROLOGUE].starts_with("mul "));
+        assert_eq!(problems, vec!["mul"]);
+        // panics are failures too, and minimize the same way
+        let panics = |src: &str| if src.contains("sra ") { panic!("sra") } else { Verdict::Same };
+        let failures = fuzz(0..40, &panics);
+        assert!(!failures.is_empty());
+        assert!(failures.iter().all(|f| f.program.lines().count() == PROLOGUE + 1 + TRAILER && f.problems == ["panicked: sra"]));
+    }
+}
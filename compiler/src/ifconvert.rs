@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::analysis::{dep_analysis, liveness, reg_reads, reg_write, AnalyzedProgram, Cfg, Solution};
+use crate::isa::{ExecutionUnit, Inst, Label, Opcode, Operand};
+use crate::rename::{rename_reads, POOL};
+
+/// Instructions an arm may have to be executed unconditionally.
+const MAX_ARM: usize = 4;
+
+/// Summary of a branch turned into straight-line code, for `--stats`.
+#[derive(Debug, Clone)]
+pub struct IfConverted {
+    pub addr: usize,
+    // instructions on the fall-through and taken side, none on the taken side of a hammock
+    pub then_len: usize,
+    pub else_len: usize,
+    // registers picked from one of the sides after both ran
+    pub selects: usize,
+}
+
+impl fmt::Display for IfConverted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "branch @ {:x}: {} + {} instructions, {} registers selected",
+            self.addr, self.then_len, self.else_len, self.selects)
+    }
+}
+
+fn alu(opcode: Opcode, rd: u32, rs1: u32, src2: Operand) -> Inst {
+    Inst { dest: Operand::Gpr(rd), src1: Some(rs1), src2, ..Inst::new(opcode) }
+}
+
+/// Whether an instruction can run whichever way the branch goes: arithmetic only, and nothing
+/// tied to its address.
+fn speculable(inst: &Inst) -> bool {
+    matches!(inst.opcode.eu_type(), ExecutionUnit::ALU | ExecutionUnit::Mult)
+        && !matches!(inst.opcode, Opcode::MOV | Opcode::AUIPC)
+        && inst.label == Label::None
+}
+
+/// `arm` writing to registers from `free` instead, with the register each original one went to.
+fn speculate(arm: &[Inst], free: &mut impl Iterator<Item = u32>) -> Option<(Vec<Inst>, HashMap<u32, u32>)> {
+    let mut renames: HashMap<u32, u32> = HashMap::new();
+    let mut out = Vec::new();
+    for inst in arm {
+        let mut inst = *inst;
+        // free registers never appear in the arm, so renames don't chain
+        for (from, to) in renames.iter() {
+            rename_reads(&mut inst, *from, *to);
+        }
+        if let Some(rd) = reg_write(&inst) {
+            let to = match renames.get(&rd) {
+                Some(to) => *to,
+                None => {
+                    let to = free.next()?;
+                    renames.insert(rd, to);
+                    to
+                }
+            };
+            inst.dest = Operand::Gpr(to);
+        }
+        out.push(inst);
+    }
+    Some((out, renames))
+}
+
+/// Straight-line code for the branch ending block `b`, if its successors form a short hammock
+/// (a fall-through arm joining the taken target) or diamond (a fall-through arm jumping over the
+/// taken arm to the join). Both arms run into free registers; the branch condition then becomes
+/// a mask, and `r = e ^ ((e ^ t) & mask)` picks each live register from the side that would have
+/// run. The join is appended too when nothing else reaches it.
+///
+/// Returns the new block, the index of the first block after the ones it replaces, and stats.
+/// New instructions after the first take addresses from `next` on.
+fn convert(cfg: &Cfg, live: &Solution<u32>, code: &[Vec<Inst>], b: usize, next: usize) -> Option<(Vec<Inst>, usize, IfConverted)> {
+    let exits = &cfg.exits[b];
+    let (f, t) = (exits.fall?, exits.taken?);
+    if !exits.conditional || f != b + 1 {
+        return None;
+    }
+    let only_from_b = |a: usize| !cfg.external[a] && cfg.preds[a].iter().all(|(p, _)| *p == b);
+    let ends_in_cf = |a: usize| code[a].last().is_some_and(|i| i.opcode.is_control_flow());
+    if !only_from_b(f) {
+        return None;
+    }
+    let (then_arm, else_arm, join) = if t == f + 1 && !ends_in_cf(f) {
+        (&code[f][..], &[][..], t)
+    } else if t == f + 1 && only_from_b(t) && !ends_in_cf(t)
+        && code[f].last().is_some_and(|i| i.opcode == Opcode::J)
+        && cfg.exits[f].taken == Some(t + 1) {
+        (&code[f][..code[f].len() - 1], &code[t][..], t + 1)
+    } else {
+        return None;
+    };
+    if then_arm.len() > MAX_ARM || else_arm.len() > MAX_ARM
+        || !then_arm.iter().chain(else_arm).all(speculable) {
+        return None;
+    }
+
+    let (cf, head) = code[b].split_last()?;
+    let used = code[b].iter().chain(then_arm).chain(else_arm).fold(0u32, |mask, i| {
+        reg_reads(i).into_iter().chain(reg_write(i)).fold(mask, |m, r| m | (1 << r))
+    });
+    let busy = used | live.entry[f] | live.entry[t] | live.entry[join];
+    let mut free = POOL.iter().copied().filter(|r| busy & (1 << r) == 0);
+    let (c, m) = (free.next()?, free.next()?);
+    let (then_code, then_regs) = speculate(then_arm, &mut free)?;
+    let (else_code, else_regs) = speculate(else_arm, &mut free)?;
+
+    // registers live at the join that either side writes, with their value on each side
+    let mut written: Vec<u32> = then_regs.keys().chain(else_regs.keys()).copied()
+        .filter(|r| live.entry[join] & (1 << r) != 0)
+        .collect();
+    written.sort();
+    written.dedup();
+    let picks: Vec<(u32, Option<u32>, Option<u32>)> = written.iter()
+        .map(|r| (*r, then_regs.get(r).copied(), else_regs.get(r).copied()))
+        .collect();
+
+    // c is 1 on one side of the branch and 0 on the other; branches keep their left operand in src2
+    let (lhs, rhs) = (cf.src2.unwrap_gpr(), cf.src1?);
+    let (mut out, c_on_fall) = match cf.opcode {
+        Opcode::BEQ | Opcode::BNE => (vec![
+            alu(Opcode::XOR, c, lhs, Operand::Gpr(rhs)),
+            alu(Opcode::SLTU, c, 0, Operand::Gpr(c)),
+        ], cf.opcode == Opcode::BEQ),
+        Opcode::BLT | Opcode::BGE => (vec![alu(Opcode::SLT, c, lhs, Operand::Gpr(rhs))], cf.opcode == Opcode::BGE),
+        Opcode::BLTU | Opcode::BGEU => (vec![alu(Opcode::SLTU, c, lhs, Operand::Gpr(rhs))], cf.opcode == Opcode::BGEU),
+        _ => return None,
+    };
+    // all ones on the side the mask is for: m on the fall-through side, c on the taken side
+    let negate = |rd| alu(Opcode::SUB, rd, 0, Operand::Gpr(c));
+    let decrement = |rd| alu(Opcode::ADDI, rd, c, Operand::Immediate(-1));
+    if picks.iter().any(|(_, then, _)| then.is_some()) {
+        out.push(if c_on_fall { negate(m) } else { decrement(m) });
+    }
+    if picks.iter().any(|(_, then, _)| then.is_none()) {
+        out.push(if c_on_fall { decrement(c) } else { negate(c) });
+    }
+
+    let mut selects = Vec::new();
+    for (r, then, other) in picks.iter() {
+        // x = the side's value ^ the other's, masked to the side, then flipped into the other
+        let (x, base, mask) = match (then, other) {
+            (Some(t), Some(e)) => (*t, *e, m),
+            (Some(t), None) => (*t, *r, m),
+            (None, Some(e)) => (*e, *r, c),
+            (None, None) => continue,
+        };
+        selects.extend([
+            alu(Opcode::XOR, x, x, Operand::Gpr(base)),
+            alu(Opcode::AND, x, x, Operand::Gpr(mask)),
+            alu(Opcode::XOR, *r, base, Operand::Gpr(x)),
+        ]);
+    }
+    // the first takes over the branch's address, which may be where the block starts
+    let addrs = std::iter::once(cf.addr).chain((next..).step_by(4));
+    for (inst, addr) in out.iter_mut().chain(selects.iter_mut()).zip(addrs) {
+        inst.addr = addr;
+    }
+
+    let stats = IfConverted { addr: cf.addr, then_len: then_arm.len(), else_len: else_arm.len(), selects: picks.len() };
+    let mut block: Vec<Inst> = head.to_vec();
+    block.append(&mut out);
+    block.extend(then_code);
+    block.extend(else_code);
+    block.append(&mut selects);
+    // falling into a join nothing else reaches
+    let mut end = join;
+    if !cfg.external[join] && cfg.preds[join].iter().all(|(p, _)| (b..join).contains(p)) {
+        block.extend(code[join].iter().copied());
+        end += 1;
+    }
+    Some((block, end, stats))
+}
+
+/// Turns short hammocks and diamonds into straight-line RV32I code that computes both sides and
+/// selects the live results with masks, so they schedule as one block. Repeats until nothing
+/// changes, so arms that only become straight once an inner branch is converted go too. New
+/// instructions get fresh source addresses past the end of the code.
+pub fn if_convert(prog: AnalyzedProgram) -> (AnalyzedProgram, Vec<IfConverted>) {
+    let mut prog = prog;
+    let mut converted = Vec::new();
+    let mut next = prog.bbs.iter()
+        .flat_map(|bb| bb.insns.iter().chain(bb.cf_insn.as_ref()))
+        .map(|i| i.inst.addr + 4)
+        .max()
+        .unwrap_or(0);
+    loop {
+        let cfg = Cfg::new(&prog);
+        let live = liveness(&prog, &cfg);
+        let code: Vec<Vec<Inst>> = prog.bbs.iter()
+            .map(|bb| bb.insns.iter().chain(bb.cf_insn.as_ref()).map(|d| d.inst).collect())
+            .collect();
+        let Some((b, (block, end, stats))) = (0..code.len()).find_map(|b| convert(&cfg, &live, &code, b, next).map(|c| (b, c))) else {
+            break;
+        };
+        next = next.max(block.iter().map(|i| i.addr + 4).max().unwrap_or(0));
+        converted.push(stats);
+        let bbs = code[..b].iter().cloned()
+            .chain(std::iter::once(block))
+            .chain(code[end..].iter().cloned())
+            .map(dep_analysis)
+            .collect();
+        prog = AnalyzedProgram { bbs, ..prog };
+    }
+    (prog, converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::if_convert;
+    use crate::machine::MachineModel;
+    use crate::rename::rename_registers;
+    use crate::scheduling::{schedule_program, Scheduler};
+    use crate::testutil::{analyze, assert_same};
+
+    /// If-converts and checks each scheduler against the program as is.
+    fn assert_converted_same(src: &str, regs: [u32; 32], mem: &HashMap<u32, u32>) {
+        let model = MachineModel::default();
+        let (converted, _) = if_convert(analyze(src));
+        let (renamed, _) = rename_registers(converted.clone());
+        for (prog, scheduler) in [(&converted, Scheduler::List), (&converted, Scheduler::Modulo), (&renamed, Scheduler::Superblock)] {
+            assert_same(src, &schedule_program(prog.clone(), &model, scheduler), regs, mem);
+        }
+    }
+
+    #[test]
+    fn test_convert_hammock() {
+        // abs(a0) + a1, with the sum kept only when a1 < a0
+        let src = "
+                bge a0, x0, 1f
+                sub a0, x0, a0
+            1:  add t0, a0, a1
+                bltu a0, a1, 2f
+                mv a1, t0
+            2:  ret
+        ";
+        let (prog, converted) = if_convert(analyze(src));
+        assert_eq!(converted.len(), 2);
+        assert_eq!((converted[0].then_len, converted[0].else_len, converted[0].selects), (1, 0, 1));
+        // everything joins up into one block
+        assert_eq!(prog.bbs.len(), 1);
+        for (a0, a1) in [(5, 3), (-5i32 as u32, 3), (0, 0), (3, 9), (-1i32 as u32, 1 << 31)] {
+            let mut regs = [0; 32];
+            regs[10] = a0;
+            regs[11] = a1;
+            assert_converted_same(src, regs, &HashMap::new());
+        }
+    }
+
+    #[test]
+    fn test_convert_diamond() {
+        // a0 bumped when it equals a1, else a1 doubled, in a loop summing a0 into a2
+        let src = "
+                li a2, 0
+            1:  bne a0, a1, 2f
+                addi a0, a0, 1
+                j 3f
+            2:  slli t1, a1, 1
+                mv a1, t1
+            3:  add a2, a2, a0
+                addi a3, a3, -1
+                bgtz a3, 1b
+                mv a0, a2
+                ret
+        ";
+        let (prog, converted) = if_convert(analyze(src));
+        assert_eq!(converted.len(), 1);
+        assert_eq!((converted[0].then_len, converted[0].else_len, converted[0].selects), (1, 2, 2));
+        // the loop is a single block now
+        assert_eq!(prog.bbs.len(), 3);
+        for (a0, a1) in [(1, 1), (4, 2), (-3i32 as u32, 7)] {
+            let mut regs = [0; 32];
+            regs[10] = a0;
+            regs[11] = a1;
+            regs[13] = 3;
+            assert_converted_same(src, regs, &HashMap::new());
+        }
+    }
+
+    #[test]
+    fn test_keep_unsafe_arms() {
+        // a load may fault on the side that doesn't run, and a block reached from elsewhere can't go
+        let src = "
+                beqz a0, 1f
+                lw a1, 0(a0)
+            1:  blt a1, a2, 2f
+            3:  addi a1, a1, 1
+            2:  bnez a3, 3b
+                ret
+        ";
+        assert!(if_convert(analyze(src)).1.is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::elf::Section;
+use crate::isa::{Inst, Label, Opcode, Operand};
+
+// MMIO of Core.bsv, restated here so the reference doesn't share the core's code
+const PUTCHAR_ADDR: u32 = 0xf000_fff0;
+const EXIT_ADDR: u32 = 0xf000_fff8;
+
+/// How the program stopped: the value stored to the exit address, the address of
+/// that store and how many instructions ran, the store included.
+pub struct Exit {
+    pub code: u32,
+    pub addr: usize,
+    pub insns: u64,
+}
+
+/// Byte-addressed data memory, zero where nothing was loaded or stored.
+pub struct Memory {
+    bytes: HashMap<u32, u8>,
+    // word addresses written since loading
+    stored: BTreeSet<u32>,
+}
+
+impl Memory {
+    /// The word containing byte `addr`.
+    pub fn read(&self, addr: u32) -> u32 {
+        let addr = addr & !3;
+        (0..4).fold(0, |word, i| word | (self.byte(addr.wrapping_add(i)) as u32) << (8 * i))
+    }
+
+    /// Byte addresses of the words stores have written to.
+    pub fn stored(&self) -> impl Iterator<Item = u32> + '_ {
+        self.stored.iter().map(|w| w << 2)
+    }
+
+    fn byte(&self, addr: u32) -> u8 {
+        self.bytes.get(&addr).copied().unwrap_or(0)
+    }
+
+    fn load(&self, addr: u32, len: u32) -> u32 {
+        (0..len).fold(0, |value, i| value | (self.byte(addr.wrapping_add(i)) as u32) << (8 * i))
+    }
+
+    fn store(&mut self, addr: u32, len: u32, value: u32) {
+        for i in 0..len {
+            self.bytes.insert(addr.wrapping_add(i), (value >> (8 * i)) as u8);
+        }
+        self.stored.insert(addr >> 2);
+    }
+}
+
+/// Plain sequential RV32IM over the instructions themselves, one at a time, with the
+/// MMIO of the simulated core but without its data offset. Runs the original program as
+/// the oracle for its schedule, so it shares no code with the core or the assembler.
+pub struct Interpreter {
+    pub mem: Memory,
+    pub regs: [u32; 32],
+    pc: u32,
+    code: HashMap<u32, Inst>,
+    // putchar and bus stores, as the core writes them to STDERR
+    pub console: Vec<u8>,
+}
+
+fn gpr(op: Operand) -> usize {
+    match op {
+        Operand::Gpr(r) => r as usize,
+        _ => 0,
+    }
+}
+
+fn imm(op: Operand) -> u32 {
+    match op {
+        Operand::Immediate(i) => i as u32,
+        _ => 0,
+    }
+}
+
+/// The M extension, where division by zero and overflow don't trap.
+fn mul_div(opcode: Opcode, a: u32, b: u32) -> u32 {
+    let (sa, sb) = (a as i32, b as i32);
+    match opcode {
+        Opcode::MUL => a.wrapping_mul(b),
+        Opcode::MULH => ((sa as i64 * sb as i64) >> 32) as u32,
+        Opcode::MULHSU => ((sa as i64 * b as i64) >> 32) as u32,
+        Opcode::MULHU => ((a as u64 * b as u64) >> 32) as u32,
+        Opcode::DIV if b == 0 => u32::MAX,
+        Opcode::DIV => sa.wrapping_div(sb) as u32,
+        Opcode::DIVU => a.checked_div(b).unwrap_or(u32::MAX),
+        Opcode::REM if b == 0 => a,
+        Opcode::REM => sa.wrapping_rem(sb) as u32,
+        _ => a.checked_rem(b).unwrap_or(a),
+    }
+}
+
+/// What an arithmetic instruction computes from its two operands.
+fn arith(opcode: Opcode, a: u32, b: u32) -> Option<u32> {
+    Some(match opcode {
+        Opcode::ADD | Opcode::ADDI => a.wrapping_add(b),
+        Opcode::SUB => a.wrapping_sub(b),
+        Opcode::XOR | Opcode::XORI => a ^ b,
+        Opcode::OR | Opcode::ORI => a | b,
+        Opcode::AND | Opcode::ANDI => a & b,
+        Opcode::SLL | Opcode::SLLI => a << (b & 31),
+        Opcode::SRL | Opcode::SRLI => a >> (b & 31),
+        Opcode::SRA | Opcode::SRAI => ((a as i32) >> (b & 31)) as u32,
+        Opcode::SLT | Opcode::SLTI => ((a as i32) < (b as i32)) as u32,
+        Opcode::SLTU | Opcode::SLTIU => (a < b) as u32,
+        Opcode::MUL | Opcode::MULH | Opcode::MULHSU | Opcode::MULHU |
+        Opcode::DIV | Opcode::DIVU | Opcode::REM | Opcode::REMU => mul_div(opcode, a, b),
+        _ => return None,
+    })
+}
+
+/// Whether a branch is taken, `a` being its first operand.
+fn taken(opcode: Opcode, a: u32, b: u32) -> bool {
+    match opcode {
+        Opcode::BEQ => a == b,
+        Opcode::BNE => a != b,
+        Opcode::BLT => (a as i32) < (b as i32),
+        Opcode::BGE => (a as i32) >= (b as i32),
+        Opcode::BLTU => a < b,
+        _ => a >= b,
+    }
+}
+
+impl Interpreter {
+    /// The trace runs where its addresses say, data sections past the original code
+    /// go where the ELF put them.
+    pub fn new(trace: &[Inst], sections: &[Section], orig_size: usize) -> Self {
+        let code = trace.iter().map(|inst| (inst.addr as u32, *inst)).collect();
+        let mut mem = Memory { bytes: HashMap::new(), stored: BTreeSet::new() };
+        for section in sections.iter().filter(|s| s.addr >= orig_size) {
+            for (i, b) in section.data.iter().enumerate() {
+                mem.bytes.insert((section.addr + i) as u32, *b);
+            }
+        }
+        Self { mem, regs: [0; 32], pc: 0, code, console: Vec::new() }
+    }
+
+    /// A load or store of `len` bytes, with the MMIO requests `Core.bsv` handles. Returns
+    /// the loaded value and, for a store to the exit address, the value stored.
+    fn access(&mut self, store: bool, addr: u32, len: u32, value: u32) -> Result<(u32, Option<u32>), String> {
+        if !addr.is_multiple_of(len) {
+            return Err(format!("misaligned {}-byte access to {:08x}", len, addr));
+        }
+        if addr >> 29 != 0b111 {
+            if store {
+                self.mem.store(addr, len, value);
+                return Ok((0, None));
+            }
+            return Ok((self.mem.load(addr, len), None));
+        }
+        match (store, len) {
+            (true, 4) if addr == EXIT_ADDR => return Ok((0, Some(value))),
+            (true, 4) if addr == PUTCHAR_ADDR => self.console.push(value as u8),
+            (true, 4) if addr >> 28 == 0xe => {
+                self.console.extend(format!("Bus Request: store {} = {}\n", addr, value).bytes());
+            }
+            (true, 4) => {}
+            (false, _) if addr >> 28 == 0xe => {}
+            (false, _) => return Err(format!("load from {:08x} never gets an MMIO response", addr)),
+            (true, _) => return Err(format!("Illegal sub-word MMIO access to {:08x}", addr)),
+        }
+        Ok((0, None))
+    }
+
+    /// Runs until the program stores to the exit address.
+    pub fn run(&mut self, max_insns: u64) -> Result<Exit, String> {
+        for insns in 1..=max_insns {
+            let inst = *self.code.get(&self.pc)
+                .ok_or_else(|| format!("no instruction at {:#x}", self.pc))?;
+            let rs1 = self.regs[inst.src1.unwrap_or(0) as usize];
+            let rs2 = self.regs[gpr(inst.src2)];
+            let offset = inst.offset.unwrap_or(0) as u32;
+            let target = match inst.label {
+                Label::SrcAddrSpace(l) | Label::DstAddrSpace(l) => l as u32,
+                Label::None => 0,
+            };
+            let mut next = self.pc.wrapping_add(4);
+            let result = match inst.opcode {
+                Opcode::LB | Opcode::LH | Opcode::LW | Opcode::LBU | Opcode::LHU => {
+                    let len = match inst.opcode { Opcode::LB | Opcode::LBU => 1, Opcode::LH | Opcode::LHU => 2, _ => 4 };
+                    let (value, _) = self.access(false, rs1.wrapping_add(offset), len, 0)?;
+                    match inst.opcode {
+                        Opcode::LB => value as u8 as i8 as u32,
+                        Opcode::LH => value as u16 as i16 as u32,
+                        _ => value,
+                    }
+                }
+                Opcode::SB | Opcode::SH | Opcode::SW => {
+                    // stores keep the value in src1 and the base in src2
+                    let len = match inst.opcode { Opcode::SB => 1, Opcode::SH => 2, _ => 4 };
+                    if let (_, Some(code)) = self.access(true, rs2.wrapping_add(offset), len, rs1)? {
+                        return Ok(Exit { code, addr: self.pc as usize, insns });
+                    }
+                    0
+                }
+                // branches keep their first operand in src2
+                Opcode::BEQ | Opcode::BNE | Opcode::BLT | Opcode::BGE | Opcode::BLTU | Opcode::BGEU => {
+                    if taken(inst.opcode, rs2, rs1) {
+                        next = target;
+                    }
+                    0
+                }
+                Opcode::J | Opcode::JAL => {
+                    next = target;
+                    self.pc.wrapping_add(4)
+                }
+                Opcode::JALR => {
+                    next = rs1.wrapping_add(offset) & !1;
+                    self.pc.wrapping_add(4)
+                }
+                Opcode::LUI => imm(inst.src2) << 12,
+                Opcode::AUIPC => self.pc.wrapping_add(imm(inst.src2) << 12),
+                Opcode::FENCE => 0,
+                op => {
+                    let b = if let Operand::Immediate(i) = inst.src2 { i as u32 } else { rs2 };
+                    arith(op, rs1, b).ok_or_else(|| format!("can't execute {} at {:#x}", inst, self.pc))?
+                }
+            };
+            let rd = gpr(inst.dest);
+            if rd != 0 {
+                self.regs[rd] = result;
+            }
+            self.pc = next;
+        }
+        Err(format!("no exit after {} instructions (pc {:x})", max_insns, self.pc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interpreter;
+    use crate::elf::Section;
+    use crate::parser::parse_asm;
+
+    #[test]
+    fn test_run_program() {
+        let src = "    li t0, 0xf000fff0
+                        lui a0, 0x1
+                        lw a1, 0(a0)
+                        li a2, 0
+                    1:  lbu a3, 0(a1)
+                        beqz a3, 2f
+                        sw a3, 0(t0)
+                        addi a1, a1, 1
+                        addi a2, a2, 1
+                        j 1b
+                    2:  sb a2, 5(a0)
+                        jal ra, 3f
+                        addi a2, a2, -2
+                        sw a2, 8(t0)
+                    3:  jalr x0, 0(ra)";
+        let (trace, _) = parse_asm(src).unwrap();
+        let orig_size = trace.len() * 4;
+        // a pointer to the string that follows it
+        let data = Section { name: String::from(".data"), addr: 0x1000, size: 8, data: vec![4, 0x10, 0, 0, b'h', b'i', 0, 0] };
+        let mut interp = Interpreter::new(&trace, &[data], orig_size);
+        let exit = interp.run(1000).unwrap();
+        assert_eq!(interp.console, b"hi");
+        assert_eq!(exit.code, 0);
+        assert_eq!(exit.addr, trace.iter().find(|i| i.to_string().starts_with("sw x12")).unwrap().addr);
+        assert_eq!(interp.regs[1], exit.addr as u32 - 4);
+        // the byte store leaves the rest of the word alone
+        assert_eq!(interp.mem.read(0x1004), 0x0000_0268);
+        assert_eq!(interp.mem.stored().collect::<Vec<_>>(), vec![0x1004]);
+    }
+
+    #[test]
+    fn test_mul_div_edges() {
+        let src = "    li t0, 0xf000fff0
+                        li a0, 0x80000000
+                        li a1, -1
+                        div a2, a0, a1
+                        rem a3, a0, a1
+                        divu a4, a0, x0
+                        rem a5, a0, x0
+                        mulh a6, a0, a1
+                        mulhsu a7, a1, a0
+                        sw x0, 8(t0)";
+        let (trace, _) = parse_asm(src).unwrap();
+        let mut interp = Interpreter::new(&trace, &[], trace.len() * 4);
+        interp.run(100).unwrap();
+        // overflow and division by zero don't trap
+        assert_eq!(interp.regs[12..18], [0x8000_0000, 0, 0xffff_ffff, 0x8000_0000, 0, 0xffff_ffff]);
+    }
+}
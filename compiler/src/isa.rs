@@ -1,8 +1,9 @@
-use std::{collections::HashMap, fmt};
+use std::fmt;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Opcode {
     // Arithmetic registers
@@ -16,6 +17,15 @@ pub enum Opcode {
     SRA,
     SLT,
     SLTU,
+    // Multiply/divide (M extension)
+    MUL,
+    MULH,
+    MULHSU,
+    MULHU,
+    DIV,
+    DIVU,
+    REM,
+    REMU,
     // Arithmetic immediates
     ADDI,
     XORI,
@@ -50,11 +60,12 @@ pub enum Opcode {
     // Others
     LUI,
     AUIPC,
-    // Pseudos
-    LI,
+    // System
+    FENCE,
+    ECALL,
+    EBREAK,
+    // Predicate and loop counter moves, no RV32I encoding
     MOV,
-    NOP,
-    RET,
 }
 
 impl Opcode {
@@ -70,6 +81,14 @@ impl Opcode {
             "sra" => Ok(Self::SRA),
             "slt" => Ok(Self::SLT),
             "sltu" => Ok(Self::SLTU),
+            "mul" => Ok(Self::MUL),
+            "mulh" => Ok(Self::MULH),
+            "mulhsu" => Ok(Self::MULHSU),
+            "mulhu" => Ok(Self::MULHU),
+            "div" => Ok(Self::DIV),
+            "divu" => Ok(Self::DIVU),
+            "rem" => Ok(Self::REM),
+            "remu" => Ok(Self::REMU),
             "addi" => Ok(Self::ADDI),
             "xori" => Ok(Self::XORI),
             "ori" => Ok(Self::ORI),
@@ -98,15 +117,15 @@ impl Opcode {
             "jalr" => Ok(Self::JALR),
             "lui" => Ok(Self::LUI),
             "auipc" => Ok(Self::AUIPC),
-            "li" => Ok(Self::LI),
-            "mv" => Ok(Self::MOV),
-            "nop" => Ok(Self::NOP),
-            "ret" => Ok(Self::RET),
+            "fence" => Ok(Self::FENCE),
+            "ecall" => Ok(Self::ECALL),
+            "ebreak" => Ok(Self::EBREAK),
+            "mov" => Ok(Self::MOV),
             _ => Err(format!("Unrecognized opcode: {}", op))
         }
     }
 
-    pub fn to_str(&self) -> &str {
+    pub fn to_str(self) -> &'static str {
         match self {
             Self::ADD => "add",
             Self::SUB => "sub",
@@ -118,6 +137,14 @@ impl Opcode {
             Self::SRA => "sra",
             Self::SLT => "slt",
             Self::SLTU => "sltu",
+            Self::MUL => "mul",
+            Self::MULH => "mulh",
+            Self::MULHSU => "mulhsu",
+            Self::MULHU => "mulhu",
+            Self::DIV => "div",
+            Self::DIVU => "divu",
+            Self::REM => "rem",
+            Self::REMU => "remu",
             Self::ADDI => "addi",
             Self::XORI => "xori",
             Self::ORI => "ori",
@@ -146,10 +173,10 @@ impl Opcode {
             Self::JALR => "jalr",
             Self::LUI => "lui",
             Self::AUIPC => "auipc",
-            Self::LI => "li",
-            Self::MOV => "mv",
-            Self::NOP => "nop",
-            Self::RET => "ret",
+            Self::FENCE => "fence",
+            Self::ECALL => "ecall",
+            Self::EBREAK => "ebreak",
+            Self::MOV => "mov",
         }
     }
 
@@ -160,14 +187,17 @@ impl Opcode {
             Self::SLT | Self::SLTU | Self::ADDI | Self::XORI |
             Self::ORI | Self::ANDI | Self::SLLI | Self::SRLI |
             Self::SRAI | Self::SLTI | Self::SLTIU |
-            Self::LUI | Self::AUIPC | Self::MOV | Self::LI => ExecutionUnit::ALU,
+            Self::LUI | Self::AUIPC | Self::MOV => ExecutionUnit::ALU,
+            Self::MUL | Self::MULH | Self::MULHSU | Self::MULHU |
+            Self::DIV | Self::DIVU | Self::REM | Self::REMU => ExecutionUnit::Mult,
     
             Self::BEQ | Self::BNE | Self::BLT |
             Self::BGE | Self::BLTU | Self::BGEU |
-            Self::J | Self::JAL | Self::RET | Self::JALR => ExecutionUnit::Branch,
+            Self::J | Self::JAL | Self::JALR |
+            Self::ECALL | Self::EBREAK => ExecutionUnit::Branch,
             Self::LB | Self::LH | Self::LW | Self::LBU |
-            Self::LHU | Self::SB | Self::SH | Self::SW => ExecutionUnit::Mem,
-            _ => panic!("unrecognized execution unit: {:#?}", self),
+            Self::LHU | Self::SB | Self::SH | Self::SW |
+            Self::FENCE => ExecutionUnit::Mem,
         }
     }
 
@@ -175,18 +205,20 @@ impl Opcode {
         match self {
             Self::ADD | Self::SUB | Self::XOR | Self::OR | 
             Self::AND | Self::SLL | Self::SRL | Self::SRA |
-            Self::SLT | Self::SLTU => InstParseFormat::R,
+            Self::SLT | Self::SLTU |
+            Self::MUL | Self::MULH | Self::MULHSU | Self::MULHU |
+            Self::DIV | Self::DIVU | Self::REM | Self::REMU => InstParseFormat::R,
             Self::ADDI | Self::XORI | Self::ORI | Self::ANDI |
             Self::SLLI | Self::SRLI | Self::SRAI | Self::SLTI |
             Self::SLTIU => InstParseFormat::I,
             Self::BEQ | Self::BNE | Self::BLT |
             Self::BGE | Self::BLTU | Self::BGEU => InstParseFormat::B,
-            Self::LB | Self::LH | Self::LW | Self::LBU | Self::LHU => InstParseFormat::L,
+            // jalr rd, imm(rs1) shares the syntax and I-type encoding of loads
+            Self::LB | Self::LH | Self::LW | Self::LBU | Self::LHU | Self::JALR => InstParseFormat::L,
             Self::SB | Self::SH | Self::SW => InstParseFormat::S,
             Self::J | Self::JAL => InstParseFormat::J,
-            Self::LUI | Self::AUIPC | Self::MOV | Self::LI => InstParseFormat::MOV,
-            Self::NOP | Self::RET => InstParseFormat::NOP,
-            Self::JALR => panic!("jalr will make the universe explode"),
+            Self::LUI | Self::AUIPC | Self::MOV => InstParseFormat::MOV,
+            Self::FENCE | Self::ECALL | Self::EBREAK => InstParseFormat::SYS,
         }
     }
 
@@ -194,14 +226,13 @@ impl Opcode {
         match self.parse_format() {
             InstParseFormat::R => 0b0110011,
             InstParseFormat::I => 0b0010011,
-            InstParseFormat::L => 0b0000011,
+            InstParseFormat::L => if *self == Self::JALR { 0b1100111 } else { 0b0000011 },
             InstParseFormat::S => 0b0100011,
             InstParseFormat::B => 0b1100011,
             InstParseFormat::J => {
                 match self {
                     Self::J => 0b1101111,
                     Self::JAL => 0b1101111,
-                    Self::JALR => 0b1100111,
                     _ => unreachable!()
                 }
             },
@@ -209,9 +240,8 @@ impl Opcode {
                 match self {
                     Self::LUI => 0b0110111,
                     Self::AUIPC => 0b0010111,
-                    Self::MOV => 0b0010011, // TODO
-                    Self::LI => 0b0010011,
-                    Self::RET => 0b1100111,
+                    Self::FENCE => 0b0001111,
+                    Self::ECALL | Self::EBREAK => 0b1110011,
                     _ => unreachable!(),
                 }
             }
@@ -230,6 +260,14 @@ impl Opcode {
             Self::SRA => 0x5,
             Self::SLT => 0x2,
             Self::SLTU => 0x3,
+            Self::MUL => 0x0,
+            Self::MULH => 0x1,
+            Self::MULHSU => 0x2,
+            Self::MULHU => 0x3,
+            Self::DIV => 0x4,
+            Self::DIVU => 0x5,
+            Self::REM => 0x6,
+            Self::REMU => 0x7,
             Self::ADDI => 0x0,
             Self::XORI => 0x4,
             Self::ORI => 0x6,
@@ -255,12 +293,11 @@ impl Opcode {
             Self::BGEU => 0x7,
             Self::JAL => 0x0,
             Self::JALR => 0x0,
-            Self::RET => 0x0,
             Self::LUI => 0x0,
             Self::AUIPC => 0x0,
-            // TODO
-            Self::LI => 0x0,
-            Self::MOV => 0x0,
+            Self::FENCE => 0x0,
+            Self::ECALL => 0x0,
+            Self::EBREAK => 0x0,
             _ => unreachable!(),
         }
     }
@@ -269,20 +306,36 @@ impl Opcode {
         match self {
             Self::SUB => 0x20,
             Self::SRA => 0x20,
+            Self::SRAI => 0x20,
+            Self::MUL | Self::MULH | Self::MULHSU | Self::MULHU |
+            Self::DIV | Self::DIVU | Self::REM | Self::REMU => 0x1,
             _ => 0x0,
         }
     }
 
     pub fn is_control_flow(&self) -> bool {
-        match self {
+        matches!(self,
             Self::BEQ | Self::BNE | Self::BLT |
             Self::BGE | Self::BLTU | Self::BGEU |
-            Self::J | Self::JAL | Self::JALR | Self::RET => true,
-            _ => false
+            Self::J | Self::JAL | Self::JALR |
+            Self::ECALL | Self::EBREAK)
+    }
+
+    /// The branch taken exactly when this one falls through.
+    pub fn inverted_branch(&self) -> Option<Self> {
+        match self {
+            Self::BEQ => Some(Self::BNE),
+            Self::BNE => Some(Self::BEQ),
+            Self::BLT => Some(Self::BGE),
+            Self::BGE => Some(Self::BLT),
+            Self::BLTU => Some(Self::BGEU),
+            Self::BGEU => Some(Self::BLTU),
+            _ => None,
         }
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum InstParseFormat {
     R,
@@ -292,10 +345,12 @@ pub enum InstParseFormat {
     B,
     J,
     MOV,
-    NOP
+    SYS
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ExecutionUnit {
     ALU,
     Mult,
@@ -303,18 +358,7 @@ pub enum ExecutionUnit {
     Branch,
 }
 
-impl ExecutionUnit {
-    pub fn latency(&self) -> usize {
-        match self {
-            Self::ALU => 1,
-            Self::Mult => 3,
-            Self::Mem => 1,
-            Self::Branch => 1,
-        }
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operand {
     Gpr(u32),
     Predicate(u32),
@@ -325,13 +369,29 @@ pub enum Operand {
     None,
 }
 
+/// RISC-V calling convention names for x0-x31, in register order.
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
 impl Operand {
-    fn from_str(op: &str) -> Result<Self, String> {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(op: &str) -> Result<Self, String> {
+        let op = op.trim();
+        if let Some(reg) = ABI_NAMES.iter().position(|name| *name == op) {
+            return Ok(Self::Gpr(reg as u32));
+        }
+        if op == "fp" {
+            return Ok(Self::Gpr(8));
+        }
         let mut chars = op.chars();
         match chars.next() {
             Some('x') => {
                 let Ok(reg) = chars.collect::<String>().parse::<u32>() else { return Err(format!("Register parse error: {}", op)) };
-                if reg > 95 {
+                if reg > 31 {
                     Err(format!("Unrecognized architectural register {}", reg))
                 } else {
                     Ok(Self::Gpr(reg))
@@ -367,8 +427,10 @@ impl Operand {
                 let imm = match op.parse::<i64>() {
                     Ok(imm) => imm,
                     Err(_) => {
-                        if op.starts_with("0x") {
-                            i64::from_str_radix(&op[2..], 16).map_err(|e| format!("Hex parse error: {}", e))?
+                        if let Some(hex) = op.strip_prefix("0x") {
+                            i64::from_str_radix(hex, 16).map_err(|e| format!("Hex parse error: {}", e))?
+                        } else if let Some(bin) = op.strip_prefix("0b") {
+                            i64::from_str_radix(bin, 2).map_err(|e| format!("Binary parse error: {}", e))?
                         } else {
                             return Err(format!("Unrecognized token: {}", op))
                         }
@@ -379,6 +441,8 @@ impl Operand {
         }
     }
 
+    /// The register of an operand its instruction format says is a GPR, as `Inst::from_str`
+    /// and the decoder check.
     pub fn unwrap_gpr(&self) -> u32 {
         match self {
             Self::Gpr(r) => *r,
@@ -402,7 +466,7 @@ impl fmt::Display for Operand {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum Label {
     SrcAddrSpace(usize),
     DstAddrSpace(usize),
@@ -412,7 +476,7 @@ pub enum Label {
 impl fmt::Display for Label {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Label::DstAddrSpace(i) | Label::SrcAddrSpace(i) => write!(f, "{:x}", i),
+            Label::DstAddrSpace(i) | Label::SrcAddrSpace(i) => write!(f, "{:#x}", i),
             Label::None => write!(f, ""),
         }?;
         Ok(())
@@ -440,9 +504,9 @@ fn parse_i_r_b_format_inst(opcode: Opcode, remaining_line: String) -> Result<Ins
     }
 
     let dest = Operand::from_str(remaining.next().unwrap())?;
-    let Operand::Gpr(_) = dest else { return Err(format!("dest must be a register of the form xN."))};
+    let Operand::Gpr(_) = dest else { return Err(String::from("dest must be a register of the form xN."))};
     let src1 = Operand::from_str(remaining.next().unwrap())?;
-    let Operand::Gpr(src1) = src1 else { return Err(format!("src1 must be a register of the form xN."))};
+    let Operand::Gpr(src1) = src1 else { return Err(String::from("src1 must be a register of the form xN."))};
     let src1 = Some(src1);
     let src2 = Operand::from_str(remaining.next().unwrap())?;
     if let InstParseFormat::I = opcode.parse_format() {
@@ -496,16 +560,16 @@ fn parse_l_s_format_inst(opcode: Opcode, remaining_line: String) -> Result<Inst,
         return Err(format!("Incorrect number of operands, expected 2, got {}", operand_cnt));
     }
     let reg = Operand::from_str(remaining.next().unwrap())?;
-    let Operand::Gpr(reg) = reg else { return Err(format!("dest must be a register of the form xN."))};
+    let Operand::Gpr(reg) = reg else { return Err(String::from("dest must be a register of the form xN."))};
     
-    let mem_loc = String::from(remaining.next().unwrap().split_once(")").unwrap().0);
-    let mut mem_loc = mem_loc.split("(");
+    let mem_loc = remaining.next().unwrap().split_once(")").and_then(|(m, _)| m.split_once("("));
+    let Some((ofs, base)) = mem_loc else { return Err(String::from("Memory operand must be of the form offset(xN).")) };
 
-    let ofs = Operand::from_str(mem_loc.next().unwrap())?;
-    let Operand::Immediate(ofs) = ofs else { return Err(format!("Offset must be an immediate."))};
+    let ofs = Operand::from_str(ofs)?;
+    let Operand::Immediate(ofs) = ofs else { return Err(String::from("Offset must be an immediate."))};
 
-    let base = Operand::from_str(mem_loc.next().unwrap())?;
-    let Operand::Gpr(base) = base else { return Err(format!("base must be a register of the form xN.."))};
+    let base = Operand::from_str(base)?;
+    let Operand::Gpr(base) = base else { return Err(String::from("base must be a register of the form xN.."))};
 
     if opcode.parse_format() == InstParseFormat::S {
         Ok(Inst {
@@ -531,13 +595,18 @@ fn parse_l_s_format_inst(opcode: Opcode, remaining_line: String) -> Result<Inst,
 }
 
 fn parse_j_format_inst(opcode: Opcode, remaining_line: String) -> Result<Inst, String> {
-    let loop_label = Operand::from_str(&remaining_line)?;
-    let Operand::Immediate(i) = loop_label else { return Err(format!("Loop label must be an immediate."))};
+    // jal takes an optional link register, defaulting to ra
+    let (link, target) = match remaining_line.split_once(",") {
+        Some((link, target)) if opcode == Opcode::JAL => (Operand::from_str(link)?, target),
+        _ => (Operand::Gpr(if opcode == Opcode::JAL {1} else {0}), remaining_line.as_str()),
+    };
+    let Operand::Gpr(_) = link else { return Err(String::from("Link register must be a register of the form xN."))};
+    let loop_label = Operand::from_str(target)?;
+    let Operand::Immediate(i) = loop_label else { return Err(String::from("Loop label must be an immediate."))};
     Ok(Inst {
         opcode,
         addr: 0,
-        // TODO handle a jump and link which is not using the link register
-        dest: Operand::Gpr(if opcode == Opcode::JAL {1} else {0}),
+        dest: link,
         src1: None,
         src2: Operand::None,
         label: Label::SrcAddrSpace(i as usize),
@@ -554,19 +623,19 @@ fn parse_mov_format_inst(opcode: Opcode, remaining_line: String) -> Result<Inst,
     let dest = Operand::from_str(remaining.next().unwrap())?;
     let src = Operand::from_str(remaining.next().unwrap())?;
     match dest {
+        // general register moves are the mv/li pseudo-instructions, which expand to addi
+        Operand::Gpr(_) if opcode == Opcode::MOV => return Err(String::from("mov into a GPR is not an instruction, use mv or li.")),
         Operand::Gpr(_) => {
-            match src {
-                Operand::Gpr(_) | Operand::Immediate(_) => {},
-                _ => return Err(format!("mov src must be a register of the form xN or an immediate when dest is a GPR."))
-            }
+            let Operand::Immediate(_) = src else { return Err(format!("{} src must be an immediate.", opcode.to_str()))};
         },
+        _ if opcode != Opcode::MOV => return Err(format!("{} dest must be a register of the form xN.", opcode.to_str())),
         Operand::Predicate(_) => {
-            let Operand::PredicateVal(_) = src else { return Err(format!("mov src must be a predicate value when dest is a predicate register."))};
+            let Operand::PredicateVal(_) = src else { return Err(String::from("mov src must be a predicate value when dest is a predicate register."))};
         }
         Operand::Ec | Operand::Lc => {
-            let Operand::Immediate(_) = src else { return Err(format!("mov src must be an immediate value when dest is LC/EC."))};
+            let Operand::Immediate(_) = src else { return Err(String::from("mov src must be an immediate value when dest is LC/EC."))};
         }
-        _ => return Err(format!("mov dest must be a register of the form xN, a predicate register, or LC/EC."))
+        _ => return Err(String::from("mov dest must be a register of the form xN, a predicate register, or LC/EC."))
     }
     Ok(Inst {
         opcode,
@@ -579,30 +648,137 @@ fn parse_mov_format_inst(opcode: Opcode, remaining_line: String) -> Result<Inst,
     })
 }
 
+// fence predecessor/successor sets, written as a subset of "iorw"
+const FENCE_SET: [(char, i64); 4] = [('i', 8), ('o', 4), ('r', 2), ('w', 1)];
+
+fn parse_fence_set(set: &str) -> Result<i64, String> {
+    set.chars().try_fold(0, |bits, c| {
+        FENCE_SET.iter().find(|(name, _)| *name == c)
+            .map(|(_, bit)| bits | bit)
+            .ok_or_else(|| format!("Unrecognized fence set: {}", set))
+    })
+}
+
+fn fence_set_to_str(bits: i64) -> String {
+    FENCE_SET.iter().filter(|(_, bit)| bits & bit != 0).map(|(name, _)| name).collect()
+}
+
+fn parse_sys_format_inst(opcode: Opcode, remaining_line: String) -> Result<Inst, String> {
+    let mut inst = Inst::new(opcode);
+    if opcode == Opcode::FENCE {
+        // a bare fence orders everything
+        let ordering = if remaining_line.is_empty() {
+            0xff
+        } else {
+            let Some((pred, succ)) = remaining_line.split_once(",") else {
+                return Err(String::from("fence expects predecessor and successor sets"));
+            };
+            parse_fence_set(pred)? << 4 | parse_fence_set(succ)?
+        };
+        inst.src2 = Operand::Immediate(ordering);
+    } else if !remaining_line.is_empty() {
+        return Err(format!("{} takes no operands", opcode.to_str()));
+    }
+    Ok(inst)
+}
+
+/// Sign-extended low 12 bits, the part %lo leaves for the I/S immediate.
+pub fn lo12(value: i64) -> i64 {
+    ((value & 0xfff) ^ 0x800) - 0x800
+}
+
+/// Upper 20 bits, rounded so that adding `lo12` gives back the original value.
+pub fn hi20(value: i64) -> i64 {
+    ((value + 0x800) >> 12) & 0xfffff
+}
+
+fn pseudo_operands<'a, const N: usize>(opcode: &str, operands: &'a [String]) -> Result<&'a [String; N], String> {
+    operands.try_into()
+        .map_err(|_| format!("{} expects {} operands, got {}", opcode, N, operands.len()))
+}
+
+fn pseudo_imm(op: &str) -> Result<i64, String> {
+    match Operand::from_str(op)? {
+        Operand::Immediate(i) => Ok(i),
+        _ => Err(format!("Expected an immediate, got {}", op)),
+    }
+}
+
+/// Expands an assembler pseudo-instruction at `pc` into the RV32I instructions it
+/// stands for, in the syntax `Inst::from_str` takes. Operands must already be
+/// registers or plain numbers, with labels resolved to absolute addresses.
+/// Returns None for real instructions.
+pub fn expand_pseudo(opcode: &str, operands: &[String], pc: usize) -> Result<Option<Vec<String>>, String> {
+    let lines = match opcode {
+        "nop" => { pseudo_operands::<0>(opcode, operands)?; vec![String::from("addi x0,x0,0")] }
+        "li" => {
+            let [rd, imm] = pseudo_operands(opcode, operands)?;
+            let imm = pseudo_imm(imm)?;
+            if !(-(1 << 31)..(1 << 32)).contains(&imm) {
+                return Err(format!("li immediate {} does not fit in 32 bits", imm));
+            }
+            let imm = imm as i32 as i64;
+            if (-2048..2048).contains(&imm) {
+                vec![format!("addi {},x0,{}", rd, imm)]
+            } else if lo12(imm) == 0 {
+                vec![format!("lui {},{}", rd, hi20(imm))]
+            } else {
+                vec![format!("lui {},{}", rd, hi20(imm)), format!("addi {},{},{}", rd, rd, lo12(imm))]
+            }
+        }
+        "la" => {
+            let [rd, sym] = pseudo_operands(opcode, operands)?;
+            let offset = pseudo_imm(sym)? - pc as i64;
+            vec![format!("auipc {},{}", rd, hi20(offset)), format!("addi {},{},{}", rd, rd, lo12(offset))]
+        }
+        // programs are small enough for jal to reach everything, so no auipc+jalr
+        "call" => { let [sym] = pseudo_operands(opcode, operands)?; vec![format!("jal x1,{}", sym)] }
+        "tail" => { let [sym] = pseudo_operands(opcode, operands)?; vec![format!("j {}", sym)] }
+        "mv" => { let [rd, rs] = pseudo_operands(opcode, operands)?; vec![format!("addi {},{},0", rd, rs)] }
+        "not" => { let [rd, rs] = pseudo_operands(opcode, operands)?; vec![format!("xori {},{},-1", rd, rs)] }
+        "neg" => { let [rd, rs] = pseudo_operands(opcode, operands)?; vec![format!("sub {},x0,{}", rd, rs)] }
+        "seqz" => { let [rd, rs] = pseudo_operands(opcode, operands)?; vec![format!("sltiu {},{},1", rd, rs)] }
+        "snez" => { let [rd, rs] = pseudo_operands(opcode, operands)?; vec![format!("sltu {},x0,{}", rd, rs)] }
+        "sltz" => { let [rd, rs] = pseudo_operands(opcode, operands)?; vec![format!("slt {},{},x0", rd, rs)] }
+        "sgtz" => { let [rd, rs] = pseudo_operands(opcode, operands)?; vec![format!("slt {},x0,{}", rd, rs)] }
+        "beqz" => { let [rs, l] = pseudo_operands(opcode, operands)?; vec![format!("beq {},x0,{}", rs, l)] }
+        "bnez" => { let [rs, l] = pseudo_operands(opcode, operands)?; vec![format!("bne {},x0,{}", rs, l)] }
+        "blez" => { let [rs, l] = pseudo_operands(opcode, operands)?; vec![format!("bge x0,{},{}", rs, l)] }
+        "bgez" => { let [rs, l] = pseudo_operands(opcode, operands)?; vec![format!("bge {},x0,{}", rs, l)] }
+        "bltz" => { let [rs, l] = pseudo_operands(opcode, operands)?; vec![format!("blt {},x0,{}", rs, l)] }
+        "bgtz" => { let [rs, l] = pseudo_operands(opcode, operands)?; vec![format!("blt x0,{},{}", rs, l)] }
+        "bgt" => { let [a, b, l] = pseudo_operands(opcode, operands)?; vec![format!("blt {},{},{}", b, a, l)] }
+        "ble" => { let [a, b, l] = pseudo_operands(opcode, operands)?; vec![format!("bge {},{},{}", b, a, l)] }
+        "bgtu" => { let [a, b, l] = pseudo_operands(opcode, operands)?; vec![format!("bltu {},{},{}", b, a, l)] }
+        "bleu" => { let [a, b, l] = pseudo_operands(opcode, operands)?; vec![format!("bgeu {},{},{}", b, a, l)] }
+        "jr" => { let [rs] = pseudo_operands(opcode, operands)?; vec![format!("jalr x0,0({})", rs)] }
+        "ret" => { pseudo_operands::<0>(opcode, operands)?; vec![String::from("jalr x0,0(x1)")] }
+        // jalr rs, jalr rd, rs and jalr rd, rs, imm; jalr rd, imm(rs) is the real instruction
+        "jalr" => match operands {
+            [rs] => vec![format!("jalr x1,0({})", rs)],
+            [rd, rs] if !rs.contains('(') => vec![format!("jalr {},0({})", rd, rs)],
+            [rd, rs, imm] => vec![format!("jalr {},{}({})", rd, imm, rs)],
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(lines))
+}
+
 impl Inst {
     pub fn from_str(line: &str, addr: usize) -> Result<Self, String> {
-        let mut line_split = line.split(" ");
-        // TODO error message if no opcode
-        let opcode: Opcode = Opcode::from_str(line_split.next().unwrap())?;
+        let mut line_split = line.split_whitespace();
+        let Some(opcode) = line_split.next() else { return Err(String::from("Missing opcode")) };
+        let opcode: Opcode = Opcode::from_str(opcode)?;
         let remaining = line_split.collect::<String>();
         let inst = match opcode.parse_format() {
             InstParseFormat::R | InstParseFormat::I | InstParseFormat::B => parse_i_r_b_format_inst(opcode, remaining),
             InstParseFormat::S | InstParseFormat::L => parse_l_s_format_inst(opcode, remaining),
             InstParseFormat::J => parse_j_format_inst(opcode, remaining),
             InstParseFormat::MOV => parse_mov_format_inst(opcode, remaining),
-            InstParseFormat::NOP => {
-                Ok(Inst {
-                    opcode,
-                    addr: 0,
-                    dest: Operand::None,
-                    src1: None,
-                    src2: Operand::None,
-                    label: Label::None,
-                    offset: None
-                })
-            },
+            InstParseFormat::SYS => parse_sys_format_inst(opcode, remaining),
         };
-        inst.and_then(|mut x| {x.addr = addr; Ok(x)}) 
+        inst.map(|mut x| {x.addr = addr; x})
     }
 
     pub fn print_fill(&self, output_str: &mut String, budget: usize) {
@@ -615,9 +791,10 @@ impl Inst {
         output_str.push_str(" | ");
     }
 
-    pub fn nop() -> Self {
+    /// An instruction with no operands, for callers that fill them in themselves.
+    pub fn new(opcode: Opcode) -> Self {
         Self {
-            opcode: Opcode::NOP,
+            opcode,
             addr: 0,
             dest: Operand::None,
             src1: None,
@@ -627,6 +804,16 @@ impl Inst {
         }
     }
 
+    /// `addi x0, x0, 0`, the canonical RV32I nop.
+    pub fn nop() -> Self {
+        Self {
+            dest: Operand::Gpr(0),
+            src1: Some(0),
+            src2: Operand::Immediate(0),
+            ..Self::new(Opcode::ADDI)
+        }
+    }
+
     /*pub fn gen_loop(pipelined: bool, addr: usize) -> Self {
         Self {
             opcode: if pipelined { Opcode::LOOP_PIP } else { Opcode::LOOP },
@@ -663,9 +850,18 @@ impl fmt::Display for Inst {
                 write!(f, " {}({})", self.offset.unwrap(), self.src2)?;
             },
             InstParseFormat::J => {
+                // jal links through ra unless it says otherwise
+                if self.opcode == Opcode::JAL && self.dest != Operand::Gpr(1) {
+                    write!(f, " {},", self.dest)?;
+                }
                 write!(f, " {}", self.label)?;
             },
-            InstParseFormat::NOP => {},
+            InstParseFormat::SYS => {
+                if let Operand::Immediate(ordering) = self.src2 {
+                    write!(f, " {},", fence_set_to_str(ordering >> 4))?;
+                    write!(f, " {}", fence_set_to_str(ordering))?;
+                }
+            },
             InstParseFormat::MOV => {
                 write!(f, " {},", self.dest)?;
                 write!(f, " {}", self.src2)?;
@@ -675,8 +871,8 @@ impl fmt::Display for Inst {
     }
 }
 
-impl Into<String> for Inst {
-    fn into(self) -> String {
-        format!("{}", self)
+impl From<Inst> for String {
+    fn from(inst: Inst) -> Self {
+        format!("{}", inst)
     }
 }
\ No newline at end of file
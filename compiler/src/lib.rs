@@ -0,0 +1,468 @@
+//! Compiles RV32IM programs for the VLIW core in hw/: `parse` an ELF or assembly file,
+//! `analyze` it into basic blocks, `schedule` them into bundles, `relocate` the bundles
+//! and code pointers to their VLIW addresses and `emit` the hex image the core loads.
+//! `compile` runs all five.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use clap::ValueEnum;
+
+use analysis::{auipc_target, dep_analysis, find_code_pointers, find_loops, live_after, liveness, trace_to_basicblocks, AnalyzedProgram, Cfg, CodePointers, Loop};
+use assembler::{assemble, HexFormat};
+use elf::{is_elf, Elf};
+use error::Error;
+use ifconvert::{if_convert, IfConverted};
+use interp::Interpreter;
+use isa::{hi20, lo12, Inst, Label, Opcode, Operand};
+use machine::MachineModel;
+use modulo::PipelinedLoop;
+use parser::parse_asm;
+use rename::{false_deps, rename_registers};
+use scheduling::{schedule_program, ScheduledProgram, Scheduler};
+use sim::{Core, Memory};
+use superblock::Trace;
+use unroll::{unroll_loops, UnrolledLoop};
+
+pub mod analysis;
+pub mod assembler;
+pub mod decoder;
+pub mod disassembler;
+pub mod elf;
+pub mod error;
+#[doc(hidden)]
+pub mod fuzz;
+pub mod ifconvert;
+pub mod interp;
+pub mod isa;
+pub mod machine;
+pub mod modulo;
+pub mod parser;
+pub mod rename;
+pub mod scheduling;
+pub mod sim;
+pub mod superblock;
+#[cfg(test)]
+pub mod testutil;
+pub mod unroll;
+pub mod verify;
+
+/// How to compile: which scheduler, which passes ahead of it and for what machine.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    pub scheduler: Scheduler,
+    pub model: MachineModel,
+    // compute both sides of short branches and select the results
+    pub if_convert: bool,
+    // copies of the body of counted single-block loops to schedule together, 1 disables unrolling
+    pub unroll: usize,
+    // rename live ranges apart
+    pub rename: bool,
+    // branch address -> (taken, not taken), guiding trace formation
+    pub branch_weights: HashMap<usize, (u64, u64)>,
+    // also count false dependences and schedule with every scheduler, for `Stats`
+    pub stats: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            scheduler: Scheduler::Superblock,
+            model: MachineModel::default(),
+            if_convert: true,
+            unroll: 1,
+            rename: true,
+            branch_weights: HashMap::new(),
+            stats: false,
+        }
+    }
+}
+
+/// What relocating and emitting a schedule need to know about the input besides its code:
+/// the data sections (and symbols and source lines) of the ELF, the code pointers in it and
+/// the size of the original code in bytes.
+pub struct Image {
+    pub elf: Elf,
+    pub pointers: CodePointers,
+    pub orig_size: usize,
+}
+
+fn label_auipc(trace: &mut [Inst]) -> Result<(), Error> {
+    let text_end = trace.len() as i64 * 4;
+    for i in 1..trace.len() {
+        if trace[i - 1].opcode != Opcode::AUIPC {
+            continue;
+        }
+        if trace[i].opcode != Opcode::ADDI {
+            return Err(Error::new(format!("Expected ADDI offset right after AUIPC, got {}", trace[i])).at(&trace[i - 1]));
+        }
+        let auipc_pc = trace[i - 1].addr;
+        let target = auipc_target(&trace[i - 1], &trace[i])
+            .ok_or_else(|| Error::new(format!("Expected an immediate AUIPC/ADDI pair, got {}", trace[i])).at(&trace[i - 1]))?;
+        if (0..text_end).contains(&target) {
+            // a code address: materialize the VLIW address with an absolute lui/addi pair
+            let label = Label::SrcAddrSpace(target as usize);
+            trace[i - 1].opcode = Opcode::LUI;
+            trace[i - 1].label = label;
+            trace[i].label = label;
+        } else {
+            // a data address: both halves are split again from wherever the auipc goes
+            for inst in trace[i - 1..=i].iter_mut() {
+                inst.offset = Some(target);
+                inst.label = Label::SrcAddrSpace(auipc_pc);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn fix_addresses(sp: &mut ScheduledProgram) -> Result<(), Error> {
+    let bundle_bytes = sp.model.bundle_bytes();
+    for bundle in sp.schedule.iter_mut() {
+        bundle.addr *= bundle_bytes;
+        for inst in bundle.valid_insts_mut() {
+            if let Label::SrcAddrSpace(l) = inst.inst.label {
+                // pc-relative data pairs are relative to the auipc itself, everything else targets a block
+                let new_addr = if inst.inst.offset.is_some() { &sp.starts } else { &sp.entries }.get(&l)
+                    .ok_or_else(|| Error::new(format!("Could not find new label for {:#x}", l)).at(&inst.inst))?;
+                inst.inst.label = Label::DstAddrSpace(*new_addr * bundle_bytes);
+            }
+            let Operand::Immediate(mut imm) = inst.inst.src2 else { continue };
+            match (inst.inst.opcode, inst.inst.offset, inst.inst.label) {
+                // code address pairs from label_auipc
+                (Opcode::LUI, _, Label::DstAddrSpace(d)) => imm = hi20(d as i64),
+                (Opcode::ADDI, None, Label::DstAddrSpace(d)) => imm = lo12(d as i64),
+                // pc-relative data address, keep pointing at the original location
+                (Opcode::AUIPC, Some(target), Label::DstAddrSpace(d)) => imm = hi20(target - d as i64),
+                (Opcode::ADDI, Some(target), Label::DstAddrSpace(d)) => imm = lo12(target - d as i64),
+                _ => {}
+            }
+            inst.inst.src2 = Operand::Immediate(imm);
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the jump-table words found by `find_code_pointers` to VLIW addresses.
+fn fix_data_pointers(image: &mut Image, sp: &ScheduledProgram) -> Result<(), Error> {
+    for addr in image.pointers.data_words.iter() {
+        let section = image.elf.sections.iter_mut()
+            .find(|s| *addr >= s.addr && addr + 4 <= s.addr + s.data.len())
+            .ok_or_else(|| Error::new(format!("Code pointer at {:#x} is outside of the data sections", addr)))?;
+        let word = &mut section.data[addr - section.addr..addr - section.addr + 4];
+        let target = u32::from_le_bytes([word[0], word[1], word[2], word[3]]) as usize;
+        let new_addr = sp.entries.get(&target)
+            .ok_or_else(|| Error::new(format!("Could not find new label for {:#x} (data word at {:#x})", target, addr)))?;
+        word.copy_from_slice(&((*new_addr * sp.model.bundle_bytes()) as u32).to_le_bytes());
+    }
+    Ok(())
+}
+
+/// What the passes did to a program and, with `CompileOptions::stats`, what each scheduler
+/// makes of it. Prints as the `--stats` report.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    // None for passes that didn't run
+    pub converted: Option<Vec<IfConverted>>,
+    pub unrolled: Vec<UnrolledLoop>,
+    pub renamed: Option<usize>,
+    // false dependences before and after renaming
+    pub false_deps: Option<(usize, usize)>,
+    // the rest only with `stats`
+    pub insts: usize,
+    pub blocks: usize,
+    pub loops: Vec<Loop>,
+    // static bundle counts, one per scheduler
+    pub bundles: Vec<(Scheduler, usize)>,
+    pub pipelined: Vec<PipelinedLoop>,
+    // traces over several blocks
+    pub traces: Vec<Trace>,
+}
+
+impl Stats {
+    fn add_schedulers(&mut self, ap: &AnalyzedProgram, model: &MachineModel) {
+        self.insts = ap.bbs.iter().map(|bb| bb.insns.len() + bb.cf_insn.is_some() as usize).sum();
+        self.blocks = ap.bbs.len();
+        self.loops = find_loops(ap, &Cfg::new(ap));
+        for scheduler in Scheduler::value_variants() {
+            let sp = schedule_program(ap.clone(), model, *scheduler);
+            self.bundles.push((*scheduler, sp.schedule.len()));
+            match scheduler {
+                Scheduler::Modulo => self.pipelined = sp.pipelined,
+                Scheduler::Superblock => self.traces = sp.traces.into_iter().filter(|t| t.blocks > 1).collect(),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(converted) = &self.converted {
+            writeln!(f, "if-conversion: {} branches converted", converted.len())?;
+            for c in converted.iter() {
+                writeln!(f, "  {}", c)?;
+            }
+        }
+        writeln!(f, "unrolling: {} loops unrolled", self.unrolled.len())?;
+        for l in self.unrolled.iter() {
+            writeln!(f, "  {}", l)?;
+        }
+        if let Some(renamed) = self.renamed {
+            write!(f, "renaming: {} live ranges renamed", renamed)?;
+            if let Some((before, after)) = self.false_deps {
+                write!(f, ", false dependences {} -> {}", before, after)?;
+            }
+            writeln!(f)?;
+        }
+        if self.bundles.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "{} instructions, {} basic blocks", self.insts, self.blocks)?;
+        writeln!(f, "{} loops, nested up to depth {}", self.loops.len(), self.loops.iter().map(|l| l.depth).max().unwrap_or(0))?;
+        for l in self.loops.iter() {
+            writeln!(f, "  {}", l)?;
+        }
+        for (scheduler, bundles) in self.bundles.iter() {
+            let name = scheduler.to_possible_value().map_or(String::new(), |v| v.get_name().to_string());
+            write!(f, "{}: {} bundles (IPC {:.2})", name, bundles, self.insts as f64 / *bundles as f64)?;
+            match scheduler {
+                Scheduler::Modulo => {
+                    writeln!(f, ", {} loops pipelined", self.pipelined.len())?;
+                    for l in self.pipelined.iter() {
+                        writeln!(f, "  {}", l)?;
+                    }
+                }
+                Scheduler::Superblock => {
+                    writeln!(f, ", {} traces over several blocks", self.traces.len())?;
+                    for t in self.traces.iter() {
+                        writeln!(f, "  {}", t)?;
+                    }
+                }
+                _ => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes `.text` of an ELF or parses assembly into an instruction trace. The ELF is empty
+/// for assembly, but for the data sections and symbols it defines.
+pub fn parse(input: &[u8]) -> Result<(Vec<Inst>, Elf), Error> {
+    if is_elf(input) {
+        let elf = Elf::parse(input).map_err(|e| Error::new(format!("Error reading ELF: {}", e)))?;
+        let trace = elf.trace().map_err(|e| Error::new(format!("Error decoding .text: {}", e)))?;
+        Ok((trace, elf))
+    } else {
+        parse_asm(&String::from_utf8_lossy(input))
+    }
+}
+
+/// Splits a trace into basic blocks, with code addresses labeled for `relocate`.
+pub fn analyze(mut trace: Vec<Inst>, elf: Elf, opts: &CompileOptions) -> Result<(AnalyzedProgram, Image), Error> {
+    let orig_size = trace.len() * 4;
+    let pointers = find_code_pointers(&trace, &elf.sections, &elf.symbols);
+    label_auipc(&mut trace).map_err(|e| e.with_lines(&elf.lines))?;
+    let bbs = trace_to_basicblocks(trace, &pointers.targets).map_err(|e| e.with_lines(&elf.lines))?;
+    let ap = AnalyzedProgram {
+        bbs: bbs.into_iter().map(dep_analysis).collect(),
+        indirect_targets: pointers.targets.clone(),
+        branch_weights: opts.branch_weights.clone(),
+    };
+    Ok((ap, Image { elf, pointers, orig_size }))
+}
+
+/// The passes between analysis and scheduling, with what each of them did.
+pub fn optimize(ap: AnalyzedProgram, opts: &CompileOptions) -> (AnalyzedProgram, Stats) {
+    let mut stats = Stats::default();
+    let ap = if !opts.if_convert {
+        ap
+    } else {
+        let (ap, converted) = if_convert(ap);
+        stats.converted = Some(converted);
+        ap
+    };
+    let (ap, unrolled) = unroll_loops(ap, opts.unroll);
+    stats.unrolled = unrolled;
+    if !opts.rename {
+        return (ap, stats);
+    }
+    let before = if opts.stats { false_deps(&ap) } else { 0 };
+    let (ap, renamed) = rename_registers(ap);
+    stats.renamed = Some(renamed);
+    if opts.stats {
+        stats.false_deps = Some((before, false_deps(&ap)));
+    }
+    (ap, stats)
+}
+
+/// Optimizes the program and schedules it into bundles. Addresses are still the original
+/// ones until `relocate`.
+pub fn schedule(ap: AnalyzedProgram, opts: &CompileOptions) -> (ScheduledProgram, Stats) {
+    let (ap, mut stats) = optimize(ap, opts);
+    if opts.stats {
+        stats.add_schedulers(&ap, &opts.model);
+    }
+    (schedule_program(ap, &opts.model, opts.scheduler), stats)
+}
+
+/// Moves the schedule to its bundle addresses and points the code pointers in the data
+/// sections of `image` at them.
+pub fn relocate(sp: &mut ScheduledProgram, image: &mut Image) -> Result<(), Error> {
+    fix_addresses(sp).map_err(|e| e.with_lines(&image.elf.lines))?;
+    fix_data_pointers(image, sp)
+}
+
+/// The hex image of a relocated schedule and the data sections, as `$readmemh` loads it,
+/// in words, bytes or memory lines.
+pub fn emit(sp: &ScheduledProgram, image: &Image, format: HexFormat) -> Result<String, Error> {
+    assemble(sp, image.orig_size, &image.elf.sections, format).map_err(|e| e.with_lines(&image.elf.lines))
+}
+
+/// An ELF or assembly file all the way to its hex image.
+pub fn compile(input: &[u8], opts: &CompileOptions) -> Result<String, Error> {
+    let (trace, elf) = parse(input)?;
+    let (ap, mut image) = analyze(trace, elf, opts)?;
+    let (mut sp, _) = schedule(ap, opts);
+    relocate(&mut sp, &mut image)?;
+    emit(&sp, &image, HexFormat::Words)
+}
+
+/// Runs the trace on the reference interpreter and its hex image on the simulated core, and
+/// lists what differs: console output, exit code, the registers live after the exit store
+/// (and sp, gp, tp) and every word either side stored to. Also returns a summary of both
+/// runs. Err if the input can't be analyzed or the reference itself doesn't get to the exit.
+pub fn compare(trace: Vec<Inst>, elf: Elf, opts: &CompileOptions, max_cycles: u64) -> Result<(Vec<String>, String), Error> {
+    // the reference runs the trace before label_auipc turns code address pairs into lui/addi
+    let mut reference = Interpreter::new(&trace, &elf.sections, trace.len() * 4);
+    let expected = reference.run(max_cycles)
+        .map_err(|e| Error::new(format!("Reference run failed: {}", e)))?;
+    let (ap, mut image) = analyze(trace, elf, opts)?;
+    let live = liveness(&ap, &Cfg::new(&ap));
+    let compared = live_after(&ap, &live, expected.addr).unwrap_or(0) | (0b111 << 2);
+
+    let (mut sp, _) = schedule(ap, opts);
+    let hex = match relocate(&mut sp, &mut image).and_then(|_| emit(&sp, &image, HexFormat::Words)) {
+        Ok(hex) => hex,
+        Err(e) => return Ok((vec![format!("the compiler fails: {}", e)], String::new())),
+    };
+    let mut core = Core::new(Memory::from_hex(&hex)?, &opts.model);
+    let finish = match core.run(max_cycles) {
+        Ok(finish) => finish,
+        Err(e) => return Ok((vec![format!("the core fails: {}", e)], String::new())),
+    };
+
+    let mut problems = Vec::new();
+    if core.console != reference.console {
+        problems.push(format!("output is {:?}, expected {:?}",
+            String::from_utf8_lossy(&core.console), String::from_utf8_lossy(&reference.console)));
+    }
+    if finish.code != expected.code {
+        problems.push(format!("exit code is {}, expected {}", finish.code, expected.code));
+    }
+    for r in (1..32).filter(|r| compared & (1 << r) != 0) {
+        if core.rf[r] != reference.regs[r] {
+            problems.push(format!("x{} is {:#x}, expected {:#x}", r, core.rf[r], reference.regs[r]));
+        }
+    }
+    // the core adds the data offset to every address it stores to
+    let offset = core.addr_offset;
+    let mut stored: Vec<u32> = reference.mem.stored()
+        .chain(core.mem.stored().map(|a| a.wrapping_sub(offset)))
+        .collect();
+    stored.sort();
+    stored.dedup();
+    for addr in stored {
+        let (got, want) = (core.mem.read(addr.wrapping_add(offset)), reference.mem.read(addr));
+        if got != want {
+            problems.push(format!("word at {:#x} is {:#x}, expected {:#x}", addr, got, want));
+        }
+    }
+    let summary = format!("{} instructions on the reference, {} cycles and {} instructions on the core",
+        expected.insns, finish.cycles, finish.insns);
+    Ok((problems, summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, compare, compile, emit, parse, relocate, schedule, CompileOptions};
+    use crate::assembler::HexFormat;
+    use crate::scheduling::Scheduler;
+    use crate::sim::{Core, Memory};
+
+    const HELLO: &str = "
+            .text
+            li   t0, 0xf000fff0
+            la   a1, msg
+        1:  lbu  a0, 0(a1)
+            beqz a0, 2f
+            sw   a0, 0(t0)
+            addi a1, a1, 1
+            j    1b
+        2:  sw   x0, 8(t0)
+        3:  j    3b
+            .rodata
+        msg: .string \"hello\"
+    ";
+
+    #[test]
+    fn test_stages() {
+        for scheduler in [Scheduler::Asap, Scheduler::List, Scheduler::Modulo, Scheduler::Superblock] {
+            let opts = CompileOptions { scheduler, ..Default::default() };
+            let (trace, elf) = parse(HELLO.as_bytes()).unwrap();
+            let (ap, mut image) = analyze(trace, elf, &opts).unwrap();
+            let (mut sp, _) = schedule(ap, &opts);
+            relocate(&mut sp, &mut image).unwrap();
+            let hex = emit(&sp, &image, HexFormat::Words).unwrap();
+            assert_eq!(hex, compile(HELLO.as_bytes(), &opts).unwrap());
+
+            let mut core = Core::new(Memory::from_hex(&hex).unwrap(), &opts.model);
+            let finish = core.run(10_000).unwrap();
+            assert_eq!((core.console.as_slice(), finish.code), (&b"hello"[..], 0));
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let (trace, elf) = parse(HELLO.as_bytes()).unwrap();
+        let (ap, _) = analyze(trace, elf, &CompileOptions::default()).unwrap();
+        let (_, stats) = schedule(ap.clone(), &CompileOptions::default());
+        assert!(stats.bundles.is_empty() && stats.false_deps.is_none());
+
+        let opts = CompileOptions { scheduler: Scheduler::List, stats: true, ..Default::default() };
+        let (sp, stats) = schedule(ap, &opts);
+        let schedulers: Vec<Scheduler> = stats.bundles.iter().map(|(s, _)| *s).collect();
+        assert_eq!(schedulers, [Scheduler::Asap, Scheduler::List, Scheduler::Modulo, Scheduler::Superblock]);
+        assert_eq!(stats.bundles[1].1, sp.schedule.len());
+        assert!(stats.to_string().contains(&format!("list: {} bundles", sp.schedule.len())), "{}", stats);
+    }
+
+    #[test]
+    fn test_pc_relative_data() {
+        // the auipc moves up past the chain, which pushes the old low part out of 12 bits
+        let src = format!("li t0, 0xf000fff0\n{}auipc a0, 1\naddi a0, a0, 2000\nli a2, 7\nsw a2, 0(a0)\nlw a1, 0(a0)\nadd a1, a1, t1\nsw a1, 8(t0)\n",
+            "addi t1, t1, 1\n".repeat(40));
+        for scheduler in [Scheduler::List, Scheduler::Superblock] {
+            let opts = CompileOptions { scheduler, ..Default::default() };
+            let (trace, elf) = parse(src.as_bytes()).unwrap();
+            let (problems, _) = compare(trace, elf, &opts, 10_000).unwrap();
+            assert!(problems.is_empty(), "{:?}", problems);
+        }
+    }
+
+    #[test]
+    fn test_stage_errors() {
+        // the error points at the auipc, by address and source line
+        let src = "nop\nauipc a0, 0\nlw a1, 0(a0)\n";
+        let (trace, elf) = parse(src.as_bytes()).unwrap();
+        let err = analyze(trace, elf, &CompileOptions::default()).err().unwrap();
+        assert_eq!((err.line, err.addr), (Some(2), Some(4)));
+        assert!(compile(b"lw a0, 4", &CompileOptions::default()).is_err());
+        // branches out of the code or between instructions
+        for src in ["nop\nbeqz a0, 0x40\nret\n", "nop\nj 0x6\nret\n"] {
+            let (trace, elf) = parse(src.as_bytes()).unwrap();
+            let err = analyze(trace, elf, &CompileOptions::default()).err().unwrap();
+            assert_eq!((err.line, err.addr), (Some(2), Some(4)), "{}", src);
+        }
+    }
+}
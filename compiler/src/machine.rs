@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::isa::{ExecutionUnit, Opcode};
+
+/// The 4-wide bundle implemented by `VLIW.bsv`.
+const DEFAULT_MACHINE: &str = include_str!("../machines/vliw4.json");
+
+const ALL_UNITS: [ExecutionUnit; 4] = [ExecutionUnit::ALU, ExecutionUnit::Mult, ExecutionUnit::Mem, ExecutionUnit::Branch];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Slot {
+    pub name: String,
+    pub units: Vec<ExecutionUnit>,
+}
+
+/// Shape of a bundle and the latencies the scheduler plans with.
+/// `slots` is the order the scheduler fills slots in (and the listing prints them),
+/// `issue_order` the order their words appear in memory.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MachineModel {
+    pub slots: Vec<Slot>,
+    issue_order: Vec<String>,
+    latencies: HashMap<ExecutionUnit, usize>,
+    #[serde(default)]
+    opcode_latencies: HashMap<Opcode, usize>,
+    // issue_order resolved to indices into slots
+    #[serde(skip)]
+    issue: Vec<usize>,
+}
+
+impl MachineModel {
+    pub fn from_json(src: &str) -> Result<Self, String> {
+        let mut model: MachineModel = serde_json::from_str(src)
+            .map_err(|e| format!("Bad machine description: {}", e))?;
+        if model.slots.is_empty() {
+            return Err(String::from("Machine description has no slots"));
+        }
+        for (i, slot) in model.slots.iter().enumerate() {
+            if model.slots[..i].iter().any(|s| s.name == slot.name) {
+                return Err(format!("Slot {} is listed twice", slot.name));
+            }
+        }
+        for unit in ALL_UNITS.iter() {
+            if !model.slots.iter().any(|s| s.units.contains(unit)) {
+                return Err(format!("No slot can issue {:?} instructions", unit));
+            }
+            match model.latencies.get(unit) {
+                Some(0) => return Err(format!("Latency of {:?} must be at least 1", unit)),
+                Some(_) => {}
+                None => return Err(format!("Missing latency for {:?}", unit)),
+            }
+        }
+        if let Some((opcode, _)) = model.opcode_latencies.iter().find(|(_, l)| **l == 0) {
+            return Err(format!("Latency of {:?} must be at least 1", opcode));
+        }
+        model.issue = model.issue_order.iter()
+            .map(|name| model.slots.iter().position(|s| &s.name == name)
+                .ok_or_else(|| format!("Unknown slot {} in issue_order", name)))
+            .collect::<Result<_, _>>()?;
+        let mut sorted = model.issue.clone();
+        sorted.sort();
+        sorted.dedup();
+        if sorted.len() != model.slots.len() || model.issue.len() != model.slots.len() {
+            return Err(String::from("issue_order has to list every slot exactly once"));
+        }
+        Ok(model)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let src = fs::read_to_string(path)
+            .map_err(|e| format!("Error opening machine description: {}", e))?;
+        Self::from_json(&src)
+    }
+
+    pub fn width(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn bundle_bytes(&self) -> usize {
+        self.width() * 4
+    }
+
+    /// Slot indices in memory order.
+    pub fn issue_order(&self) -> &[usize] {
+        &self.issue
+    }
+
+    pub fn latency(&self, opcode: Opcode) -> usize {
+        self.opcode_latencies.get(&opcode).copied()
+            .unwrap_or_else(|| self.latencies[&opcode.eu_type()])
+    }
+
+    pub fn accepts(&self, slot: usize, unit: ExecutionUnit) -> bool {
+        self.slots[slot].units.contains(&unit)
+    }
+}
+
+impl Default for MachineModel {
+    fn default() -> Self {
+        Self::from_json(DEFAULT_MACHINE).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MachineModel;
+    use crate::isa::Opcode;
+
+    #[test]
+    fn test_default_machine() {
+        let model = MachineModel::default();
+        assert_eq!(model.bundle_bytes(), 16);
+        // VLIW.bsv reads mem, branch, alu0, alu1
+        assert_eq!(model.issue_order(), &[2, 3, 0, 1]);
+        assert_eq!(model.latency(Opcode::MUL), 3);
+        assert_eq!(model.latency(Opcode::LW), 1);
+    }
+
+    #[test]
+    fn test_bad_machine() {
+        let no_branch = r#"{"slots": [{"name": "a", "units": ["alu", "mult", "mem"]}],
+            "issue_order": ["a"], "latencies": {"alu": 1, "mult": 3, "mem": 1, "branch": 1}}"#;
+        assert!(MachineModel::from_json(no_branch).unwrap_err().contains("Branch"));
+        let bad_order = r#"{"slots": [{"name": "a", "units": ["alu", "mult", "mem", "branch"]}],
+            "issue_order": ["a", "a"], "latencies": {"alu": 1, "mult": 3, "mem": 1, "branch": 1}}"#;
+        assert!(MachineModel::from_json(bad_order).is_err());
+        let opcode_latency = r#"{"slots": [{"name": "a", "units": ["alu", "mult", "mem", "branch"]}],
+            "issue_order": ["a"], "latencies": {"alu": 1, "mult": 3, "mem": 1, "branch": 1},
+            "opcode_latencies": {"div": 20}}"#;
+        let model = MachineModel::from_json(opcode_latency).unwrap();
+        assert_eq!(model.latency(Opcode::DIV), 20);
+        assert_eq!(model.latency(Opcode::MUL), 3);
+    }
+}
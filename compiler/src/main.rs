@@ -1,119 +1,227 @@
-use analysis::{trace_to_basicblocks,dep_analysis};
-use analysis::AnalyzedProgram;
-use assembler::{assemble, assemble_ap};
-use isa::{Label, Operand};
-use scheduling::{ScheduledProgram,schedule_program};
-//use scheduling::{loop_schedule, ScheduleSlot};
-use serde_json;
+use vliw_opt::analysis::{dep_analysis, find_code_pointers, trace_to_basicblocks, AnalyzedProgram};
+use vliw_opt::assembler::{assemble_ap, HexFormat};
+use vliw_opt::disassembler::disassemble;
+use vliw_opt::elf::Elf;
+use vliw_opt::error::Error;
+use vliw_opt::fuzz::{check, fuzz};
+use vliw_opt::isa::Inst;
+use vliw_opt::machine::MachineModel;
+use vliw_opt::scheduling::{schedule_program, Scheduler};
+use vliw_opt::sim::{Core, Memory};
+use vliw_opt::superblock::load_profile;
+use vliw_opt::verify::verify;
+use vliw_opt::{analyze, compare, emit, optimize, parse, relocate, schedule, CompileOptions};
 use std::fs;
-use std::fs::File;
-use std::io;
+use std::io::{self, Read};
 use std::path::Path;
 
-use isa::Inst;
-use isa::Opcode;
+fn read_input(inp_path: &Path) -> Result<Vec<u8>, Error> {
+    if inp_path.as_os_str() == "STDIN" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)
+            .map_err(|err| Error::new(format!("Error reading STDIN: {}", err)))?;
+        Ok(bytes)
+    } else {
+        fs::read(inp_path)
+            .map_err(|err| Error::new(format!("Error opening {}: {}", inp_path.display(), err)))
+    }
+}
 
-mod analysis;
-mod isa;
-mod scheduling;
-mod assembler;
+/// The compiler settings among the command line flags.
+fn options(args: &Args) -> Result<CompileOptions, Error> {
+    Ok(CompileOptions {
+        scheduler: args.scheduler,
+        model: match &args.machine {
+            Some(path) => MachineModel::load(Path::new(path))?,
+            None => MachineModel::default(),
+        },
+        if_convert: !args.no_if_convert,
+        unroll: args.unroll,
+        rename: !args.no_rename,
+        branch_weights: match &args.profile {
+            Some(path) => load_profile(Path::new(path))?,
+            None => Default::default(),
+        },
+        stats: args.stats,
+    })
+}
 
-fn read_trace(inp_asm_path: &Path) -> Vec<Inst> {
+/// Schedules the input and checks the schedule against the program it was made from,
+/// exiting with the problems on STDERR if there are any.
+fn verify_schedule(inp_path: &Path, opts: &CompileOptions) -> Result<String, Error> {
+    let (trace, elf) = parse(&read_input(inp_path)?)?;
+    let (ap, _) = analyze(trace, elf, opts)?;
+    let (ap, _) = optimize(ap, opts);
+    let sp = schedule_program(ap.clone(), &opts.model, opts.scheduler);
+    match verify(&ap, &sp) {
+        Ok(()) => Ok(format!("{} bundles verified", sp.schedule.len())),
+        Err(problems) => {
+            for problem in problems.iter() {
+                eprintln!("{}", problem);
+            }
+            eprintln!("{} problems in {} bundles", problems.len(), sp.schedule.len());
+            std::process::exit(1);
+        }
+    }
+}
 
-    let inp_json_string = if inp_asm_path.as_os_str() == "STDIN" {
-        io::read_to_string(io::stdin())
-        .map_err(|err| format!("Error opening trace file: {}", err)).unwrap()
+/// Runs a hex image on the simulated core, with its console output and the PASS/FAIL
+/// line on STDERR like Bluesim, and returns the cycle and instruction counts.
+fn simulate(inp_hex: &Path, model: &MachineModel, max_cycles: u64) -> Result<String, Error> {
+    let hex = read_input(inp_hex)?;
+    let mem = Memory::from_hex(&String::from_utf8_lossy(&hex))
+        .map_err(|e| Error::new(format!("Error reading hex image: {}", e)))?;
+    let mut core = Core::new(mem, model);
+    let finish = core.run(max_cycles);
+    eprint!("{}", String::from_utf8_lossy(&core.console));
+    let finish = finish.map_err(|e| Error::new(format!("Simulation failed: {}", e)))?;
+    if finish.code == 0 {
+        eprintln!("  \x1b[0;32mPASS first thread \x1b[0m");
     } else {
-        fs::read_to_string(inp_asm_path)
-        .map_err(|err| format!("Error opening trace file: {}", err)).unwrap()
-    };
-    
-    inp_json_string
-        .lines()
-        .enumerate()
-        .map(|(i,s)| Inst::from_str(s, i*4).unwrap()).collect()
+        eprintln!("  \x1b[0;31mFAIL first thread\x1b[0m ({})", finish.code);
+    }
+    Ok(finish.to_string())
 }
 
-fn label_auipc(trace: &mut Vec<Inst>) {
-    let mut auipc_pc = -1;
-    for inst in trace.iter_mut() {
-        if auipc_pc >= 0 {
-            if inst.opcode != Opcode::ADDI {
-                panic!("Expected ADDI offset right after AUIPC! Got {}", inst);
-            }
-            inst.offset = Some(auipc_pc);
-            inst.label = Label::SrcAddrSpace(auipc_pc as usize);
-            auipc_pc = -1;
-        } else if inst.opcode == Opcode::AUIPC {
-            auipc_pc = inst.addr as i64;
+/// Compares the input with its schedule (see `compare`), exiting with the differences on
+/// STDERR if there are any.
+fn diff(inp_path: &Path, opts: &CompileOptions, max_cycles: u64) -> Result<String, Error> {
+    let (trace, elf) = parse(&read_input(inp_path)?)?;
+    let (problems, summary) = compare(trace, elf, opts, max_cycles)?;
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            eprintln!("{}", problem);
         }
+        eprintln!("{} differences", problems.len());
+        std::process::exit(1);
     }
+    Ok(format!("same results: {}", summary))
 }
 
-fn fix_addresses(sp: &mut ScheduledProgram) {
-    for bundle in sp.schedule.iter_mut() {
-        bundle.addr = bundle.addr * 16;
-        for inst in bundle.valid_insts_mut() {
-            if let Label::SrcAddrSpace(l) = inst.inst.label {
-                let new_addr = sp.starts.get(&l)
-                    .unwrap_or_else(|| panic!("Could not find new label for: {} (inst addr = {})", l, inst.inst.addr));
-                inst.inst.label = Label::DstAddrSpace(*new_addr * 16);
-            }
-            if let Operand::Immediate(mut imm) = inst.inst.src2 {
-                if let Some(offset) = inst.inst.offset {
-                    imm += offset;
-                }
-                if let Label::DstAddrSpace(d) = inst.inst.label {
-                    imm -= d as i64;
-                }
-                inst.inst.src2 = Operand::Immediate(imm);
-            }
+/// Compares random programs with their schedules, printing the failing ones minimized.
+fn fuzz_schedules(opts: &CompileOptions, seed: u64, count: u64, max_cycles: u64) -> Result<String, Error> {
+    // fuzz catches compiler panics and counts them as failures, with the message in the report
+    let failures = fuzz(seed..seed + count, &|src| check(src, opts, max_cycles));
+    if !failures.is_empty() {
+        for failure in failures.iter() {
+            eprintln!("{}\n", failure);
         }
+        eprintln!("{} of {} programs differ", failures.len(), count);
+        std::process::exit(1);
     }
+    Ok(format!("{} programs, no differences", count))
 }
 
+/// The input as plain RV32I in basic blocks, without the VLIW passes: a listing or its words.
+fn rv32(trace: Vec<Inst>, elf: &Elf, args: &Args, opts: &CompileOptions) -> Result<String, Error> {
+    let pointers = find_code_pointers(&trace, &elf.sections, &elf.symbols);
+    let bbs = trace_to_basicblocks(trace, &pointers.targets).map_err(|e| e.with_lines(&elf.lines))?;
+    let ap = AnalyzedProgram {
+        bbs: bbs.into_iter().map(dep_analysis).collect(),
+        indirect_targets: pointers.targets,
+        branch_weights: opts.branch_weights.clone(),
+    };
+    if args.skip_assemble {
+        Ok(ap.to_string())
+    } else {
+        assemble_ap(&ap, args.bytes_hex, args.disassembly).map_err(|e| e.with_lines(&elf.lines))
+    }
+}
 
-
-fn core(inp_json_path: &Path, args: &Args) -> String {
-    let mut trace = read_trace(inp_json_path);
-    let orig_size = trace.len() * 4;
-    if !args.skip_vliw {
-        // remove nops
-        //trace = trace.into_iter().filter(|i| i.opcode != Opcode::NOP).collect();
-        label_auipc(&mut trace);
+fn hex_format(args: &Args) -> Result<HexFormat, Error> {
+    match args.line_bits {
+        Some(bits) if ![32, 64, 128, 256, 512].contains(&bits) => Err(Error::new(format!("Line width must be 32, 64, 128, 256 or 512 bits, got {}", bits))),
+        Some(bits) => Ok(HexFormat::Lines(bits / 32)),
+        None if args.bytes_hex => Ok(HexFormat::Bytes),
+        None => Ok(HexFormat::Words),
     }
-    let ap_insns = trace_to_basicblocks(trace).into_iter().map(|bb| dep_analysis(bb)).collect();
-    let ap = AnalyzedProgram {
-        bbs: ap_insns
+}
+
+fn core(inp_path: &Path, args: &Args, opts: &CompileOptions) -> Result<String, Error> {
+    let (trace, elf) = parse(&read_input(inp_path)?)?;
+    // listings start with the section and symbol tables, if the input had any
+    let header = if args.skip_assemble && !(elf.sections.is_empty() && elf.symbols.is_empty()) {
+        format!("{}", elf)
+    } else {
+        String::new()
     };
-    if !args.skip_vliw {  
-        let mut sp = schedule_program(ap);
-        fix_addresses(&mut sp);
-        if !args.skip_assemble {
-            assemble(&sp, orig_size, args.bytes_hex)
-        } else {
-            format!("{}", sp)
-        }
+    if args.skip_vliw {
+        return Ok(format!("{}{}", header, rv32(trace, &elf, args, opts)?));
+    }
+    let (ap, mut image) = analyze(trace, elf, opts)?;
+    let (mut sp, stats) = schedule(ap, opts);
+    if opts.stats {
+        eprint!("{}", stats);
+    }
+    relocate(&mut sp, &mut image)?;
+    if !args.skip_assemble {
+        emit(&sp, &image, hex_format(args)?)
     } else {
-        if !args.skip_assemble {
-            assemble_ap(&ap, args.bytes_hex, args.disassembly)
-        } else { 
-            format!("{}", ap)
-        }
+        Ok(format!("{}{}", header, sp))
     }
 }
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Reconstruct the bundle listing of a hex image produced by vliw_opt
+    Disasm {
+        // Input hex file (STDIN works)
+        inphex: String,
+    },
+    /// Schedule the input and check the schedule keeps every dependence, slot and branch right
+    Verify {
+        // Input RV32 ELF or ASM file (STDIN works)
+        inpasm: String,
+    },
+    /// Run a hex image produced by vliw_opt on a cycle-level model of the core in hw/. Every unit takes one cycle there, the machine's latencies are not modelled
+    Sim {
+        // Input hex file (STDIN works)
+        inphex: String,
+
+        // Give up if the program has not exited after this many cycles
+        #[arg(long,default_value_t=100_000_000)]
+        max_cycles: u64,
+    },
+    /// Run the input on a reference RV32I interpreter and its schedule on the simulated core, and compare the results
+    Diff {
+        // Input RV32 ELF or ASM file (STDIN works)
+        inpasm: String,
 
-/// Simple program to greet a person
+        // Give up if either side has not exited after this many cycles (instructions on the reference)
+        #[arg(long,default_value_t=100_000_000)]
+        max_cycles: u64,
+    },
+    /// Compare random programs with their schedules, as diff does, and print the failing ones minimized
+    Fuzz {
+        // First seed
+        #[arg(long,default_value_t=0)]
+        seed: u64,
+
+        // Number of programs
+        #[arg(long,default_value_t=100)]
+        count: u64,
+
+        // Give up on a program after this many cycles
+        #[arg(long,default_value_t=100_000)]
+        max_cycles: u64,
+    },
+}
+
+/// Compiles RV32I ELF or assembly files to hex images for the VLIW core in hw/
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    // Input ASM file (STDIN works)
-    inpasm: String,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
+    // Input RV32 ELF or ASM file (STDIN works)
+    #[arg(required = true)]
+    inpasm: Option<String>,
 
     // Output file (default is STDOUT)
-    #[arg(short,long,default_value="STDOUT")]
+    #[arg(short,long,default_value="STDOUT",global=true)]
     out: String,
 
     #[arg(short='a',long)]
@@ -122,24 +230,74 @@ struct Args {
     #[arg(short='b',long)]
     bytes_hex: bool,
 
+    // Write memory lines of this many bits instead of words, 512 for hw/mem and 128 for bundles
+    #[arg(long,conflicts_with_all=["bytes_hex", "skip_vliw", "skip_assemble"])]
+    line_bits: Option<usize>,
+
     #[arg(short='d',long)]
     disassembly: bool,
 
     #[arg(short='v',long)]
     skip_vliw: bool,
+
+    #[arg(long,value_enum,default_value_t=Scheduler::Superblock,global=true)]
+    scheduler: Scheduler,
+
+    // Keep short branches instead of computing both sides and selecting the results
+    #[arg(long,global=true)]
+    no_if_convert: bool,
+
+    // Copies of the body of counted single-block loops to schedule together (1 disables unrolling)
+    #[arg(long,default_value_t=1,global=true)]
+    unroll: usize,
+
+    // Keep the registers the input uses instead of renaming live ranges apart
+    #[arg(long,global=true)]
+    no_rename: bool,
+
+    // Branch profile (JSON, branch address -> [taken, not taken]) guiding trace formation
+    #[arg(long,global=true)]
+    profile: Option<String>,
+
+    // Print bundle counts of each scheduler to STDERR
+    #[arg(short='s',long)]
+    stats: bool,
+
+    // Machine description (JSON), defaults to the 4-slot core in hw/
+    #[arg(short='m',long,global=true)]
+    machine: Option<String>,
 }
 
-fn main() {
-    let args = Args::parse();
-    let inp_asm_path = Path::new(&args.inpasm);
-    //let out_asm_path = Path::new(&out_asm_path);
-    
-    let out_insns = core(inp_asm_path,&args);
+/// Runs the mode `args` ask for and writes its output.
+fn run(args: &Args) -> Result<(), Error> {
+    let opts = options(args)?;
+    let out_insns = match &args.mode {
+        Some(Mode::Disasm { inphex }) => {
+            let hex = read_input(Path::new(inphex))?;
+            disassemble(&String::from_utf8_lossy(&hex), &opts.model)
+                .map_err(|e| Error::new(format!("Error disassembling {}: {}", inphex, e)))
+        }
+        Some(Mode::Verify { inpasm }) => verify_schedule(Path::new(inpasm), &opts),
+        Some(Mode::Sim { inphex, max_cycles }) => simulate(Path::new(inphex), &opts.model, *max_cycles),
+        Some(Mode::Diff { inpasm, max_cycles }) => diff(Path::new(inpasm), &opts, *max_cycles),
+        Some(Mode::Fuzz { seed, count, max_cycles }) => fuzz_schedules(&opts, *seed, *count, *max_cycles),
+        None => core(Path::new(args.inpasm.as_ref().unwrap()), args, &opts),
+    }?;
         
     if &args.out == "STDOUT" {
         println!("{}", out_insns);
     } else {
         let out_path = Path::new(&args.out);
-        std::fs::write(out_path, out_insns).unwrap();
+        std::fs::write(out_path, out_insns)
+            .map_err(|e| Error::new(format!("Error writing {}: {}", out_path.display(), e)))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(&args) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::analysis::{reg_reads, reg_write, AnalyzedBasicBlock, DepInst};
+use crate::isa::{ExecutionUnit, Inst, Label, Opcode, Operand};
+use crate::machine::MachineModel;
+use crate::scheduling::Bundle;
+
+/// Summary of a software pipelined loop, for `--stats`.
+#[derive(Debug, Clone)]
+pub struct PipelinedLoop {
+    pub addr: usize,
+    pub res_mii: usize,
+    pub rec_mii: usize,
+    pub ii: usize,
+    pub stages: usize,
+    // bundles per iteration without pipelining
+    pub flat_len: usize,
+}
+
+impl fmt::Display for PipelinedLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "loop @ {:x}: II {} (ResMII {}, RecMII {}), {} stages, {} -> {} bundles per iteration",
+            self.addr, self.ii, self.res_mii, self.rec_mii, self.stages, self.flat_len, self.ii)
+    }
+}
+
+/// `bne iv, bound` closing a block with a single `addi iv, iv, step` and an invariant bound.
+struct CountedLoop {
+    iv: u32,
+    bound: u32,
+    step: i64,
+    // written before it is read in the body, so free on entry for the trip count guard
+    scratch: u32,
+}
+
+/// `from` has to issue `latency` cycles before `to` of `distance` iterations later.
+struct Edge {
+    from: usize,
+    to: usize,
+    latency: i64,
+    distance: i64,
+}
+
+fn counted_loop(bb: &AnalyzedBasicBlock) -> Option<CountedLoop> {
+    let cf = &bb.cf_insn.as_ref()?.inst;
+    let first = bb.insns.first()?.inst.addr;
+    if cf.opcode != Opcode::BNE || cf.label != Label::SrcAddrSpace(first) {
+        return None;
+    }
+    // copies of a pc-relative pair would need their own offsets
+    if bb.insns.iter().any(|i| i.inst.opcode == Opcode::AUIPC || (i.inst.offset.is_some() && i.inst.label != Label::None)) {
+        return None;
+    }
+    let defs = |reg: u32| bb.insns.iter().filter(|i| reg_write(&i.inst) == Some(reg)).collect::<Vec<_>>();
+    let (a, b) = (cf.src2.unwrap_gpr(), cf.src1.unwrap());
+    let (iv, bound, update) = [(a, b), (b, a)].into_iter().find_map(|(iv, bound)| {
+        match (defs(iv).as_slice(), defs(bound).len()) {
+            ([update], 0) if iv != bound => Some((iv, bound, update.inst)),
+            _ => None,
+        }
+    })?;
+    let Operand::Immediate(step) = update.src2 else { return None };
+    if update.opcode != Opcode::ADDI || update.src1 != Some(iv) || step == 0 {
+        return None;
+    }
+    let mut seen = Vec::new();
+    let mut scratch = None;
+    for inst in bb.insns.iter().map(|i| &i.inst) {
+        seen.extend(reg_reads(inst));
+        if let Some(rd) = reg_write(inst) {
+            if scratch.is_none() && !seen.contains(&rd) && rd != iv && rd != bound {
+                scratch = Some(rd);
+            }
+            seen.push(rd);
+        }
+    }
+    Some(CountedLoop { iv, bound, step, scratch: scratch? })
+}
+
+/// The immediate `inst` adds to `iv`, if that is all it reads `iv` for.
+pub fn iv_immediate(inst: &Inst, iv: u32) -> Option<i64> {
+    match (inst.opcode, inst.src2) {
+        (Opcode::LB | Opcode::LH | Opcode::LW | Opcode::LBU | Opcode::LHU, _) if inst.src1 == Some(iv) => inst.offset,
+        (Opcode::SB | Opcode::SH | Opcode::SW, Operand::Gpr(base)) if base == iv && inst.src1 != Some(iv) => inst.offset,
+        (Opcode::ADDI, Operand::Immediate(imm)) if inst.src1 == Some(iv) && inst.label == Label::None => Some(imm),
+        _ => None,
+    }
+}
+
+pub fn shift_iv_immediate(inst: &mut Inst, by: i64) {
+    match inst.src2 {
+        Operand::Immediate(imm) if inst.opcode == Opcode::ADDI => inst.src2 = Operand::Immediate(imm + by),
+        _ => inst.offset = inst.offset.map(|o| o + by),
+    }
+}
+
+pub fn is_mem(inst: &Inst) -> bool {
+    inst.opcode.eu_type() == ExecutionUnit::Mem
+}
+
+pub fn is_store(inst: &Inst) -> bool {
+    matches!(inst.opcode, Opcode::SB | Opcode::SH | Opcode::SW | Opcode::FENCE)
+}
+
+/// Dependences within an iteration come from the block's dep analysis, the ones into the
+/// next iteration keep every register value alive until its last use and keep stores in order.
+/// Bundles read their registers before any of them write, so a write only has to wait for
+/// earlier reads to issue, not to complete.
+/// `keep` drops dependences within the iteration that the caller resolves itself.
+fn loop_edges(model: &MachineModel, nodes: &[&DepInst], keep: impl Fn(usize, usize) -> bool) -> Vec<Edge> {
+    let index: HashMap<usize, usize> = nodes.iter().enumerate().map(|(i, n)| (n.inst.addr, i)).collect();
+    let latency = |i: usize| model.latency(nodes[i].inst.opcode) as i64;
+    let writes = |i: usize, reg: u32| reg_write(&nodes[i].inst) == Some(reg);
+    let mut edges = Vec::new();
+    for (to, node) in nodes.iter().enumerate() {
+        let true_deps = node.src1.iter().chain(node.src2.iter()).chain(node.mem_deps.iter());
+        for dep in true_deps {
+            if let Some(from) = index.get(&dep.addr) {
+                edges.push(Edge { from: *from, to, latency: latency(*from), distance: 0 });
+            }
+        }
+        for dep in node.false_deps.iter() {
+            if let Some(from) = index.get(&dep.addr).filter(|from| keep(**from, to)) {
+                let latency = if writes(*from, dep.reg) { 1 } else { 0 };
+                edges.push(Edge { from: *from, to, latency, distance: 0 });
+            }
+        }
+    }
+    for (u, nu) in nodes.iter().enumerate() {
+        for (v, nv) in nodes.iter().enumerate() {
+            let (u_reads, u_write) = (reg_reads(&nu.inst), reg_write(&nu.inst));
+            let (v_reads, v_write) = (reg_reads(&nv.inst), reg_write(&nv.inst));
+            // v reads the value u leaves behind if nothing before v redefines it
+            let last_def = u_write.is_some_and(|r| (u + 1..nodes.len()).all(|n| !writes(n, r)));
+            let exposed = |r: u32| (0..v).all(|n| !writes(n, r));
+            let true_dep = last_def && u_write.is_some_and(|r| v_reads.contains(&r) && exposed(r));
+            let mem_dep = is_mem(&nu.inst) && is_mem(&nv.inst) && (is_store(&nu.inst) || is_store(&nv.inst));
+            let latency = if true_dep || mem_dep {
+                latency(u)
+            } else if u_write.is_some() && u_write == v_write {
+                1
+            } else if v_write.is_some_and(|r| u_reads.contains(&r)) {
+                0
+            } else {
+                continue;
+            };
+            edges.push(Edge { from: u, to: v, latency, distance: 1 });
+        }
+    }
+    edges
+}
+
+/// Largest number of instructions per bundle any set of units can sustain.
+fn res_mii(model: &MachineModel, nodes: &[&DepInst]) -> usize {
+    let units = [ExecutionUnit::ALU, ExecutionUnit::Mult, ExecutionUnit::Mem, ExecutionUnit::Branch];
+    (1..1 << units.len()).map(|set: usize| {
+        let in_set = |u: ExecutionUnit| units.iter().enumerate().any(|(i, x)| set & (1 << i) != 0 && *x == u);
+        let need = nodes.iter().filter(|n| in_set(n.inst.opcode.eu_type())).count();
+        let slots = (0..model.width()).filter(|s| units.iter().any(|u| in_set(*u) && model.accepts(*s, *u))).count();
+        need.div_ceil(slots)
+    }).max().unwrap_or(1)
+}
+
+/// Longest path between every pair of nodes when iterations start `ii` cycles apart,
+/// None if a dependence cycle needs more than that.
+fn longest_paths(n: usize, edges: &[Edge], ii: i64) -> Option<Vec<Vec<i64>>> {
+    let mut dist = vec![vec![i64::MIN; n]; n];
+    for e in edges.iter() {
+        dist[e.from][e.to] = dist[e.from][e.to].max(e.latency - ii * e.distance);
+    }
+    for k in 0..n {
+        for i in 0..n {
+            if dist[i][k] == i64::MIN {
+                continue;
+            }
+            for j in 0..n {
+                if dist[k][j] != i64::MIN {
+                    dist[i][j] = dist[i][j].max(dist[i][k].saturating_add(dist[k][j]));
+                }
+            }
+        }
+        // a positive cycle only grows from here on
+        if (0..n).any(|i| dist[i][i] > 0) {
+            return None;
+        }
+    }
+    Some(dist)
+}
+
+/// Smallest II at which no dependence cycle needs more than II cycles per iteration.
+/// A larger II only shortens cycles, so the search halves the range.
+fn rec_mii(n: usize, edges: &[Edge]) -> usize {
+    let (mut lo, mut hi) = (1, edges.iter().map(|e| e.latency).sum::<i64>().max(1));
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if longest_paths(n, edges, mid).is_some() {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+    lo as usize
+}
+
+// placement attempts per II before giving up on it
+const PLACEMENT_BUDGET: usize = 4096;
+// longer loop bodies aren't pipelined, the all pairs paths are cubic in their length
+const MAX_NODES: usize = 128;
+
+struct Placer<'a> {
+    model: &'a MachineModel,
+    nodes: &'a [&'a DepInst],
+    edges: &'a [Edge],
+    ii: i64,
+    window: Vec<(i64, i64)>,
+    order: Vec<usize>,
+    mrt: Vec<Vec<bool>>,
+    placed: Vec<Option<(usize, usize)>>,
+    budget: usize,
+}
+
+impl Placer<'_> {
+    fn time(&self, i: usize) -> Option<i64> {
+        self.placed[i].map(|(t, _)| t as i64)
+    }
+
+    /// Places `order[k..]`, backtracking into the earlier choices while the budget lasts.
+    fn place(&mut self, k: usize) -> bool {
+        let Some(&x) = self.order.get(k) else { return true };
+        if self.budget == 0 {
+            return false;
+        }
+        self.budget -= 1;
+        let lo = self.edges.iter().filter(|e| e.to == x)
+            .filter_map(|e| self.time(e.from).map(|t| t + e.latency - self.ii * e.distance))
+            .fold(self.window[x].0, i64::max);
+        let hi = self.edges.iter().filter(|e| e.from == x)
+            .filter_map(|e| self.time(e.to).map(|t| t - e.latency + self.ii * e.distance))
+            .fold(self.window[x].1, i64::min);
+        let unit = self.nodes[x].inst.opcode.eu_type();
+        for t in lo..=hi.min(lo + self.ii - 1) {
+            let row = (t % self.ii) as usize;
+            for slot in 0..self.model.width() {
+                if self.mrt[row][slot] || !self.model.accepts(slot, unit) {
+                    continue;
+                }
+                self.mrt[row][slot] = true;
+                self.placed[x] = Some((t as usize, slot));
+                if self.place(k + 1) {
+                    return true;
+                }
+                self.mrt[row][slot] = false;
+                self.placed[x] = None;
+            }
+        }
+        false
+    }
+}
+
+/// Places the nodes in program order into a modulo reservation table, the branch fixed in
+/// the last bundle of the first stage so it can test the trip count unchanged.
+/// Returns the (cycle, slot) of each node.
+fn modulo_place(model: &MachineModel, nodes: &[&DepInst], edges: &[Edge], ii: usize) -> Option<Vec<(usize, usize)>> {
+    let cf = nodes.len() - 1;
+    let branch_at = ii as i64 - 1;
+    let dist = longest_paths(nodes.len(), edges, ii as i64)?;
+    // the window every node has around the branch, before anything else is placed
+    let window = (0..nodes.len()).map(|x| {
+        let lo = if dist[cf][x] == i64::MIN { 0 } else { (branch_at + dist[cf][x]).max(0) };
+        let hi = if dist[x][cf] == i64::MIN { i64::MAX } else { branch_at - dist[x][cf] };
+        if x == cf { (branch_at, branch_at) } else { (lo, hi) }
+    }).collect();
+    let mut placer = Placer {
+        model,
+        nodes,
+        edges,
+        ii: ii as i64,
+        window,
+        order: std::iter::once(cf).chain(0..cf).collect(),
+        mrt: vec![vec![false; model.width()]; ii],
+        placed: vec![None; nodes.len()],
+        budget: PLACEMENT_BUDGET,
+    };
+    if !placer.place(0) {
+        return None;
+    }
+    placer.placed.into_iter().collect()
+}
+
+pub fn synthetic(inst: Inst) -> DepInst {
+    DepInst { inst, false_deps: Vec::new(), mem_deps: Vec::new(), src1: None, src2: None }
+}
+
+fn push_bundle(model: &MachineModel, schedule: &mut Vec<Bundle>, insts: Vec<(usize, DepInst)>) {
+    let mut bundle = Bundle::new(schedule.len(), model.width());
+    for (slot, inst) in insts {
+        bundle.slots[slot] = Some(inst);
+    }
+    schedule.push(bundle);
+}
+
+/// Puts `inst` in a fresh bundle on its own.
+pub fn push_alone(model: &MachineModel, schedule: &mut Vec<Bundle>, inst: Inst) {
+    let slot = (0..model.width()).find(|s| model.accepts(*s, inst.opcode.eu_type()))
+        .expect("MachineModel::from_json checks every unit has a slot");
+    push_bundle(model, schedule, vec![(slot, synthetic(inst))]);
+}
+
+/// Software pipelines a single-block counted loop when that beats `flat_len` bundles per iteration.
+/// Emits a trip count guard, the prologue, the kernel and the epilogue, which jumps to `exit`,
+/// the source address of the block after the loop. The guard branches to the end of what is
+/// emitted here when there are fewer iterations than stages, the caller schedules the original
+/// loop there as the fallback.
+pub fn pipeline_loop(
+    model: &MachineModel,
+    bb: &AnalyzedBasicBlock,
+    exit: usize,
+    flat_len: usize,
+    schedule: &mut Vec<Bundle>,
+) -> Option<PipelinedLoop> {
+    let counted = counted_loop(bb)?;
+    let cf = bb.cf_insn.as_ref()?;
+    let nodes: Vec<&DepInst> = bb.insns.iter().chain(std::iter::once(cf)).collect();
+    if nodes.len() > MAX_NODES {
+        return None;
+    }
+    // registers stepped by a single `addi r, r, step`; instructions that read one only to add
+    // an immediate may issue after the update, with the step taken back out of the immediate
+    let steps: Vec<(usize, u32, i64)> = nodes.iter().enumerate().filter_map(|(i, n)| {
+        let r = reg_write(&n.inst)?;
+        let step = iv_immediate(&n.inst, r)?;
+        (n.inst.opcode == Opcode::ADDI && nodes.iter().filter(|m| reg_write(&m.inst) == Some(r)).count() == 1)
+            .then_some((i, r, step))
+    }).collect();
+    let movable: Vec<Option<(usize, i64)>> = nodes.iter().enumerate().map(|(i, n)| {
+        steps.iter().find(|(update, r, step)| {
+            i < *update && iv_immediate(&n.inst, *r).is_some_and(|imm| (-2048..2048).contains(&(imm - step)))
+        }).map(|(update, _, step)| (*update, *step))
+    }).collect();
+    let edges = loop_edges(model, &nodes, |from, to| movable[from].is_none_or(|(update, _)| update != to));
+    let res_mii = res_mii(model, &nodes);
+    let rec_mii = rec_mii(nodes.len(), &edges);
+    // the branch needs the induction variable update in the same stage
+    let min_ii = res_mii.max(rec_mii).max(2);
+    let (ii, placement) = (min_ii..flat_len).find_map(|ii| modulo_place(model, &nodes, &edges, ii).map(|p| (ii, p)))?;
+    let stages = placement.iter().map(|(t, _)| t / ii).max()? + 1;
+    let span = counted.step.unsigned_abs() as i64 * stages as i64;
+    if stages < 2 || span >= 2048 {
+        return None;
+    }
+
+    let bytes = model.bundle_bytes();
+    let addr = nodes[0].inst.addr;
+    // fall back to the original loop when bound - iv < stages * step
+    let (hi, lo) = if counted.step > 0 { (counted.bound, counted.iv) } else { (counted.iv, counted.bound) };
+    let r = counted.scratch;
+    push_alone(model, schedule, Inst { addr, dest: Operand::Gpr(r), src1: Some(hi), src2: Operand::Gpr(lo), ..Inst::new(Opcode::SUB) });
+    push_alone(model, schedule, Inst { addr, dest: Operand::Gpr(r), src1: Some(r), src2: Operand::Immediate(span), ..Inst::new(Opcode::SLTIU) });
+    let guard = schedule.len();
+    push_alone(model, schedule, Inst { addr, src1: Some(0), src2: Operand::Gpr(r), ..Inst::new(Opcode::BNE) });
+
+    let body: Vec<DepInst> = nodes.iter().enumerate().map(|(i, n)| {
+        let mut n = (*n).clone();
+        // the loop carried dependence keeps it ahead of the next iteration's update
+        if let Some((_, step)) = movable[i].filter(|(update, _)| placement[i].0 > placement[*update].0) {
+            shift_iv_immediate(&mut n.inst, -step);
+        }
+        n
+    }).collect();
+    let copy = |stage_ok: &dyn Fn(usize) -> bool, row: usize| -> Vec<(usize, DepInst)> {
+        body.iter().zip(placement.iter())
+            .filter(|(_, (t, _))| t % ii == row && stage_ok(t / ii))
+            .map(|(n, (_, s))| (*s, n.clone()))
+            .collect()
+    };
+    for p in 0..stages - 1 {
+        for row in 0..ii {
+            // the first stage branch only matters in the kernel
+            let insts: Vec<_> = copy(&|s| s <= p, row).into_iter()
+                .filter(|(_, n)| !n.inst.opcode.is_control_flow())
+                .collect();
+            // the scoreboard covers latencies, empty bundles would only cost fetches
+            if !insts.is_empty() {
+                push_bundle(model, schedule, insts);
+            }
+        }
+    }
+    let kernel = schedule.len();
+    for row in 0..ii {
+        let mut insts = copy(&|_| true, row);
+        for (_, inst) in insts.iter_mut().filter(|(_, n)| n.inst.opcode.is_control_flow()) {
+            inst.inst.label = Label::DstAddrSpace(kernel * bytes);
+        }
+        push_bundle(model, schedule, insts);
+    }
+    for e in 0..stages - 1 {
+        for row in 0..ii {
+            let insts = copy(&|s| s > e, row);
+            if !insts.is_empty() {
+                push_bundle(model, schedule, insts);
+            }
+        }
+    }
+    let exit = Inst { addr: cf.inst.addr, dest: Operand::Gpr(0), label: Label::SrcAddrSpace(exit), ..Inst::new(Opcode::J) };
+    let last = schedule.last_mut().unwrap();
+    match (0..model.width()).find(|s| last.slots[*s].is_none() && model.accepts(*s, ExecutionUnit::Branch)) {
+        Some(slot) => last.slots[slot] = Some(synthetic(exit)),
+        None => push_alone(model, schedule, exit),
+    }
+    let fallback = schedule.len();
+    schedule[guard].slots.iter_mut().flatten().for_each(|g| g.inst.label = Label::DstAddrSpace(fallback * bytes));
+
+    Some(PipelinedLoop { addr, res_mii, rec_mii, ii, stages, flat_len })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::scheduling::Scheduler;
+    use crate::testutil::{assert_same, schedule};
+
+    const DOT: &str = "
+            li a3, 0
+        1:  lw a4, 0(a0)
+            lw a5, 0(a1)
+            addi a0, a0, 4
+            addi a1, a1, 4
+            mul a4, a4, a5
+            add a3, a3, a4
+            bne a0, a2, 1b
+            mv a0, a3
+            ret
+    ";
+
+    #[test]
+    fn test_pipelined_dot_product() {
+        let modulo = schedule(DOT, Scheduler::Modulo);
+        assert_eq!(modulo.pipelined.len(), 1);
+        let info = &modulo.pipelined[0];
+        assert_eq!((info.res_mii, info.rec_mii, info.ii, info.stages), (2, 4, 4, 2));
+        assert!(info.ii < info.flat_len);
+        // short trip counts take the fallback loop
+        for n in [1, 2, 3, 8] {
+            let mut mem = HashMap::new();
+            for i in 0..n {
+                mem.insert(0x100 + 4 * i, i + 1);
+                mem.insert(0x200 + 4 * i, 2 * i + 3);
+            }
+            let mut regs = [0; 32];
+            regs[10] = 0x100;
+            regs[11] = 0x200;
+            regs[12] = 0x100 + 4 * n;
+            let got = assert_same(DOT, &modulo, regs, &mem);
+            assert_eq!(got[10], (1..=n).map(|i| i * (2 * i + 1)).sum::<u32>());
+        }
+    }
+
+    #[test]
+    fn test_pipelined_loops_match() {
+        let loops = [
+            // prefix sums, the store feeds the next load so there is nothing to overlap
+            ("1: lw a4, 0(a0)\n add a3, a3, a4\n sw a3, 0(a1)\n addi a0, a0, 4\n addi a1, a1, 4\n bne a0, a2, 1b\n ret\n", false),
+            // a[i] += 3 * a[i-1], in place
+            ("1: lw a4, 0(a0)\n mul a3, a3, a5\n add a3, a3, a4\n sw a3, 0(a0)\n addi a0, a0, 4\n bne a0, a2, 1b\n ret\n", true),
+            // counting down to zero, squaring in place
+            ("1: slli a4, a6, 2\n add a4, a4, a1\n lw a5, 0(a4)\n mul a5, a5, a5\n sw a5, 0(a4)\n addi a6, a6, -1\n bne a6, x0, 1b\n ret\n", true),
+        ];
+        for (src, pipelined) in loops {
+            let modulo = schedule(src, Scheduler::Modulo);
+            assert_eq!(modulo.pipelined.len() == 1, pipelined, "{}", src);
+            for n in 1..12 {
+                let mem: HashMap<u32, u32> = (0..16).map(|i| (0x100 + 4 * i, 7 * i + 1)).collect();
+                let mut regs = [0; 32];
+                regs[10] = 0x100;
+                regs[11] = 0x100;
+                regs[12] = 0x100 + 4 * n;
+                regs[15] = 3;
+                regs[16] = n;
+                assert_same(src, &modulo, regs, &mem);
+            }
+        }
+    }
+
+    #[test]
+    fn test_long_loop_body() {
+        // the recurrence through a0 is as long as the body, below and above the size limit
+        for len in [60, 200] {
+            let src = format!("1: {}addi a2, a2, -1\n bnez a2, 1b\n ret\n", "addi a0, a0, 1\n ".repeat(len));
+            for scheduler in [Scheduler::Modulo, Scheduler::Superblock] {
+                let sp = schedule(&src, scheduler);
+                assert!(sp.pipelined.is_empty());
+                let mut regs = [0; 32];
+                regs[12] = 3;
+                let got = assert_same(&src, &sp, regs, &HashMap::new());
+                assert_eq!(got[10], 3 * len as u32);
+            }
+        }
+    }
+}
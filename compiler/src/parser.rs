@@ -0,0 +1,623 @@
+use std::collections::HashMap;
+
+use crate::assembler::assemble_insn;
+use crate::decoder::decode_insn;
+use crate::elf::{Elf, Section, Symbol, SymbolKind};
+use crate::error::Error;
+use crate::isa::{expand_pseudo, hi20, lo12, Inst, Opcode, Operand};
+
+/// Output sections in memory order, laid out back to back like sw/tests.ld does.
+const SECTIONS: [&str; 4] = [".text", ".rodata", ".data", ".bss"];
+
+/// Directives that only matter to a linker or debugger.
+const IGNORED_DIRECTIVES: [&str; 12] = [
+    ".globl", ".global", ".local", ".weak", ".hidden", ".file", ".size",
+    ".ident", ".attribute", ".option", ".addrsig", ".addrsig_sym",
+];
+
+enum Item {
+    Label(String),
+    Directive(String, Vec<String>),
+    Inst(String, Vec<String>),
+}
+
+struct Stmt {
+    line: usize,
+    // None inside sections we don't load, e.g. .note.GNU-stack
+    section: Option<usize>,
+    offset: usize,
+    size: usize,
+    item: Item,
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            '/' if !in_string && line[i..].starts_with("//") => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_ident(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
+
+/// Splits an operand list on the commas that are outside parentheses and strings.
+fn split_operands(s: &str) -> Vec<String> {
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    for c in s.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !current.trim().is_empty() || !operands.is_empty() {
+        operands.push(current.trim().to_string());
+    }
+    operands
+}
+
+fn parse_line(line: &str) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut rest = strip_comment(line).trim();
+    while let Some((label, tail)) = rest.split_once(':') {
+        if !is_ident(label) {
+            break;
+        }
+        items.push(Item::Label(label.to_string()));
+        rest = tail.trim();
+    }
+    if rest.is_empty() {
+        return items;
+    }
+    let (head, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    if head.starts_with('.') {
+        items.push(Item::Directive(head.to_string(), split_operands(tail)));
+    } else {
+        items.push(Item::Inst(head.to_lowercase(), split_operands(tail)));
+    }
+    items
+}
+
+fn section_index(name: &str) -> Option<usize> {
+    // GNU as takes the name quoted too, e.g. `.section ".text.init"`
+    let name = name.trim_matches('"');
+    match name {
+        _ if name.starts_with(".text") => Some(0),
+        _ if name.starts_with(".rodata") || name.starts_with(".srodata") => Some(1),
+        _ if name.starts_with(".data") || name.starts_with(".sdata") => Some(2),
+        _ if name.starts_with(".bss") || name.starts_with(".sbss") => Some(3),
+        _ => None,
+    }
+}
+
+fn parse_string(arg: &str) -> Result<Vec<u8>, String> {
+    let Some(body) = arg.strip_prefix('"').and_then(|a| a.strip_suffix('"')) else {
+        return Err(format!("Expected a string literal, got {}", arg));
+    };
+    let mut bytes = Vec::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            other => return Err(format!("Unsupported escape \\{}", other.map_or(String::new(), String::from))),
+        });
+    }
+    Ok(bytes)
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+struct Assembler {
+    symbols: HashMap<String, i64>,
+    // numeric labels can be redefined, so keep every definition with its statement index
+    locals: Vec<(String, usize, i64)>,
+    // auipc address -> the target of its %pcrel_hi, for the matching %pcrel_lo
+    pcrel_hi: HashMap<i64, i64>,
+    stmt: usize,
+    pc: i64,
+}
+
+impl Assembler {
+    fn eval(&mut self, expr: &str) -> Result<i64, String> {
+        let chars: Vec<char> = expr.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut pos = 0;
+        let value = self.eval_sum(&chars, &mut pos)?;
+        if pos != chars.len() {
+            return Err(format!("Unexpected '{}' in expression {}", chars[pos..].iter().collect::<String>(), expr));
+        }
+        Ok(value)
+    }
+
+    fn eval_sum(&mut self, chars: &[char], pos: &mut usize) -> Result<i64, String> {
+        let mut value = self.eval_unary(chars, pos)?;
+        while let Some(op) = chars.get(*pos).copied() {
+            if op != '+' && op != '-' {
+                break;
+            }
+            *pos += 1;
+            let rhs = self.eval_unary(chars, pos)?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn eval_unary(&mut self, chars: &[char], pos: &mut usize) -> Result<i64, String> {
+        match chars.get(*pos) {
+            Some('-') => {
+                *pos += 1;
+                Ok(-self.eval_unary(chars, pos)?)
+            }
+            Some('(') => {
+                *pos += 1;
+                let value = self.eval_sum(chars, pos)?;
+                self.expect(chars, pos, ')')?;
+                Ok(value)
+            }
+            Some('%') => {
+                *pos += 1;
+                let func = self.ident(chars, pos);
+                self.expect(chars, pos, '(')?;
+                let arg = self.eval_sum(chars, pos)?;
+                self.expect(chars, pos, ')')?;
+                self.relocation(&func, arg)
+            }
+            Some(c) => {
+                let token = self.ident(chars, pos);
+                if token.is_empty() {
+                    return Err(format!("Unexpected '{}' in expression", c));
+                }
+                self.resolve(&token)
+            }
+            None => Err(String::from("Unexpected end of expression")),
+        }
+    }
+
+    fn ident(&self, chars: &[char], pos: &mut usize) -> String {
+        let start = *pos;
+        while *pos < chars.len() && is_ident(&chars[*pos].to_string()) {
+            *pos += 1;
+        }
+        chars[start..*pos].iter().collect()
+    }
+
+    fn expect(&self, chars: &[char], pos: &mut usize, c: char) -> Result<(), String> {
+        if chars.get(*pos) != Some(&c) {
+            return Err(format!("Expected '{}' in expression", c));
+        }
+        *pos += 1;
+        Ok(())
+    }
+
+    fn relocation(&mut self, func: &str, value: i64) -> Result<i64, String> {
+        match func {
+            "hi" => Ok(hi20(value)),
+            "lo" => Ok(lo12(value)),
+            "pcrel_hi" => {
+                self.pcrel_hi.insert(self.pc, value);
+                Ok(hi20(value - self.pc))
+            }
+            "pcrel_lo" => {
+                // the argument labels the auipc holding the matching %pcrel_hi
+                let target = self.pcrel_hi.get(&value)
+                    .ok_or_else(|| format!("%pcrel_lo refers to {:#x}, which has no %pcrel_hi", value))?;
+                // label_auipc only relocates an auipc together with the instruction after it
+                if value != self.pc - 4 {
+                    return Err(format!("%pcrel_lo must be right after the auipc at {:#x} it refers to", value));
+                }
+                Ok(lo12(target - value))
+            }
+            _ => Err(format!("Unsupported relocation %{}", func)),
+        }
+    }
+
+    fn resolve(&self, token: &str) -> Result<i64, String> {
+        if token == "." {
+            return Ok(self.pc);
+        }
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            // 1b/1f refer to the closest definition of the numeric label 1 before/after this statement
+            let (name, dir) = token.split_at(token.len() - 1);
+            if name.chars().all(|c| c.is_ascii_digit()) && (dir == "b" || dir == "f") {
+                let found = if dir == "b" {
+                    self.locals.iter().rev().find(|(n, stmt, _)| n == name && *stmt < self.stmt)
+                } else {
+                    self.locals.iter().find(|(n, stmt, _)| n == name && *stmt > self.stmt)
+                };
+                return found.map(|(_, _, addr)| *addr)
+                    .ok_or_else(|| format!("Undefined local label {}", token));
+            }
+            return match Operand::from_str(token)? {
+                Operand::Immediate(value) => Ok(value),
+                _ => Err(format!("Expected a number, got {}", token)),
+            };
+        }
+        self.symbols.get(token).copied().ok_or_else(|| format!("Undefined symbol {}", token))
+    }
+
+    /// Rewrites one operand into the plain syntax `Inst::from_str` takes: registers
+    /// are kept, expressions (including memory offsets) are folded to numbers.
+    fn operand(&mut self, op: &str) -> Result<String, String> {
+        if let Ok(reg) = Operand::from_str(op) {
+            if !matches!(reg, Operand::Immediate(_)) {
+                return Ok(op.to_string());
+            }
+        }
+        if let Some((offset, base)) = op.strip_suffix(')').and_then(|o| o.rsplit_once('(')) {
+            if let Ok(Operand::Gpr(_)) = Operand::from_str(base) {
+                let offset = if offset.trim().is_empty() { 0 } else { self.eval(offset)? };
+                return Ok(format!("{}({})", offset, base));
+            }
+        }
+        Ok(self.eval(op)?.to_string())
+    }
+
+    /// The real instructions behind one source line, with pseudo-instructions expanded.
+    fn expand(&mut self, opcode: &str, operands: &[String]) -> Result<Vec<String>, String> {
+        // a load or store would need its offset split again on its own when the auipc moves
+        if opcode != "addi" && operands.iter().any(|op| op.contains("%pcrel_lo")) {
+            return Err(format!("%pcrel_lo is only supported in an addi, not in {}; add the address first and use a zero offset", opcode));
+        }
+        // fence sets are not expressions
+        let operands = if opcode == "fence" {
+            operands.to_vec()
+        } else {
+            operands.iter().map(|op| self.operand(op)).collect::<Result<Vec<_>, _>>()?
+        };
+        Ok(expand_pseudo(opcode, &operands, self.pc as usize)?
+            .unwrap_or_else(|| vec![format!("{} {}", opcode, operands.join(","))]))
+    }
+}
+
+/// Assembles GNU `as` syntax into an instruction trace for `.text` plus an image
+/// holding the data sections and symbols. The plain one-instruction-per-line
+/// format is a subset of this, so text traces go through here as well.
+pub fn parse_asm(src: &str) -> Result<(Vec<Inst>, Elf), Error> {
+    let mut asm = Assembler {
+        symbols: HashMap::new(),
+        locals: Vec::new(),
+        pcrel_hi: HashMap::new(),
+        stmt: 0,
+        pc: 0,
+    };
+    let mut kinds = HashMap::new();
+    let mut sizes = [0usize; 4];
+    let mut aligns = [4usize; 4];
+    let mut stmts: Vec<Stmt> = Vec::new();
+    let mut labels = Vec::new();
+    let mut section = Some(0);
+
+    // first pass: section offsets of every statement, and constants
+    for (i, line) in src.lines().enumerate() {
+        for item in parse_line(line) {
+            let err = |e: String| Error::new(e).at_line(i + 1);
+            let offset = section.map_or(0, |s| sizes[s]);
+            let mut size = 0;
+            match &item {
+                Item::Label(name) => {
+                    if let Some(s) = section {
+                        labels.push((name.clone(), stmts.len(), s, offset));
+                    }
+                }
+                Item::Inst(opcode, operands) => {
+                    // labels are not placed yet, only the li constant can change the expansion
+                    let operands: Vec<String> = operands.iter()
+                        .map(|op| asm.operand(op).unwrap_or_else(|_| String::from("0")))
+                        .collect();
+                    size = 4 * asm.expand(opcode, &operands).map_or(1, |insts| insts.len());
+                }
+                Item::Directive(name, args) => match name.as_str() {
+                    ".text" | ".data" | ".rodata" | ".bss" => section = section_index(name),
+                    ".section" => section = args.first().and_then(|s| section_index(s)),
+                    ".word" | ".4byte" => size = 4 * args.len(),
+                    ".half" | ".short" | ".2byte" => size = 2 * args.len(),
+                    ".byte" => size = args.len(),
+                    ".zero" | ".space" | ".skip" => {
+                        let len = args.first().ok_or_else(|| err(format!("{} needs a size", name)))?;
+                        size = asm.eval(len).map_err(err)? as usize;
+                    }
+                    ".string" | ".asciz" | ".ascii" => {
+                        for arg in args.iter() {
+                            size += parse_string(arg).map_err(err)?.len();
+                            if name != ".ascii" {
+                                size += 1;
+                            }
+                        }
+                    }
+                    ".align" | ".p2align" | ".balign" => {
+                        let arg = args.first().ok_or_else(|| err(format!("{} needs an alignment", name)))?;
+                        let arg = asm.eval(arg).map_err(err)? as usize;
+                        // .align is a power of two on RISC-V, like .p2align
+                        let align = if name == ".balign" { arg.max(1) } else { 1 << arg };
+                        if let Some(s) = section {
+                            aligns[s] = aligns[s].max(align);
+                            size = align_up(offset, align) - offset;
+                        }
+                    }
+                    ".equ" | ".set" => {
+                        let [sym, value] = args.as_slice() else { return Err(err(format!("{} expects a name and a value", name))) };
+                        let value = asm.eval(value).map_err(err)?;
+                        asm.symbols.insert(sym.clone(), value);
+                    }
+                    ".type" => {
+                        if let [sym, kind] = args.as_slice() {
+                            match kind.trim_start_matches(['@', '%']) {
+                                "function" => kinds.insert(sym.clone(), SymbolKind::Func),
+                                "object" => kinds.insert(sym.clone(), SymbolKind::Object),
+                                _ => None,
+                            };
+                        }
+                    }
+                    _ if IGNORED_DIRECTIVES.contains(&name.as_str()) || name.starts_with(".cfi_") => {}
+                    _ => return Err(err(format!("Unsupported directive {}", name))),
+                },
+            }
+            if size > 0 {
+                let Some(s) = section else {
+                    return Err(err(String::from("Contents outside of .text/.rodata/.data/.bss")));
+                };
+                sizes[s] += size;
+            }
+            stmts.push(Stmt { line: i + 1, section, offset, size, item });
+        }
+    }
+
+    asm.pcrel_hi.clear();
+    let mut bases = [0usize; 4];
+    for s in 1..SECTIONS.len() {
+        bases[s] = align_up(bases[s - 1] + sizes[s - 1], aligns[s]);
+    }
+    let mut symbols = Vec::new();
+    for (name, stmt, s, offset) in labels {
+        let addr = bases[s] + offset;
+        if name.chars().all(|c| c.is_ascii_digit()) {
+            asm.locals.push((name, stmt, addr as i64));
+            continue;
+        }
+        if asm.symbols.insert(name.clone(), addr as i64).is_some() {
+            return Err(Error::new(format!("Symbol {} is already defined", name)).at_line(stmts[stmt].line));
+        }
+        let kind = kinds.get(&name).copied().unwrap_or(SymbolKind::Other);
+        symbols.push(Symbol { name, addr, size: 0, kind });
+    }
+    symbols.sort_by_key(|s| s.addr);
+
+    // second pass: evaluate operands and fill in the sections
+    let mut text: Vec<Option<Inst>> = vec![None; sizes[0] / 4];
+    let mut data: Vec<Vec<u8>> = sizes.iter().map(|size| vec![0; *size]).collect();
+    let mut source_lines = HashMap::new();
+    for (i, stmt) in stmts.iter().enumerate() {
+        let err = |e: String| Error::new(e).at_line(stmt.line);
+        let Some(s) = stmt.section else { continue };
+        asm.stmt = i;
+        asm.pc = (bases[s] + stmt.offset) as i64;
+        let mut bytes = Vec::new();
+        match &stmt.item {
+            Item::Inst(opcode, operands) => {
+                if s != 0 {
+                    return Err(err(format!("Instruction {} outside of .text", opcode)));
+                }
+                let lines = asm.expand(opcode, operands).map_err(err)?;
+                if lines.len() * 4 != stmt.size {
+                    return Err(err(format!("{} must be given a constant defined before it is used", opcode)));
+                }
+                for (j, line) in lines.iter().enumerate() {
+                    let addr = asm.pc as usize + j * 4;
+                    let inst = Inst::from_str(line, addr).map_err(err)?;
+                    // fields that don't fit would only fail when the schedule is assembled, far from here
+                    if inst.opcode != Opcode::MOV {
+                        assemble_insn(&inst, addr).map_err(|e| err(format!("{}: {}", line, e)))?;
+                    }
+                    text[stmt.offset / 4 + j] = Some(inst);
+                    source_lines.insert(addr, stmt.line);
+                }
+            }
+            Item::Directive(name, args) => {
+                let width = match name.as_str() {
+                    ".word" | ".4byte" => 4,
+                    ".half" | ".short" | ".2byte" => 2,
+                    ".byte" => 1,
+                    ".string" | ".asciz" | ".ascii" => {
+                        for arg in args.iter() {
+                            bytes.extend(parse_string(arg).map_err(err)?);
+                            if name != ".ascii" {
+                                bytes.push(0);
+                            }
+                        }
+                        0
+                    }
+                    _ => 0,
+                };
+                if width > 0 {
+                    for arg in args.iter() {
+                        let value = asm.eval(arg).map_err(err)?;
+                        bytes.extend_from_slice(&value.to_le_bytes()[..width]);
+                    }
+                }
+            }
+            Item::Label(_) => {}
+        }
+        if bytes.is_empty() {
+            continue;
+        }
+        if s == 0 {
+            // raw words in .text are instructions, e.g. `.word 0x340000ef`
+            if bytes.len() % 4 != 0 || stmt.offset % 4 != 0 {
+                return Err(err(String::from("Only whole, aligned words can be placed in .text")));
+            }
+            for (j, w) in bytes.chunks_exact(4).enumerate() {
+                let addr = stmt.offset + j * 4;
+                let word = u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+                text[addr / 4] = Some(decode_insn(word, addr).map_err(err)?);
+                source_lines.insert(addr, stmt.line);
+            }
+        } else if s == 3 {
+            return Err(err(String::from("Initialized data in .bss")));
+        } else {
+            data[s][stmt.offset..stmt.offset + bytes.len()].copy_from_slice(&bytes);
+        }
+    }
+
+    // alignment padding in .text executes as nops
+    let trace = text.into_iter().enumerate()
+        .map(|(i, inst)| inst.map_or_else(|| decode_insn(0x00000013, i * 4), Ok))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sections = (1..SECTIONS.len())
+        .filter(|s| sizes[*s] > 0)
+        .map(|s| Section {
+            name: String::from(SECTIONS[s]),
+            addr: bases[s],
+            size: sizes[s],
+            data: if s == 3 { Vec::new() } else { data[s].clone() },
+        })
+        .collect();
+    Ok((trace, Elf { entry: 0, sections, symbols, lines: source_lines }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_asm;
+    use crate::assembler::assemble_insn;
+    use crate::isa::{Label, Opcode, Operand};
+
+    #[test]
+    fn test_plain_trace() {
+        let (trace, image) = parse_asm(".word 0x340000ef\naddi\tt6,t6,-1\nsb\tt6,-1(t6)\n\
+                                        jal\tt6,0x55d68 \nbeq t6,t6,0b101010101010\n").unwrap();
+        assert_eq!(trace.len(), 5);
+        assert_eq!(trace[0].opcode, Opcode::JAL);
+        assert_eq!(assemble_insn(&trace[1], 4), Ok(0xffff8f93));
+        assert_eq!(assemble_insn(&trace[2], 8), Ok(0xffff8fa3));
+        assert!(matches!(trace[3].dest, Operand::Gpr(31)));
+        assert!(matches!(trace[4].label, Label::SrcAddrSpace(0xaaa)));
+        assert!(image.sections.is_empty());
+    }
+
+    #[test]
+    fn test_labels_and_relocations() {
+        let src = "
+            .text
+            .globl _start
+        _start:
+            lui   a0, %hi(msg)     # comment
+            addi  a0, a0, %lo(msg)
+        1:  auipc a1, %pcrel_hi(table)
+            addi  a2, a1, %pcrel_lo(1b)
+            beqz_: bne a2, zero, 1f
+            j     1b
+        1:  ret
+            .section .rodata
+        msg: .string \"hi\"
+            .data
+            .align 2
+        table: .word msg, _start + 4
+            .bss
+        buf: .zero 16
+        ";
+        let (trace, image) = parse_asm(src).unwrap();
+        assert_eq!(trace.len(), 7);
+        let rodata = image.section(".rodata").unwrap();
+        let data = image.section(".data").unwrap();
+        assert_eq!((rodata.addr, rodata.data.as_slice()), (0x1c, &b"hi\0"[..]));
+        assert_eq!(data.addr, 0x20);
+        assert_eq!(data.data, [0x1c, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(image.section(".bss").unwrap().addr, 0x28);
+        // lui + addi rebuild msg, auipc + addi address table relative to the auipc at 8
+        assert!(matches!(trace[0].src2, Operand::Immediate(0)));
+        assert!(matches!(trace[1].src2, Operand::Immediate(0x1c)));
+        assert!(matches!(trace[3].src2, Operand::Immediate(0x18)));
+        assert!(matches!(trace[4].label, Label::SrcAddrSpace(0x18)));
+        assert!(matches!(trace[5].label, Label::SrcAddrSpace(0x8)));
+        let names: Vec<&str> = image.symbols.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["_start", "beqz_", "msg", "table", "buf"]);
+        // source lines by address, for diagnostics
+        assert_eq!((image.lines[&0x0], image.lines[&0x18]), (5, 11));
+    }
+
+    #[test]
+    fn test_pseudo_expansion() {
+        let src = "
+            .equ BIG, 0x12345fff
+        _start:
+            li    a0, BIG
+            li    a1, -5
+            li    a2, 0x10000
+            la    a3, value
+            mv    a4, a0
+            bgt   a0, a1, 1f
+            call  _start
+        1:  ret
+            .data
+        value: .word 1
+        ";
+        let (trace, _) = parse_asm(src).unwrap();
+        let listing: Vec<String> = trace.iter().map(|i| format!("{}", i)).collect();
+        assert_eq!(listing, [
+            "lui x10, 74566",
+            "addi x10, x10, -1",
+            "addi x11, x0, -5",
+            "lui x12, 16",
+            "auipc x13, 0",
+            "addi x13, x13, 24",
+            "addi x14, x10, 0",
+            // branch targets print in hex
+            "blt x11, x10, 0x24",
+            "jal 0x0",
+            "jalr x0, 0(x1)",
+        ]);
+        assert!(parse_asm("li a0, later\n.equ later, 0x12345\n").is_err());
+        assert_eq!(parse_asm("nop\nlw a0, 4\n").unwrap_err().line, Some(2));
+        assert!(parse_asm("lui LC, 5\n").is_err());
+        assert!(parse_asm("add x32, x1, x2\n").is_err());
+    }
+
+    #[test]
+    fn test_rejected_operands() {
+        let line = |src: &str| parse_asm(src).unwrap_err().line;
+        assert_eq!(line("nop\naddi a0, a0, 5000\n"), Some(2));
+        assert_eq!(line("slli a0, a0, 40\n"), Some(1));
+        assert_eq!(line("sw a0, -2049(sp)\n"), Some(1));
+        assert_eq!(line("lui a0, 0x100000\n"), Some(1));
+        assert!(parse_asm("addi a0, a0, -2048\nslli a0, a0, 31\nlui a0, 0xfffff\n").is_ok());
+
+        // label_auipc only takes the addi right after the auipc
+        let err = parse_asm("1: auipc a0, %pcrel_hi(x)\nlw a1, %pcrel_lo(1b)(a0)\n.data\nx: .word 0\n").unwrap_err();
+        assert!(err.msg.contains("only supported in an addi") && err.line == Some(2), "{}", err);
+        assert!(parse_asm("1: auipc a0, %pcrel_hi(x)\nnop\naddi a0, a0, %pcrel_lo(1b)\n.data\nx: .word 0\n").is_err());
+
+        // the section directive of sw/tests/init.S
+        let (trace, _) = parse_asm(".section \".text.init\"\n_start: li x1, 0\n").unwrap();
+        assert_eq!(trace.len(), 1);
+    }
+}
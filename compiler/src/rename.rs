@@ -0,0 +1,312 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::analysis::{dep_analysis, liveness, reg_reads, reg_write, AnalyzedProgram, Cfg, Edge};
+use crate::isa::{Inst, Operand};
+
+/// Registers a live range may move to, most preferred first: temporaries, arguments,
+/// saved registers and ra. sp, gp and tp are never touched.
+pub const POOL: [u32; 28] = [
+    5, 6, 7, 28, 29, 30, 31,
+    17, 16, 15, 14, 13, 12, 11, 10,
+    8, 9, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    1,
+];
+
+fn regs(mask: u32) -> impl Iterator<Item = u32> {
+    (1..32).filter(move |r| mask & (1 << r) != 0)
+}
+
+fn reg_mask(regs: impl IntoIterator<Item = u32>) -> u32 {
+    regs.into_iter().fold(0, |mask, r| mask | (1 << r))
+}
+
+pub fn rename_reads(inst: &mut Inst, from: u32, to: u32) {
+    if inst.src1 == Some(from) {
+        inst.src1 = Some(to);
+    }
+    if matches!(inst.src2, Operand::Gpr(r) if r == from) {
+        inst.src2 = Operand::Gpr(to);
+    }
+}
+
+struct UnionFind(Vec<usize>);
+
+impl UnionFind {
+    fn find(&mut self, x: usize) -> usize {
+        let parent = self.0[x];
+        if parent == x {
+            return x;
+        }
+        let root = self.find(parent);
+        self.0[x] = root;
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.0[a] = b;
+    }
+}
+
+/// Renames live ranges that share a register with another live range in the same block, so
+/// the false dependences between them go away. A live range (web) is a set of defs and the
+/// uses they reach, across blocks; it moves as a whole to a register that is dead wherever
+/// the web is live and unused in the blocks it spans. Webs reaching code we can't see (the
+/// program entry, calls, returns, code pointer targets) keep their register.
+/// Returns the renamed program and how many webs moved.
+pub fn rename_registers(prog: AnalyzedProgram) -> (AnalyzedProgram, usize) {
+    let cfg = Cfg::new(&prog);
+    let live = liveness(&prog, &cfg);
+    let mut live_in = live.entry;
+    let orig: Vec<Vec<Inst>> = prog.bbs.iter()
+        .map(|bb| bb.insns.iter().chain(bb.cf_insn.as_ref()).map(|d| d.inst).collect())
+        .collect();
+    let n = orig.len();
+
+    // union-find nodes: every def, then the value of each register on entry to each block,
+    // then each register as defined outside the code we see
+    let mut def_id = HashMap::new();
+    for (b, insts) in orig.iter().enumerate() {
+        for (i, inst) in insts.iter().enumerate() {
+            if reg_write(inst).is_some() {
+                def_id.insert((b, i), def_id.len());
+            }
+        }
+    }
+    let n_defs = def_id.len();
+    let phi = |b: usize, r: u32| n_defs + b * 32 + r as usize;
+    let outside = |r: u32| n_defs + n * 32 + r as usize;
+    // node of the value of r that instruction i of block b reads
+    let reaching = |b: usize, i: usize, r: u32| (0..i).rev()
+        .find(|j| reg_write(&orig[b][*j]) == Some(r))
+        .map_or(phi(b, r), |j| def_id[&(b, j)]);
+    let mut webs = UnionFind((0..outside(32)).collect());
+    for b in 0..n {
+        for r in regs(live_in[b]) {
+            if cfg.external[b] {
+                webs.union(phi(b, r), outside(r));
+            }
+            for (p, edge) in cfg.preds[b].iter() {
+                webs.union(phi(b, r), reaching(*p, orig[*p].len(), r));
+                // whatever lives through a call is the callee's to save and restore
+                if *edge == Edge::Return || cfg.exits[*p].escape.is_some() {
+                    webs.union(phi(b, r), outside(r));
+                }
+            }
+        }
+        for (i, inst) in orig[b].iter().enumerate() {
+            // link registers of calls stay where they are
+            if let Some(r) = reg_write(inst).filter(|_| inst.opcode.is_control_flow()) {
+                webs.union(def_id[&(b, i)], outside(r));
+            }
+        }
+        for r in regs(cfg.exits[b].escape.unwrap_or(0)) {
+            webs.union(reaching(b, orig[b].len(), r), outside(r));
+        }
+    }
+
+    let mut live_after: Vec<Vec<u32>> = (0..n).map(|b| {
+        let mut live = live.exit[b];
+        let mut after = vec![0; orig[b].len()];
+        for (i, inst) in orig[b].iter().enumerate().rev() {
+            after[i] = live;
+            live = (live & !reg_mask(reg_write(inst))) | reg_mask(reg_reads(inst));
+        }
+        after
+    }).collect();
+
+    let mut code = orig.clone();
+    let mut tried = HashSet::new();
+    let mut renamed = 0;
+    for b in 0..n {
+        for i in 0..orig[b].len() {
+            let Some(r) = reg_write(&orig[b][i]) else { continue };
+            let web = webs.find(def_id[&(b, i)]);
+            if !tried.insert(web) || webs.find(outside(r)) == web {
+                continue;
+            }
+            // only worth it when another web of r comes earlier in the block
+            let clash = (0..i).any(|j| {
+                let inst = &code[b][j];
+                (reg_write(inst) == Some(r) && webs.find(def_id[&(b, j)]) != web)
+                    || (reg_reads(inst).contains(&r) && webs.find(reaching(b, j, r)) != web)
+            });
+            if !clash {
+                continue;
+            }
+            // where the web is live: after these instructions, and on entry to these blocks
+            let points: Vec<(usize, usize)> = (0..n)
+                .flat_map(|pb| (0..orig[pb].len()).map(move |pi| (pb, pi)))
+                .filter(|(pb, pi)| live_after[*pb][*pi] & (1 << r) != 0 && webs.find(reaching(*pb, pi + 1, r)) == web)
+                .collect();
+            let entries: Vec<usize> = (0..n)
+                .filter(|eb| live_in[*eb] & (1 << r) != 0 && webs.find(phi(*eb, r)) == web)
+                .collect();
+            // a def writes its register even when nothing reads it
+            let defs: Vec<(usize, usize)> = def_id.keys().copied()
+                .filter(|(db, di)| reg_write(&orig[*db][*di]) == Some(r) && webs.find(def_id[&(*db, *di)]) == web)
+                .collect();
+            let mut spanned: Vec<usize> = points.iter().chain(defs.iter()).map(|(pb, _)| *pb).chain(entries.iter().copied()).collect();
+            spanned.sort();
+            spanned.dedup();
+            let free = |s: u32| points.iter().chain(defs.iter()).all(|(pb, pi)| live_after[*pb][*pi] & (1 << s) == 0)
+                && entries.iter().all(|eb| live_in[*eb] & (1 << s) == 0)
+                && spanned.iter().all(|sb| code[*sb].iter().all(|inst| reg_write(inst) != Some(s) && !reg_reads(inst).contains(&s)));
+            let Some(s) = POOL.iter().copied().find(|s| *s != r && free(*s)) else { continue };
+
+            let moved = (1 << r) | (1 << s);
+            for (pb, pi) in points.iter() {
+                live_after[*pb][*pi] ^= moved;
+            }
+            for eb in entries.iter() {
+                live_in[*eb] ^= moved;
+            }
+            for (db, insts) in orig.iter().enumerate() {
+                for (di, inst) in insts.iter().enumerate() {
+                    if reg_write(inst) == Some(r) && webs.find(def_id[&(db, di)]) == web {
+                        code[db][di].dest = Operand::Gpr(s);
+                    }
+                    if reg_reads(inst).contains(&r) && webs.find(reaching(db, di, r)) == web {
+                        rename_reads(&mut code[db][di], r, s);
+                    }
+                }
+            }
+            renamed += 1;
+        }
+    }
+
+    let renamed_prog = AnalyzedProgram {
+        bbs: code.into_iter().map(dep_analysis).collect(),
+        indirect_targets: prog.indirect_targets,
+        branch_weights: prog.branch_weights,
+    };
+    (renamed_prog, renamed)
+}
+
+/// Number of WAR/WAW dependences left for the scheduler to respect.
+pub fn false_deps(prog: &AnalyzedProgram) -> usize {
+    prog.bbs.iter()
+        .flat_map(|bb| bb.insns.iter().chain(bb.cf_insn.as_ref()))
+        .map(|d| d.false_deps.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{false_deps, rename_registers};
+    use crate::analysis::AnalyzedProgram;
+    use crate::isa::Operand;
+    use crate::machine::MachineModel;
+    use crate::scheduling::{schedule_program, ScheduledProgram, Scheduler};
+    use crate::testutil::{analyze, assert_same};
+
+    fn dests(prog: &AnalyzedProgram) -> Vec<u32> {
+        prog.bbs.iter()
+            .flat_map(|bb| bb.insns.iter().chain(bb.cf_insn.as_ref()))
+            .filter_map(|d| match d.inst.dest { Operand::Gpr(r) if r != 0 => Some(r), _ => None })
+            .collect()
+    }
+
+    fn list(prog: AnalyzedProgram) -> ScheduledProgram {
+        schedule_program(prog, &MachineModel::default(), Scheduler::List)
+    }
+
+    #[test]
+    fn test_rename_straight_line() {
+        let src = "
+                lw a5, 0(a0)
+                add a1, a1, a5
+                lw a5, 4(a0)
+                add a2, a2, a5
+                sw a2, 8(a0)
+                ret
+        ";
+        let prog = analyze(src);
+        let (renamed, count) = rename_registers(prog.clone());
+        assert_eq!(count, 1);
+        // the second load moves to the first temporary
+        assert_eq!(dests(&renamed), vec![15, 11, 5, 12]);
+        assert!(false_deps(&renamed) < false_deps(&prog));
+        let model = MachineModel::default();
+        let bundles = |p: &AnalyzedProgram| schedule_program(p.clone(), &model, Scheduler::List).schedule.len();
+        assert!(bundles(&renamed) < bundles(&prog));
+
+        let mut regs = [0; 32];
+        regs[10] = 0x100;
+        regs[11] = 3;
+        regs[12] = 4;
+        let mem = HashMap::from([(0x100, 10), (0x104, 20)]);
+        assert_same(src, &list(renamed), regs, &mem);
+    }
+
+    #[test]
+    fn test_rename_across_blocks() {
+        let src = "
+                lw a5, 0(a0)
+                add a1, a1, a5
+                li a5, 2
+                beq a1, x0, 1f
+                add a1, a1, a5
+                ret
+            1:  sub a1, a1, a5
+                ret
+        ";
+        let prog = analyze(src);
+        let (renamed, count) = rename_registers(prog.clone());
+        assert_eq!(count, 1);
+        // the li and both of its uses, in either successor, move together
+        let renamed_reads = renamed.bbs.iter()
+            .flat_map(|bb| bb.insns.iter())
+            .filter(|d| matches!(d.inst.src2, Operand::Gpr(5)))
+            .count();
+        assert_eq!(renamed_reads, 2);
+
+        for a1 in [0, 1, 0xffff_fffe] {
+            let mut regs = [0; 32];
+            regs[10] = 0x100;
+            regs[11] = a1;
+            let mem = HashMap::from([(0x100, 2)]);
+            assert_same(src, &list(renamed.clone()), regs, &mem);
+        }
+    }
+
+    #[test]
+    fn test_rename_dead_def() {
+        // nothing reads the second load, but it must not land on t0, which is still live
+        let src = "
+                li t0, 7
+                lw a5, 0(a0)
+                add a1, a1, a5
+                lw a5, 4(a0)
+                add a1, a1, t0
+                ret
+        ";
+        let prog = analyze(src);
+        let (renamed, count) = rename_registers(prog.clone());
+        assert_eq!(count, 1);
+        assert_eq!(dests(&renamed), vec![5, 15, 11, 6, 11]);
+
+        let mut regs = [0; 32];
+        regs[10] = 0x100;
+        regs[11] = 3;
+        let mem = HashMap::from([(0x100, 10), (0x104, 20)]);
+        assert_same(src, &list(renamed), regs, &mem);
+    }
+
+    #[test]
+    fn test_keep_escaping_registers() {
+        // both values of a0 are seen outside: the argument and the return value
+        let src = "
+                add a1, a1, a0
+                li a0, 1
+                sw a1, 0(sp)
+                ret
+        ";
+        let prog = analyze(src);
+        let (renamed, count) = rename_registers(prog.clone());
+        assert_eq!(count, 0);
+        assert_eq!(dests(&renamed), dests(&prog));
+    }
+}
@@ -1,56 +1,29 @@
 use std::collections::HashMap;
 use std::fmt;
-use std::usize::MAX;
 
-use serde::ser::{Serialize, Serializer, SerializeSeq};
-
-use crate::analysis::{AnalyzedBasicBlock, AnalyzedProgram, DepInst};
-use crate::isa::{ExecutionUnit, Inst};
+use crate::analysis::{AnalyzedProgram, DepInst};
+use crate::isa::Label;
+use crate::machine::MachineModel;
+use crate::modulo::{pipeline_loop, PipelinedLoop};
+use crate::superblock::{schedule_superblocks, Trace};
 
 #[derive(Debug, Clone)]
 pub struct Bundle {
     pub addr: usize,
-    pub alu0: Option<DepInst>,
-    pub alu1: Option<DepInst>,
-    pub mem: Option<DepInst>,
-    pub branch: Option<DepInst>,
+    /// One entry per slot of the machine model, in `MachineModel::slots` order
+    pub slots: Vec<Option<DepInst>>,
 }
 
 impl Bundle {
-    fn new(addr: usize) -> Self {
+    pub fn new(addr: usize, width: usize) -> Self {
         Bundle {
-            addr: addr,
-            alu0: None,
-            alu1: None,
-            mem: None,
-            branch: None,
+            addr,
+            slots: vec![None; width],
         } 
     }
 
-    pub fn valid_insts_mut<'a>(&'a mut self) -> Vec<&'a mut DepInst> {
-        let mut insts = Vec::new();
-        if let Some(inst) = &mut self.mem {
-            insts.push(inst);
-        }
-        if let Some(inst) = &mut self.branch {
-            insts.push(inst);
-        }
-        if let Some(inst) = &mut self.alu0 {
-            insts.push(inst);
-        }
-        if let Some(inst) = &mut self.alu1 {
-            insts.push(inst);
-        }
-        insts
-    }
-
-    pub fn insts<'a>(&'a self) -> Vec<&'a Option<DepInst>> {
-        let mut insts = Vec::new();
-        insts.push(&self.mem);
-        insts.push(&self.branch);
-        insts.push(&self.alu0);
-        insts.push(&self.alu1);
-        insts
+    pub fn valid_insts_mut(&mut self) -> Vec<&mut DepInst> {
+        self.slots.iter_mut().flatten().collect()
     }
 }
 
@@ -67,35 +40,42 @@ impl fmt::Display for Bundle {
             }
         }
         write!(f, "{:<4x} | ", self.addr)?;
-        fmt_inst(f, &self.alu0)?;
-        fmt_inst(f, &self.alu1)?;
-        fmt_inst(f, &self.mem)?;
-        fmt_inst(f, &self.branch)?;
-        write!(f, "\n")
+        for inst in self.slots.iter() {
+            fmt_inst(f, inst)?;
+        }
+        writeln!(f)
     }
 }
 
 
 
+#[derive(Clone)]
 pub struct ScheduledProgram {
+    pub model: MachineModel,
     pub schedule: Vec<Bundle>,
     pub bb_starts: Vec<usize>,
+    /// Bundle of each instruction, by source address
     pub starts: HashMap<usize, usize>,
+    /// First bundle of each block, by the source address of its first instruction
+    pub entries: HashMap<usize, usize>,
+    pub pipelined: Vec<PipelinedLoop>,
+    pub traces: Vec<Trace>,
 }
 
 impl ScheduledProgram {
+    /// Word address of the data section: past the header bundle and the schedule, 64 byte aligned
     pub fn aligned_end(&self) -> i32 {
-        ((self.schedule.len()*16 + 16)/4 + 15) as i32 & (-16)
+        (((self.schedule.len() + 1)*self.model.bundle_bytes())/4 + 15) as i32 & (-16)
     }
 }
 
 impl fmt::Display for ScheduledProgram {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut j = 0;
-        write!(f, "Data start: {}\n", self.aligned_end())?;
-        for (i,bundle) in (&self.schedule).iter().enumerate() {
+        writeln!(f, "Data start: {}", self.aligned_end())?;
+        for (i,bundle) in self.schedule.iter().enumerate() {
             if j < self.bb_starts.len() && i == *self.bb_starts.get(j).unwrap() {
-                write!(f, "BasicBlock {}:\n", j)?;
+                writeln!(f, "BasicBlock {}:", j)?;
                 j += 1;
             }
             write!(f, "{}", bundle)?;
@@ -104,97 +84,240 @@ impl fmt::Display for ScheduledProgram {
     }
 }
 
-fn compatible(slot: &Bundle, inst: &DepInst) -> bool {
-    match inst.inst.opcode.eu_type() {
-        ExecutionUnit::ALU => slot.alu0.is_none() || slot.alu1.is_none(),
-        ExecutionUnit::Mult => false,
-        ExecutionUnit::Mem => slot.mem.is_none(),
-        ExecutionUnit::Branch => slot.branch.is_none(),
-    }
+/// First free slot of `bundle` that can issue `inst`
+pub fn compatible(model: &MachineModel, bundle: &Bundle, inst: &DepInst) -> Option<usize> {
+    let eu = inst.inst.opcode.eu_type();
+    (0..bundle.slots.len()).find(|i| bundle.slots[*i].is_none() && model.accepts(*i, eu))
 }
 
-fn schedule_single(slot: &mut Bundle, inst: DepInst) {
-    match inst.inst.opcode.eu_type() {
-        ExecutionUnit::ALU => {
-            if slot.alu0.is_none() {
-                slot.alu0 = Some(inst);
-            } else {
-                slot.alu1 = Some(inst);
-            }
-        }
-        ExecutionUnit::Mult => {}
-        ExecutionUnit::Mem => {
-            slot.mem = Some(inst);
-        }
-        ExecutionUnit::Branch => {
-            slot.branch = Some(inst);
-        }
-    }
-}
-
-fn fill_schedule(cyc_end: usize, schedule: &mut Vec<Bundle>) {
+pub fn fill_schedule(model: &MachineModel, cyc_end: usize, schedule: &mut Vec<Bundle>) {
     while schedule.len() <= cyc_end {
-        schedule.push(Bundle::new(schedule.len()));
+        schedule.push(Bundle::new(schedule.len(), model.width()));
     }
 }
 
-fn min_cycle(starts: &HashMap<usize, usize>, inst: &DepInst, base: usize) -> usize {
+/// `ready` holds the first cycle at which each scheduled instruction's result can be used.
+fn min_cycle(ready: &HashMap<usize, usize>, inst: &DepInst, base: usize) -> usize {
     std::cmp::max(base, inst.all_deps().iter().map(|d| 
-        starts.get(&d.addr)
-        // unit latency assumed on all units, since we have an interlocking pipeline
-        .and_then(|s| Some(*s + 1))
+        ready.get(&d.addr)
+        .copied()
         .unwrap_or(base))
     .max().unwrap_or(base))
 }
 
 fn asap_local(
+    model: &MachineModel,
     starts: &mut HashMap<usize, usize>,
+    ready: &mut HashMap<usize, usize>,
     base: usize,
     inst: DepInst,
     schedule: &mut Vec<Bundle>,
 ) {
-    let min_cycle = min_cycle(starts, &inst, base);
-    fill_schedule(min_cycle, schedule);
-    for slot in schedule.iter_mut().skip(min_cycle) {
-        if compatible(slot, &inst) {
-            let addr = inst.inst.addr;
-            schedule_single(slot, inst);
-            starts.insert(addr, slot.addr);
-            return;
+    let min_cycle = min_cycle(ready, &inst, base);
+    let addr = inst.inst.addr;
+    let latency = model.latency(inst.inst.opcode);
+    fill_schedule(model, min_cycle, schedule);
+    let (cycle, slot) = match schedule.iter().enumerate().skip(min_cycle)
+        .find_map(|(c, bundle)| compatible(model, bundle, &inst).map(|s| (c, s))) {
+        Some(found) => found,
+        None => {
+            // unable to find a compatible slot; add a new one to the end
+            schedule.push(Bundle::new(schedule.len(), model.width()));
+            let slot = compatible(model, schedule.last().unwrap(), &inst).unwrap();
+            (schedule.len() - 1, slot)
+        }
+    };
+    schedule[cycle].slots[slot] = Some(inst);
+    starts.insert(addr, cycle);
+    ready.insert(addr, cycle + latency);
+}
+
+/// Critical-path list scheduling of the non-control-flow instructions of one block.
+/// Each cycle, the ready instructions with the longest latency-weighted path to the
+/// end of the block (including `cf_insn`) get the free slots first.
+fn list_local(
+    model: &MachineModel,
+    starts: &mut HashMap<usize, usize>,
+    ready: &mut HashMap<usize, usize>,
+    base: usize,
+    insns: Vec<DepInst>,
+    cf_insn: Option<&DepInst>,
+    schedule: &mut Vec<Bundle>,
+) {
+    let index: HashMap<usize, usize> = insns.iter().enumerate().map(|(i, inst)| (inst.inst.addr, i)).collect();
+    let preds: Vec<Vec<usize>> = insns.iter().chain(cf_insn)
+        .map(|inst| inst.all_deps().iter().filter_map(|d| index.get(&d.addr).copied()).collect())
+        .collect();
+    // deps always point backwards, so a reverse walk sees every successor before its preds
+    let mut height = vec![0; preds.len()];
+    for i in (0..preds.len()).rev() {
+        if i < insns.len() {
+            height[i] += model.latency(insns[i].inst.opcode);
+        }
+        for p in preds[i].iter() {
+            height[*p] = std::cmp::max(height[*p], height[i]);
         }
     }
-    // unable to find a compatible slot; add a new one to the end
-    let mut bundle = Bundle::new(schedule.len());
-    let addr = inst.inst.addr;
-    schedule_single(&mut bundle, inst);
-    starts.insert(addr, bundle.addr);
-    schedule.push(bundle);
+
+    let mut insns: Vec<Option<DepInst>> = insns.into_iter().map(Some).collect();
+    let mut remaining = insns.len();
+    let mut cycle = base;
+    while remaining > 0 {
+        fill_schedule(model, cycle, schedule);
+        let mut cands: Vec<usize> = (0..insns.len())
+            .filter(|i| insns[*i].as_ref().is_some_and(|inst| {
+                preds[*i].iter().all(|p| insns[*p].is_none()) && min_cycle(ready, inst, base) <= cycle
+            }))
+            .collect();
+        cands.sort_by_key(|i| (std::cmp::Reverse(height[*i]), *i));
+        for i in cands {
+            let Some(slot) = compatible(model, &schedule[cycle], insns[i].as_ref().unwrap()) else { continue };
+            let inst = insns[i].take().unwrap();
+            starts.insert(inst.inst.addr, cycle);
+            ready.insert(inst.inst.addr, cycle + model.latency(inst.inst.opcode));
+            schedule[cycle].slots[slot] = Some(inst);
+            remaining -= 1;
+        }
+        cycle += 1;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Scheduler {
+    /// Program order, each instruction in the first free slot after its deps
+    Asap,
+    /// Critical-path priority list scheduling
+    List,
+    /// List scheduling, with single-block counted loops software pipelined
+    Modulo,
+    /// Traces of blocks scheduled as superblocks, single-block loops pipelined
+    Superblock,
 }
 
-pub fn schedule_program(prog: AnalyzedProgram) -> ScheduledProgram {
+pub fn schedule_program(prog: AnalyzedProgram, model: &MachineModel, scheduler: Scheduler) -> ScheduledProgram {
+    if scheduler == Scheduler::Superblock {
+        return schedule_superblocks(prog, model);
+    }
     let mut sp = ScheduledProgram {
+        model: model.clone(),
         starts: HashMap::new(),
+        entries: HashMap::new(),
         schedule: Vec::new(),
-        bb_starts: Vec::new()
+        bb_starts: Vec::new(),
+        pipelined: Vec::new(),
+        traces: Vec::new(),
     };
 
+    let mut ready = HashMap::new();
     let mut base = 0;
-    for (i,bb) in prog.bbs.into_iter().enumerate() {
+    let bb_addrs: Vec<usize> = prog.bbs.iter().map(|bb| bb.addr()).collect();
+    for (i, bb) in prog.bbs.into_iter().enumerate() {
         sp.bb_starts.push(base);
-        for inst in bb.insns {
-            asap_local(&mut sp.starts, base, inst, &mut sp.schedule);
+        let bb_addr = bb.insns.first().or(bb.cf_insn.as_ref()).map(|i| i.inst.addr);
+        // a pipelined loop exits with a jump to the next block, so there has to be one
+        let pipelined = match scheduler {
+            Scheduler::Modulo if i + 1 < bb_addrs.len() => {
+                let single = AnalyzedProgram { bbs: vec![bb.clone()], ..Default::default() };
+                let flat_len = schedule_program(single, model, Scheduler::List).schedule.len();
+                pipeline_loop(model, &bb, bb_addrs[i + 1], flat_len, &mut sp.schedule)
+            }
+            _ => None,
+        };
+        // the original block, or the fallback of a pipelined loop
+        let body = sp.schedule.len();
+        match scheduler {
+            Scheduler::Asap => for inst in bb.insns {
+                asap_local(model, &mut sp.starts, &mut ready, body, inst, &mut sp.schedule);
+            },
+            Scheduler::List | Scheduler::Modulo | Scheduler::Superblock => 
+                list_local(model, &mut sp.starts, &mut ready, body, bb.insns, bb.cf_insn.as_ref(), &mut sp.schedule),
         }
         // need to have at least base number of instructions in the schedule
         // most of the time will have more, and the branch slots will be empty, so it is ok
-        fill_schedule(base, &mut sp.schedule);
-        if let Some(cf_insn) = bb.cf_insn {
-            let branch_start = min_cycle(&sp.starts, &cf_insn, base);
-            fill_schedule(branch_start, &mut sp.schedule);
-            sp.starts.insert(cf_insn.inst.addr, base);
-            sp.schedule.last_mut().unwrap().branch = Some(cf_insn);
+        fill_schedule(model, body, &mut sp.schedule);
+        if let Some(mut cf_insn) = bb.cf_insn {
+            if pipelined.is_some() {
+                cf_insn.inst.label = Label::DstAddrSpace(body * model.bundle_bytes());
+            }
+            let branch_start = min_cycle(&ready, &cf_insn, body);
+            fill_schedule(model, branch_start, &mut sp.schedule);
+            // the branch ends the block, so it goes in the last bundle unless its slot is taken
+            let slot = match compatible(model, sp.schedule.last().unwrap(), &cf_insn) {
+                Some(slot) => slot,
+                None => {
+                    fill_schedule(model, sp.schedule.len(), &mut sp.schedule);
+                    compatible(model, sp.schedule.last().unwrap(), &cf_insn).unwrap()
+                }
+            };
+            sp.starts.insert(cf_insn.inst.addr, sp.schedule.len() - 1);
+            sp.schedule.last_mut().unwrap().slots[slot] = Some(cf_insn);
+        }
+        // jumps to the block land on its first bundle, wherever its first instruction went
+        if let Some(addr) = bb_addr {
+            sp.entries.insert(addr, base);
         }
+        sp.pipelined.extend(pipelined);
         base = sp.schedule.len();
     }
     
     sp
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::{schedule_program, Scheduler};
+    use crate::machine::MachineModel;
+    use crate::testutil::analyze;
+
+    fn schedule_asm(src: &str, model: &MachineModel, scheduler: Scheduler) -> super::ScheduledProgram {
+        schedule_program(analyze(src), model, scheduler)
+    }
+
+    #[test]
+    fn test_mult_latency() {
+        let sp = schedule_asm("mul a0, a1, a2\naddi a3, a1, 1\naddi a0, a0, 1\nmulhu a4, a1, a2\n",
+            &MachineModel::default(), Scheduler::Asap);
+        // independent work shares the first bundle, the consumer waits out the multiply
+        assert_eq!(sp.starts[&0x0], 0);
+        assert_eq!(sp.starts[&0x4], 0);
+        assert_eq!(sp.starts[&0xc], 1);
+        assert_eq!(sp.starts[&0x8], 3);
+    }
+
+    #[test]
+    fn test_list_critical_path() {
+        // the multiply chain comes last in program order but bounds the block
+        let src = "addi a0, a1, 1\naddi a2, a1, 2\naddi a3, a1, 3\naddi a4, a1, 4\n\
+                   mul a5, a6, a6\nmul a5, a5, a5\n";
+        let asap = schedule_asm(src, &MachineModel::default(), Scheduler::Asap);
+        let list = schedule_asm(src, &MachineModel::default(), Scheduler::List);
+        assert_eq!(asap.schedule.len(), 6);
+        assert_eq!(list.schedule.len(), 4);
+        assert_eq!(list.starts[&0x10], 0);
+        assert_eq!(list.starts[&0x14], 3);
+    }
+
+    #[test]
+    fn test_block_entry() {
+        let src = "addi t0, a1, 1\nmul a2, a3, a3\nmul a4, a2, a2\nmul a6, a7, a7\nmul a6, a6, a6\n";
+        let sp = schedule_asm(src, &MachineModel::default(), Scheduler::List);
+        // the first instruction loses its slot to the multiplies, the block still starts at 0
+        assert_eq!(sp.starts[&0x0], 1);
+        assert_eq!(sp.entries[&0x0], 0);
+    }
+
+    #[test]
+    fn test_three_alus() {
+        let src = "addi a0, a1, 1\naddi a2, a1, 2\naddi a3, a1, 3\nlw a4, 0(sp)\nlw a5, 4(sp)\n";
+        let sp = schedule_asm(src, &MachineModel::default(), Scheduler::List);
+        assert_eq!(sp.starts[&0x8], 1);
+        assert_eq!(sp.starts[&0x10], 1);
+
+        // three ALUs and two memory slots take all five in one bundle
+        let model = MachineModel::from_json(include_str!("../machines/vliw6_dualmem.json")).unwrap();
+        let sp = schedule_asm(src, &model, Scheduler::List);
+        assert_eq!(sp.schedule.len(), 1);
+        assert_eq!(sp.schedule[0].slots.iter().flatten().count(), 5);
+        // 2 bundles of 24 bytes, rounded up to 64 bytes
+        assert_eq!(sp.aligned_end(), 16);
+    }
+}
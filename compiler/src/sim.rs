@@ -0,0 +1,527 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+
+use crate::decoder::{imm_b, imm_i, imm_j, imm_s};
+use crate::disassembler::read_hex_words;
+use crate::machine::MachineModel;
+
+// the mkFIFOs between pipeline stages hold two bundles
+const FIFO_DEPTH: usize = 2;
+const PUTCHAR_ADDR: u32 = 0xf000_fff0;
+const EXIT_ADDR: u32 = 0xf000_fff8;
+
+pub fn rd(word: u32) -> usize {
+    (word >> 7 & 31) as usize
+}
+
+pub fn rs1(word: u32) -> usize {
+    (word >> 15 & 31) as usize
+}
+
+pub fn rs2(word: u32) -> usize {
+    (word >> 20 & 31) as usize
+}
+
+fn funct3(word: u32) -> u32 {
+    word >> 12 & 7
+}
+
+// usesRD, usesRS1 and usesRS2 from RVUtil.bsv, by opcode[6:2]
+pub fn uses_rd(word: u32) -> bool {
+    matches!(word >> 2 & 31, 0b01101 | 0b11011 | 0b00000 | 0b01100 | 0b11001 | 0b00100 | 0b00101)
+}
+
+fn uses_rs1(word: u32) -> bool {
+    matches!(word >> 2 & 31, 0b11000 | 0b00000 | 0b01000 | 0b01100 | 0b11001 | 0b00100)
+}
+
+fn uses_rs2(word: u32) -> bool {
+    matches!(word >> 2 & 31, 0b11000 | 0b01000 | 0b01100)
+}
+
+pub fn immediate(word: u32) -> u32 {
+    match word >> 2 & 31 {
+        0b00000 | 0b00001 | 0b00100 | 0b00110 | 0b11001 => imm_i(word) as u32,
+        0b00101 | 0b01101 => word & 0xffff_f000,
+        0b01000 | 0b01001 => imm_s(word) as u32,
+        0b11000 => imm_b(word) as u32,
+        0b11011 => imm_j(word) as u32,
+        _ => 0,
+    }
+}
+
+fn mul_div(funct3: u32, a: u32, b: u32) -> u32 {
+    let (a_s, b_s) = (a as i32 as i64, b as i32 as i64);
+    let overflow = a == 0x8000_0000 && b == 0xffff_ffff;
+    match funct3 {
+        0 => a.wrapping_mul(b),
+        1 => ((a_s * b_s) >> 32) as u32,
+        2 => ((a_s * b as i64) >> 32) as u32,
+        3 => ((a as u64 * b as u64) >> 32) as u32,
+        // division by zero and overflow don't trap
+        4 if b == 0 => 0xffff_ffff,
+        4 if overflow => a,
+        4 => (a as i32 / b as i32) as u32,
+        5 if b == 0 => 0xffff_ffff,
+        5 => a / b,
+        6 if b == 0 => a,
+        6 if overflow => 0,
+        6 => (a as i32 % b as i32) as u32,
+        _ if b == 0 => a,
+        _ => a % b,
+    }
+}
+
+/// `execALU32`: what an ALU computes for any word it is handed.
+pub fn alu(word: u32, rv1: u32, rv2: u32, imm: u32, pc: u32) -> u32 {
+    let is_imm = word & 0x20 == 0;
+    if word & 0x4 != 0 {
+        // lui and auipc
+        return if is_imm { pc.wrapping_add(imm) } else { imm };
+    }
+    if word & 0x7f == 0b0110011 && word >> 25 == 1 {
+        return mul_div(funct3(word), rv1, rv2);
+    }
+    let b = if is_imm { imm } else { rv2 };
+    // addi has no subtract, its bit 30 is part of the immediate
+    let alt = word & (1 << 30) != 0 && !(is_imm && funct3(word) == 0);
+    let shamt = b & 31;
+    match funct3(word) {
+        0 if alt => rv1.wrapping_sub(b),
+        0 => rv1.wrapping_add(b),
+        1 => rv1 << shamt,
+        2 => ((rv1 as i32) < (b as i32)) as u32,
+        3 => (rv1 < b) as u32,
+        4 => rv1 ^ b,
+        5 if alt => ((rv1 as i32) >> shamt) as u32,
+        5 => rv1 >> shamt,
+        6 => rv1 | b,
+        _ => rv1 & b,
+    }
+}
+
+/// `execControl32`: the next PC if the word redirects fetch.
+pub fn control(word: u32, rv1: u32, rv2: u32, imm: u32, pc: u32) -> Option<u32> {
+    if word >> 4 & 7 != 0b110 {
+        return None;
+    }
+    match word >> 2 & 3 {
+        // jal
+        0b11 => return Some(pc.wrapping_add(imm)),
+        // jalr
+        0b01 => return Some(rv1.wrapping_add(imm) & !1),
+        _ => {}
+    }
+    let taken = match funct3(word) {
+        0 => rv1 == rv2,
+        1 => rv1 != rv2,
+        4 => (rv1 as i32) < (rv2 as i32),
+        5 => (rv1 as i32) >= (rv2 as i32),
+        6 => rv1 < rv2,
+        7 => rv1 >= rv2,
+        _ => false,
+    };
+    taken.then(|| pc.wrapping_add(imm))
+}
+
+/// Byte-addressed memory holding the words of a hex image, zero elsewhere.
+pub struct Memory {
+    words: HashMap<u32, u32>,
+    // word addresses written since loading
+    stored: BTreeSet<u32>,
+}
+
+impl Memory {
+    pub fn new(words: HashMap<u32, u32>) -> Self {
+        Self { words, stored: BTreeSet::new() }
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        let words = read_hex_words(hex)?.into_iter()
+            .map(|(addr, word)| (addr as u32, word))
+            .collect();
+        Ok(Self::new(words))
+    }
+
+    /// The word containing byte `addr`.
+    pub fn read(&self, addr: u32) -> u32 {
+        *self.words.get(&(addr >> 2)).unwrap_or(&0)
+    }
+
+    fn write(&mut self, addr: u32, data: u32, byte_en: u32) {
+        let mask = (0..4).filter(|b| byte_en >> b & 1 != 0).fold(0, |m, b| m | 0xff << (8 * b));
+        let word = self.words.entry(addr >> 2).or_insert(0);
+        *word = *word & !mask | data & mask;
+        self.stored.insert(addr >> 2);
+    }
+
+    /// Byte addresses of the words stores have written to.
+    pub fn stored(&self) -> impl Iterator<Item = u32> + '_ {
+        self.stored.iter().map(|w| w << 2)
+    }
+
+    /// `MemUnit` and the MMIO requests `Core.bsv` handles: `addr_offset` is added to
+    /// every non-MMIO address, putchar and bus stores go to `console`. Returns the
+    /// loaded value and, for a store to the exit address, the value stored.
+    pub fn access(&mut self, word: u32, rv1: u32, rv2: u32, imm: u32, addr_offset: u32, console: &mut Vec<u8>) -> Result<(u32, Option<u32>), String> {
+        let addr = rv1.wrapping_add(imm);
+        let offset = addr & 3;
+        let addr = addr & !3;
+        let byte_en = match funct3(word) & 3 {
+            0 => 0b0001 << offset,
+            1 => 0b0011 << offset,
+            2 => 0b1111 << offset,
+            _ => 0,
+        } & 0xf;
+        let store = word & 0x20 != 0;
+        let data = if addr >> 29 == 0b111 {
+            match (store, byte_en) {
+                (true, 0xf) => {
+                    if addr >> 28 == 0xe {
+                        console.extend(format!("Bus Request: store {} = {}\n", addr, rv2).bytes());
+                    } else if addr == PUTCHAR_ADDR {
+                        console.push(rv2 as u8);
+                    } else if addr == EXIT_ADDR {
+                        return Ok((0, Some(rv2)));
+                    }
+                    rv2
+                }
+                (false, _) if addr >> 28 == 0xe => 0,
+                (false, _) => return Err(format!("load from {:08x} never gets an MMIO response", addr)),
+                (true, _) => return Err(format!("Illegal sub-word MMIO access to {:08x}", addr)),
+            }
+        } else {
+            let addr = addr.wrapping_add(addr_offset);
+            if store {
+                self.write(addr, rv2 << (8 * offset), byte_en);
+            }
+            self.read(addr)
+        };
+        let data = data >> (8 * offset);
+        let data = match funct3(word) {
+            0b000 => data as u8 as i8 as u32,
+            0b001 => data as u16 as i16 as u32,
+            0b100 => data as u8 as u32,
+            0b101 => data as u16 as u32,
+            _ => data,
+        };
+        Ok((data, None))
+    }
+}
+
+/// Where the core stopped: the cycle the exit store reached `Core.bsv`, the
+/// instructions written back by then (squashed ones included, as `insn_count` does)
+/// and the value stored.
+pub struct Finish {
+    pub cycles: u64,
+    pub insns: u32,
+    pub code: u32,
+}
+
+impl fmt::Display for Finish {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // $display pads 32-bit values to 10 digits
+        write!(f, "RAN CYCLES{:>10}\nRAN INSNS{:>10}", self.cycles, self.insns)
+    }
+}
+
+struct Fetched {
+    // PC of the bundle, one bundle below its memory address
+    pc: u32,
+    epoch: bool,
+    words: Vec<u32>,
+}
+
+struct Issued {
+    bundle: Fetched,
+    ops: Vec<(u32, u32)>,
+}
+
+struct Executed {
+    words: Vec<u32>,
+    // None for a squashed bundle, which only frees its scoreboard entries
+    results: Option<Vec<u32>>,
+}
+
+/// The pipeline of `VLIW.bsv`: fetch, decode (stalling on the scoreboard),
+/// issue (register read), execute and writeback, one bundle per stage per cycle.
+/// Memory answers within the cycle, so cache misses cost nothing. Bundles are as
+/// wide as the machine model's, each word goes to the unit its opcode needs.
+/// Every unit takes one execute cycle like `mulDiv32` in RVUtil.bsv does, so the
+/// model's latencies (mult 3, div 8) only shape the schedule, not the cycle count.
+pub struct Core {
+    pub mem: Memory,
+    pub rf: [u32; 32],
+    width: usize,
+    bundle_bytes: u32,
+    // bit set = no bundle in flight writes the register
+    sc: u32,
+    pc: u32,
+    epoch: bool,
+    pub addr_offset: u32,
+    insn_count: u32,
+    f2d: VecDeque<Fetched>,
+    d2i: VecDeque<Fetched>,
+    i2e: VecDeque<Issued>,
+    e2w: VecDeque<Executed>,
+    // what the core writes to STDERR: putchar and bus stores
+    pub console: Vec<u8>,
+}
+
+impl Core {
+    pub fn new(mem: Memory, model: &MachineModel) -> Self {
+        Self {
+            mem,
+            rf: [0; 32],
+            width: model.width(),
+            bundle_bytes: model.bundle_bytes() as u32,
+            sc: 0xffff_ffff,
+            pc: 0,
+            epoch: false,
+            addr_offset: 0,
+            insn_count: 0,
+            f2d: VecDeque::new(),
+            d2i: VecDeque::new(),
+            i2e: VecDeque::new(),
+            e2w: VecDeque::new(),
+            console: Vec::new(),
+        }
+    }
+
+    /// Runs until the program stores to the exit address.
+    pub fn run(&mut self, max_cycles: u64) -> Result<Finish, String> {
+        // cycle 0 requests the header, cycle 1 reads the data offset from it
+        self.addr_offset = self.mem.read(0);
+        for cycle in 2..max_cycles {
+            if let Some(finish) = self.cycle(cycle)? {
+                return Ok(finish);
+            }
+        }
+        Err(format!("no exit after {} cycles (pc {:x})", max_cycles, self.pc))
+    }
+
+    /// Writes a bundle's results back, returning the registers it frees.
+    fn writeback(&mut self, done: Executed) -> u32 {
+        let mut remove = 0;
+        for (i, word) in done.words.iter().enumerate().filter(|(_, w)| **w != 0) {
+            let dest = if uses_rd(*word) { rd(*word) } else { 0 };
+            remove |= 1 << dest;
+            if let Some(results) = &done.results {
+                if dest != 0 {
+                    self.rf[dest] = results[i];
+                }
+            }
+            self.insn_count = self.insn_count.wrapping_add(1);
+        }
+        remove
+    }
+
+    /// One clock edge. Stages run back to front so each takes what the stage
+    /// before it produced in earlier cycles, and a stage only passes a bundle on
+    /// if the FIFO after it had room when the cycle started.
+    fn cycle(&mut self, cycle: u64) -> Result<Option<Finish>, String> {
+        let f2d_full = self.f2d.len() >= FIFO_DEPTH;
+        let d2i_full = self.d2i.len() >= FIFO_DEPTH;
+        let i2e_full = self.i2e.len() >= FIFO_DEPTH;
+        let e2w_full = self.e2w.len() >= FIFO_DEPTH;
+        let mut remove = 0u32;
+        let mut insert = 0u32;
+        let mut redirect = None;
+        let mut finish = None;
+
+        if let Some(done) = self.e2w.pop_front() {
+            remove = self.writeback(done);
+        }
+
+        if !e2w_full {
+            if let Some(issued) = self.i2e.pop_front() {
+                let bundle = &issued.bundle;
+                let results = if bundle.epoch != self.epoch {
+                    None
+                } else {
+                    let mut results = vec![0; self.width];
+                    for (i, &word) in bundle.words.iter().enumerate().filter(|(_, w)| **w != 0) {
+                        let (rv1, rv2) = issued.ops[i];
+                        let imm = immediate(word);
+                        results[i] = match word >> 2 & 31 {
+                            // loads and stores
+                            0b00000 | 0b01000 => {
+                                let (data, code) = self.mem.access(word, rv1, rv2, imm, self.addr_offset, &mut self.console)?;
+                                if let Some(code) = code {
+                                    // Core.bsv sees the request a cycle later
+                                    finish = Some(Finish { cycles: cycle + 1, insns: self.insn_count, code });
+                                }
+                                data
+                            }
+                            // branches, jal and jalr
+                            0b11000 | 0b11011 | 0b11001 => {
+                                if let Some(target) = control(word, rv1, rv2, imm, bundle.pc) {
+                                    redirect = Some(target);
+                                }
+                                bundle.pc.wrapping_add(self.bundle_bytes)
+                            }
+                            _ => alu(word, rv1, rv2, imm, bundle.pc),
+                        };
+                    }
+                    Some(results)
+                };
+                self.e2w.push_back(Executed { words: issued.bundle.words, results });
+                if finish.is_some() {
+                    // let everything up to the exit store reach the register file
+                    while let Some(done) = self.e2w.pop_front() {
+                        self.writeback(done);
+                    }
+                    return Ok(finish);
+                }
+            }
+        }
+
+        if !i2e_full {
+            if let Some(bundle) = self.d2i.pop_front() {
+                let ops = bundle.words.iter().map(|w| (self.rf[rs1(*w)], self.rf[rs2(*w)])).collect();
+                self.i2e.push_back(Issued { bundle, ops });
+            }
+        }
+
+        if !d2i_full {
+            let ready = |used: bool, reg: usize| !used || reg == 0 || self.sc >> reg & 1 != 0;
+            let all_ready = self.f2d.front().is_some_and(|bundle| bundle.words.iter().all(|w| {
+                *w == 0 || (ready(uses_rs1(*w), rs1(*w)) && ready(uses_rs2(*w), rs2(*w)))
+            }));
+            if all_ready {
+                let bundle = self.f2d.pop_front().unwrap();
+                for word in bundle.words.iter().filter(|w| **w != 0 && uses_rd(**w)) {
+                    insert |= 1 << rd(*word);
+                }
+                self.d2i.push_back(bundle);
+            }
+        }
+
+        let mut next_pc = self.pc;
+        if !f2d_full {
+            let words = (0..self.width)
+                .map(|i| self.mem.read(self.pc.wrapping_add(self.bundle_bytes + 4 * i as u32)))
+                .collect();
+            self.f2d.push_back(Fetched { pc: self.pc, epoch: self.epoch, words });
+            next_pc = self.pc.wrapping_add(self.bundle_bytes);
+        }
+
+        // x0 never goes busy
+        self.sc = (self.sc | remove) & !(insert & !1);
+        if let Some(target) = redirect {
+            self.pc = target;
+            self.epoch = !self.epoch;
+        } else {
+            self.pc = next_pc;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Core, Memory};
+    use crate::analysis::{dep_analysis, trace_to_basicblocks, AnalyzedProgram};
+    use crate::assembler::{assemble, assemble_insn, HexFormat};
+    use crate::isa::Inst;
+    use crate::machine::MachineModel;
+    use crate::parser::parse_asm;
+    use crate::scheduling::{schedule_program, Scheduler};
+
+    /// Hex image of hand-placed bundles (mem, branch, alu, alu), empty strings for nops.
+    fn image(bundles: &[[&str; 4]]) -> String {
+        let mut hex = String::from("@0\n100\n0\n0\n0\n");
+        for (i, bundle) in bundles.iter().enumerate() {
+            for src in bundle.iter() {
+                let word = if src.is_empty() {
+                    0
+                } else {
+                    assemble_insn(&Inst::from_str(src, i * 16).unwrap(), i * 16).unwrap()
+                };
+                hex.push_str(&format!("{:08x}\n", word));
+            }
+        }
+        hex
+    }
+
+    fn run(hex: &str) -> (Core, super::Finish) {
+        let mut core = Core::new(Memory::from_hex(hex).unwrap(), &MachineModel::default());
+        let finish = core.run(10_000).unwrap();
+        (core, finish)
+    }
+
+    const EXIT: [&str; 4] = ["sw a1, -8(t0)", "", "", ""];
+
+    #[test]
+    fn test_scoreboard_stalls() {
+        let independent = image(&[
+            ["", "", "lui t0, 0xf0010", "addi a0, x0, 5"],
+            ["", "", "addi a1, x0, 1", ""],
+            EXIT,
+        ]);
+        let dependent = image(&[
+            ["", "", "lui t0, 0xf0010", "addi a0, x0, 5"],
+            ["", "", "addi a1, a0, 1", ""],
+            EXIT,
+        ]);
+        let (_, fast) = run(&independent);
+        let (core, slow) = run(&dependent);
+        // a read waits for decode, issue, execute and writeback of the bundle before it
+        assert_eq!(slow.cycles, fast.cycles + 3);
+        assert_eq!(slow.code, 6);
+        assert_eq!(core.rf[11], 6);
+        assert_eq!(fast.insns, 3);
+    }
+
+    #[test]
+    fn test_branches_squash() {
+        let hex = image(&[
+            ["", "", "lui t0, 0xf0010", "addi a1, x0, 7"],
+            ["", "jal ra, 64", "", ""],
+            ["", "", "addi a1, x0, 1", ""],
+            ["", "", "addi a1, x0, 2", ""],
+            EXIT,
+        ]);
+        let (core, finish) = run(&hex);
+        assert_eq!(finish.code, 7);
+        // the link register holds the bundle after the jump
+        assert_eq!(core.rf[1], 32);
+        // the three bundles fetched behind the jump are squashed, and counted
+        assert_eq!(finish.insns, 6);
+        assert!(finish.to_string().starts_with("RAN CYCLES"));
+    }
+
+    #[test]
+    fn test_run_scheduled_program() {
+        let src = "   li t0, 0xf000fff0
+                       li a0, 5
+                       li a1, 0
+                   1:  add a1, a1, a0
+                       addi a0, a0, -1
+                       bnez a0, 1b
+                       sw a1, 256(x0)
+                       lw a2, 256(x0)
+                       addi a2, a2, 50
+                       sw a2, 0(t0)
+                       addi t1, t0, 8
+                       addi a1, a1, -15
+                       sw a1, 0(t1)
+                   2:  j 2b";
+        // a second base register keeps the exit store after putchar
+        let (trace, _) = parse_asm(src).unwrap();
+        let orig_size = trace.len() * 4;
+        let ap = AnalyzedProgram {
+            bbs: trace_to_basicblocks(trace, &[]).unwrap().into_iter().map(dep_analysis).collect(),
+            ..Default::default()
+        };
+        let dualmem = MachineModel::from_json(include_str!("../machines/vliw6_dualmem.json")).unwrap();
+        for (model, scheduler) in [(MachineModel::default(), Scheduler::List), (MachineModel::default(), Scheduler::Superblock), (dualmem, Scheduler::List)] {
+            let mut sp = schedule_program(ap.clone(), &model, scheduler);
+            crate::fix_addresses(&mut sp).unwrap();
+            let mut core = Core::new(Memory::from_hex(&assemble(&sp, orig_size, &[], HexFormat::Words).unwrap()).unwrap(), &model);
+            let finish = core.run(10_000).unwrap();
+            assert_eq!(core.console, b"A");
+            assert_eq!(finish.code, 0);
+        }
+    }
+}
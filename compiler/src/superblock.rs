@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::analysis::{dep_graph, liveness, AnalyzedBasicBlock, AnalyzedProgram, Cfg, ALL_REGS};
+use crate::isa::{ExecutionUnit, Inst, Label, Opcode, Operand};
+use crate::machine::MachineModel;
+use crate::modulo::{pipeline_loop, push_alone, synthetic};
+use crate::scheduling::{compatible, fill_schedule, schedule_program, ScheduledProgram, Scheduler};
+
+/// Instructions a side entrance may copy before the trace is cut there instead.
+const MAX_TAIL_DUP: usize = 24;
+
+/// Summary of a scheduled trace, for `--stats`.
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub addr: usize,
+    pub blocks: usize,
+    // copies of its tail made for side entrances
+    pub copies: usize,
+    // instructions issued in or ahead of the bundle of a branch they follow
+    pub speculated: usize,
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "trace @ {:x}: {} blocks, {} tail copies, {} instructions speculated",
+            self.addr, self.blocks, self.copies, self.speculated)
+    }
+}
+
+fn block_len(bb: &AnalyzedBasicBlock) -> usize {
+    bb.insns.len() + bb.cf_insn.is_some() as usize
+}
+
+/// The successor a trace continues with from `b`: the profiled more likely one, else
+/// taken for backward branches (loops) and not taken for forward ones.
+fn likely_successor(prog: &AnalyzedProgram, cfg: &Cfg, b: usize) -> Option<usize> {
+    let exits = &cfg.exits[b];
+    if exits.escape.is_some() {
+        return None;
+    }
+    if !exits.conditional {
+        return exits.taken.or(exits.fall);
+    }
+    let (fall, taken) = (exits.fall?, exits.taken?);
+    let cf = &prog.bbs[b].cf_insn.as_ref()?.inst;
+    let taken_likely = match prog.branch_weights.get(&cf.addr) {
+        Some((taken, not_taken)) => taken > not_taken,
+        None => taken <= b,
+    };
+    Some(if taken_likely { taken } else { fall })
+}
+
+/// Grows a trace from each block not yet in one, in layout order, along likely forward edges.
+/// Loop headers only ever start a trace. A trace is cut at a side entrance whose tail would
+/// be too long to copy.
+fn form_traces(
+    prog: &AnalyzedProgram,
+    cfg: &Cfg,
+    side_entered: impl Fn(usize, usize) -> bool,
+) -> Vec<Vec<usize>> {
+    let mut in_trace = vec![false; cfg.len()];
+    let mut traces = Vec::new();
+    for seed in 0..cfg.len() {
+        if in_trace[seed] {
+            continue;
+        }
+        let mut trace = vec![seed];
+        in_trace[seed] = true;
+        while let Some(next) = likely_successor(prog, cfg, *trace.last().unwrap())
+            .filter(|s| !in_trace[*s] && *s > *trace.last().unwrap() && cfg.preds[*s].iter().all(|(p, _)| p < s)) {
+            in_trace[next] = true;
+            trace.push(next);
+        }
+        let tail_len = |from: usize| trace[from..].iter().map(|b| block_len(&prog.bbs[*b])).sum::<usize>();
+        if let Some(cut) = (1..trace.len()).find(|i| side_entered(trace[*i], trace[i - 1]) && tail_len(*i) > MAX_TAIL_DUP) {
+            // later seeds pick these up
+            for b in trace.drain(cut..) {
+                in_trace[b] = false;
+            }
+        }
+        traces.push(trace);
+    }
+    traces
+}
+
+/// The instructions of `blocks` as one straight line, with the jumps along it dropped and the
+/// branches taken along it inverted. Also returns the registers live where each remaining
+/// branch leaves the line, by branch address.
+fn linearize(prog: &AnalyzedProgram, cfg: &Cfg, live_in: &[u32], blocks: &[usize]) -> (Vec<Inst>, HashMap<usize, u32>) {
+    let mut insts = Vec::new();
+    let mut live_off = HashMap::new();
+    for (i, b) in blocks.iter().enumerate() {
+        let bb = &prog.bbs[*b];
+        insts.extend(bb.insns.iter().map(|d| d.inst));
+        let Some(mut cf) = bb.cf_insn.as_ref().map(|d| d.inst) else { continue };
+        let Some(next) = blocks.get(i + 1) else {
+            insts.push(cf);
+            continue;
+        };
+        let exits = &cfg.exits[*b];
+        // a branch to the block it falls into stays on the line either way
+        if !exits.conditional || exits.taken == exits.fall {
+            continue;
+        }
+        let off = if exits.fall == Some(*next) {
+            exits.taken
+        } else {
+            cf.opcode = cf.opcode.inverted_branch().unwrap();
+            cf.label = Label::SrcAddrSpace(prog.bbs[exits.fall.unwrap()].addr());
+            exits.fall
+        };
+        live_off.insert(cf.addr, off.map_or(ALL_REGS, |o| live_in[o]));
+        insts.push(cf);
+    }
+    (insts, live_off)
+}
+
+/// ALU ops can't trap, so they may run on the path where a branch ahead of them is taken.
+fn speculable(inst: &Inst) -> bool {
+    matches!(inst.opcode.eu_type(), ExecutionUnit::ALU | ExecutionUnit::Mult) && matches!(inst.dest, Operand::Gpr(_))
+}
+
+/// List schedules a superblock. Nothing sinks below a branch, branches keep their order in
+/// bundles of their own, and only speculable instructions whose destination is dead where
+/// the branch leaves may issue in or ahead of its bundle. Returns how many did.
+fn schedule_trace(model: &MachineModel, sp: &mut ScheduledProgram, insts: Vec<Inst>, live_off: &HashMap<usize, u32>) -> usize {
+    let nodes = dep_graph(insts);
+    let n = nodes.len();
+    let index: HashMap<usize, usize> = nodes.iter().enumerate().map(|(i, d)| (d.inst.addr, i)).collect();
+    let branches: Vec<usize> = (0..n).filter(|i| nodes[*i].inst.opcode.is_control_flow()).collect();
+    // (pred, cycles it issues ahead)
+    let mut preds: Vec<Vec<(usize, usize)>> = nodes.iter()
+        .map(|d| d.all_deps().iter()
+            .filter_map(|dep| index.get(&dep.addr))
+            .map(|p| (*p, model.latency(nodes[*p].inst.opcode)))
+            .collect())
+        .collect();
+    let mut prev: Option<usize> = None;
+    for b in branches.iter() {
+        let from = prev.map_or(0, |p| p + 1);
+        preds[*b].extend((from..*b).map(|i| (i, 0)));
+        preds[*b].extend(prev.map(|p| (p, 1)));
+        prev = Some(*b);
+    }
+    let mut height = vec![0; n];
+    for i in (0..n).rev() {
+        height[i] += model.latency(nodes[i].inst.opcode);
+        for (p, _) in preds[i].iter() {
+            height[*p] = std::cmp::max(height[*p], height[i]);
+        }
+    }
+    let may_cross = |i: usize, b: usize| {
+        let inst = &nodes[i].inst;
+        speculable(inst) && live_off.get(&nodes[b].inst.addr).is_some_and(|live| live & (1 << inst.dest.unwrap_gpr()) == 0)
+    };
+
+    let bytes = model.bundle_bytes();
+    let mut cycle_of: Vec<Option<usize>> = vec![None; n];
+    let mut remaining = n;
+    let mut cycle = sp.schedule.len();
+    let mut speculated = 0;
+    while remaining > 0 {
+        fill_schedule(model, cycle, &mut sp.schedule);
+        // placing a node can make another ready in the same bundle
+        let mut placed = true;
+        while placed {
+            placed = false;
+            let ahead_of = |i: usize, cycle_of: &[Option<usize>]| branches.iter()
+                .take_while(|b| **b < i)
+                .filter(|b| cycle_of[**b].is_none_or(|c| c >= cycle))
+                .copied()
+                .collect::<Vec<_>>();
+            let mut cands: Vec<usize> = (0..n)
+                .filter(|i| cycle_of[*i].is_none()
+                    && preds[*i].iter().all(|(p, lat)| cycle_of[*p].is_some_and(|c| c + lat <= cycle))
+                    && ahead_of(*i, &cycle_of).iter().all(|b| may_cross(*i, *b)))
+                .collect();
+            cands.sort_by_key(|i| (std::cmp::Reverse(height[*i]), *i));
+            for i in cands {
+                let Some(slot) = compatible(model, &sp.schedule[cycle], &nodes[i]) else { continue };
+                if !ahead_of(i, &cycle_of).is_empty() {
+                    speculated += 1;
+                }
+                let mut inst = nodes[i].clone();
+                // pc-relative data pairs are relative to where this copy of the auipc went
+                if let (Some(_), Label::SrcAddrSpace(l)) = (inst.inst.offset, inst.inst.label) {
+                    let auipc = index.get(&l).and_then(|a| if *a == i { Some(cycle) } else { cycle_of[*a] });
+                    if let Some(auipc) = auipc {
+                        inst.inst.label = Label::DstAddrSpace(auipc * bytes);
+                    }
+                }
+                sp.starts.insert(inst.inst.addr, cycle);
+                sp.schedule[cycle].slots[slot] = Some(inst);
+                cycle_of[i] = Some(cycle);
+                remaining -= 1;
+                placed = true;
+            }
+        }
+        cycle += 1;
+    }
+    speculated
+}
+
+/// Superblock scheduling: the blocks of each trace are scheduled as one unit, a side entrance
+/// gets its own copy of the rest of its trace (tail duplication), and a single-block loop is
+/// software pipelined when it can be.
+pub fn schedule_superblocks(prog: AnalyzedProgram, model: &MachineModel) -> ScheduledProgram {
+    let cfg = Cfg::new(&prog);
+    let live_in = liveness(&prog, &cfg).entry;
+    let side_entered = |b: usize, pred: usize| cfg.external[b] || cfg.preds[b].iter().any(|(p, _)| *p != pred);
+    let traces = form_traces(&prog, &cfg, side_entered);
+
+    // (trace, blocks): every trace, then a copy of its tail from each side entrance
+    let mut seqs: Vec<(usize, Vec<usize>)> = Vec::new();
+    for (t, trace) in traces.iter().enumerate() {
+        seqs.push((t, trace.clone()));
+        seqs.extend((1..trace.len()).filter(|i| side_entered(trace[*i], trace[i - 1])).map(|i| (t, trace[i..].to_vec())));
+    }
+    let owner: HashMap<usize, usize> = seqs.iter().enumerate().map(|(s, (_, blocks))| (blocks[0], s)).collect();
+    // lay out the sequence a sequence falls into right after it, when it hasn't been yet;
+    // tail copies only start a chain once no trace falls into them
+    let mut order = Vec::new();
+    let mut laid_out = vec![false; seqs.len()];
+    let is_trace = |s: &usize| seqs[*s].1[0] == traces[seqs[*s].0][0];
+    for s in (0..seqs.len()).filter(is_trace).chain(0..seqs.len()) {
+        let mut next = Some(s);
+        while let Some(cur) = next.filter(|c| !laid_out[*c]) {
+            laid_out[cur] = true;
+            order.push(cur);
+            next = cfg.exits[*seqs[cur].1.last().unwrap()].fall.and_then(|f| owner.get(&f).copied());
+        }
+    }
+
+    let mut sp = ScheduledProgram {
+        model: model.clone(),
+        starts: HashMap::new(),
+        entries: HashMap::new(),
+        schedule: Vec::new(),
+        bb_starts: Vec::new(),
+        pipelined: Vec::new(),
+        traces: traces.iter()
+            .map(|t| Trace { addr: prog.bbs[t[0]].addr(), blocks: t.len(), copies: 0, speculated: 0 })
+            .collect(),
+    };
+    let bytes = model.bundle_bytes();
+    for (k, s) in order.iter().enumerate() {
+        let (t, blocks) = &seqs[*s];
+        let (first, last) = (blocks[0], *blocks.last().unwrap());
+        let base = sp.schedule.len();
+        sp.bb_starts.push(base);
+        sp.entries.insert(prog.bbs[first].addr(), base);
+        let (mut insts, live_off) = linearize(&prog, &cfg, &live_in, blocks);
+        // a pipelined loop exits with a jump to the next block, so there has to be one
+        if blocks.len() == 1 && cfg.exits[first].taken == Some(first) && cfg.exits[first].fall.is_some() {
+            let single = AnalyzedProgram { bbs: vec![prog.bbs[first].clone()], ..Default::default() };
+            let flat_len = schedule_program(single, model, Scheduler::List).schedule.len();
+            let exit = prog.bbs[cfg.exits[first].fall.unwrap()].addr();
+            if let Some(pipelined) = pipeline_loop(model, &prog.bbs[first], exit, flat_len, &mut sp.schedule) {
+                // the original loop is the fallback, and branches back to itself
+                insts.last_mut().unwrap().label = Label::DstAddrSpace(sp.schedule.len() * bytes);
+                sp.pipelined.push(pipelined);
+            }
+        }
+        sp.traces[*t].speculated += schedule_trace(model, &mut sp, insts, &live_off);
+        if first != traces[*t][0] {
+            sp.traces[*t].copies += 1;
+        }
+        // leaving through the bottom for a block that isn't laid out next
+        let Some(fall) = cfg.exits[last].fall else { continue };
+        if order.get(k + 1).map(|n| seqs[*n].1[0]) == Some(fall) {
+            continue;
+        }
+        let bb = &prog.bbs[last];
+        let from = bb.insns.iter().chain(bb.cf_insn.as_ref()).last().unwrap().inst.addr;
+        let jump = Inst {
+            addr: from,
+            dest: Operand::Gpr(0),
+            label: Label::SrcAddrSpace(prog.bbs[fall].addr()),
+            ..Inst::new(Opcode::J)
+        };
+        // a call returns to the bundle after it, and a bundle can't hold two branches
+        let bottom = sp.schedule.last_mut().unwrap();
+        let slot = (0..model.width()).find(|s| bottom.slots[*s].is_none() && model.accepts(*s, ExecutionUnit::Branch));
+        match slot.filter(|_| !bottom.slots.iter().flatten().any(|i| i.inst.opcode.is_control_flow())) {
+            Some(slot) => bottom.slots[slot] = Some(synthetic(jump)),
+            None => push_alone(model, &mut sp.schedule, jump),
+        }
+    }
+    sp
+}
+
+/// Reads branch weights for trace formation: a JSON object from branch address (hex) to
+/// `[taken, not taken]` counts.
+pub fn load_profile(path: &Path) -> Result<HashMap<usize, (u64, u64)>, String> {
+    let src = fs::read_to_string(path)
+        .map_err(|e| format!("Error opening profile: {}", e))?;
+    let counts: HashMap<String, (u64, u64)> = serde_json::from_str(&src)
+        .map_err(|e| format!("Bad profile: {}", e))?;
+    counts.into_iter()
+        .map(|(addr, c)| usize::from_str_radix(addr.trim_start_matches("0x"), 16)
+            .map(|a| (a, c))
+            .map_err(|_| format!("Bad branch address {} in profile", addr)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::analysis::AnalyzedProgram;
+    use crate::isa::Opcode;
+    use crate::machine::MachineModel;
+    use crate::scheduling::{schedule_program, ScheduledProgram, Scheduler};
+    use crate::testutil::{analyze, assert_same};
+
+    fn schedule(src: &str, branch_weights: &[(usize, (u64, u64))], scheduler: Scheduler) -> ScheduledProgram {
+        let ap = AnalyzedProgram { branch_weights: branch_weights.iter().copied().collect(), ..analyze(src) };
+        schedule_program(ap, &MachineModel::default(), scheduler)
+    }
+
+    #[test]
+    fn test_speculation_past_branch() {
+        let src = "
+                beq a0, x0, 1f
+                addi t0, a1, 5
+                add a2, a2, t0
+            1:  add a0, a1, a2
+                ret
+        ";
+        let sp = schedule(src, &[], Scheduler::Superblock);
+        let trace = &sp.traces[0];
+        // the join is entered from the branch too, so it gets a copy of its own
+        assert_eq!((trace.blocks, trace.copies, trace.speculated), (3, 1, 1));
+        let copies = sp.schedule.iter().flat_map(|b| b.slots.iter().flatten()).filter(|i| i.inst.addr == 0xc).count();
+        assert_eq!(copies, 2);
+        // t0 is dead if the branch is taken, a2 isn't
+        assert_eq!(sp.starts[&0x4], sp.starts[&0x0]);
+        assert!(sp.starts[&0x8] > sp.starts[&0x0]);
+        for a0 in [0, 1] {
+            let mut regs = [0; 32];
+            (regs[10], regs[11], regs[12]) = (a0, 3, 4);
+            assert_same(src, &sp, regs, &HashMap::new());
+        }
+    }
+
+    #[test]
+    fn test_profiled_trace() {
+        let src = "
+                blt a0, a1, 1f
+                addi a2, a2, 1
+                j 2f
+            1:  addi a3, a3, 1
+            2:  add a0, a2, a3
+                ret
+        ";
+        let fall = schedule(src, &[], Scheduler::Superblock);
+        let taken = schedule(src, &[(0x0, (90, 10))], Scheduler::Superblock);
+        let first_branch = |sp: &ScheduledProgram| sp.schedule.iter()
+            .flat_map(|b| b.slots.iter().flatten())
+            .find(|i| i.inst.addr == 0x0)
+            .unwrap().inst.opcode;
+        // forward branches are predicted not taken, the profile says otherwise
+        assert_eq!(fall.traces[0].blocks, 3);
+        assert_eq!(first_branch(&fall), Opcode::BLT);
+        assert_eq!(taken.traces[0].blocks, 3);
+        assert_eq!(first_branch(&taken), Opcode::BGE);
+        for a0 in [0, 5, 10] {
+            let mut regs = [0; 32];
+            (regs[10], regs[11], regs[12], regs[13]) = (a0, 5, 10, 20);
+            assert_same(src, &fall, regs, &HashMap::new());
+            assert_same(src, &taken, regs, &HashMap::new());
+        }
+    }
+
+    #[test]
+    fn test_superblocks_match() {
+        let programs = [
+            // shift and add multiply, a hammock inside a loop
+            "   mv a5, a0
+                li a3, 0
+                li a0, 0
+                li a6, 32
+            1:  sra a4, a1, a3
+                andi a4, a4, 1
+                sll a2, a5, a3
+                addi a3, a3, 1
+                beqz a4, 2f
+                add a0, a0, a2
+            2:  bne a3, a6, 1b
+                ret
+            ",
+            // running maximum, stored as it goes
+            "   li a3, 0
+            1:  lw a4, 0(a0)
+                bge a3, a4, 2f
+                mv a3, a4
+            2:  sw a3, 0x100(a0)
+                addi a0, a0, 4
+                bne a0, a2, 1b
+                mv a0, a3
+                ret
+            ",
+            // a chain of forward branches with work to hoist between them
+            "   slli t1, a0, 3
+                blt a0, a1, 1f
+                sub t2, a0, a1
+                xor a0, t2, t1
+                bgeu t2, a2, 2f
+                addi t3, t2, 7
+                mul t3, t3, t3
+                add a0, a0, t3
+                j 3f
+            1:  sub t2, a1, a0
+                add a0, t2, t1
+            2:  sw a0, 0(a2)
+                lw a1, 4(a2)
+            3:  or a1, a1, a0
+                ret
+            ",
+            // a branch to where it falls through anyway
+            "   add a0, a0, a1
+                bltu a0, a1, 1f
+            1:  sub a1, a0, a1
+                ret
+            ",
+        ];
+        for src in programs {
+            let superblock = schedule(src, &[], Scheduler::Superblock);
+            for (a0, a1) in [(0, 0), (1, 3), (7, 2), (9, 9), (0x55, 0xf0), (3, 0x7fff_ffff)] {
+                let mem: HashMap<u32, u32> = (0..16).map(|i| (0x40 + 4 * i, (i * 37) % 11)).collect();
+                let mut regs = [0; 32];
+                (regs[10], regs[11], regs[12]) = (a0, a1, 0x40 + 4 * (a1 % 16));
+                if src.contains("lw a4, 0(a0)") {
+                    (regs[10], regs[12]) = (0x40, 0x40 + 4 * (a0 % 16 + 1));
+                }
+                assert_same(src, &superblock, regs, &mem);
+            }
+        }
+    }
+}
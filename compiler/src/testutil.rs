@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use crate::analysis::{dep_analysis, trace_to_basicblocks, AnalyzedProgram};
+use crate::assembler::{assemble, assemble_insn, HexFormat};
+use crate::disassembler::read_hex_words;
+use crate::elf::Section;
+use crate::interp::Interpreter;
+use crate::isa::Inst;
+use crate::machine::MachineModel;
+use crate::parser::parse_asm;
+use crate::scheduling::{schedule_program, ScheduledProgram, Scheduler};
+use crate::sim::{Core, Memory};
+
+/// What a caller can see after `ret`: sp, the saved registers and the return values.
+pub const CALLER_VISIBLE: [usize; 15] = [2, 8, 9, 10, 11, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
+
+// where `ret` goes: a store to the exit address, well past any test's code and data
+const RETURN_ADDR: usize = 0x10000;
+const EXIT: [&str; 2] = ["lui t6, 0xf0010", "sw x0, -8(t6)"];
+
+/// Basic blocks of an assembly snippet, without indirect targets or a profile.
+pub fn analyze(src: &str) -> AnalyzedProgram {
+    let (trace, _) = parse_asm(src).unwrap();
+    AnalyzedProgram {
+        bbs: trace_to_basicblocks(trace, &[]).unwrap().into_iter().map(dep_analysis).collect(),
+        ..Default::default()
+    }
+}
+
+/// The program from `analyze`, scheduled for the default machine.
+pub fn schedule(src: &str, scheduler: Scheduler) -> ScheduledProgram {
+    schedule_program(analyze(src), &MachineModel::default(), scheduler)
+}
+
+/// Runs `src` on the interpreter and `sp` on the core from `regs` and `mem` (byte
+/// address to word, above the code), and compares memory and the caller visible
+/// registers. Returns the registers the core ends with.
+pub fn assert_same(src: &str, sp: &ScheduledProgram, regs: [u32; 32], mem: &HashMap<u32, u32>) -> [u32; 32] {
+    let mut regs = regs;
+    regs[1] = RETURN_ADDR as u32;
+
+    let (mut trace, _) = parse_asm(src).unwrap();
+    let orig_size = trace.len() * 4;
+    assert!(mem.keys().all(|a| *a as usize >= orig_size), "test data overlaps the code");
+    trace.extend(EXIT.iter().enumerate().map(|(i, s)| Inst::from_str(s, RETURN_ADDR + 4 * i).unwrap()));
+    let data: Vec<Section> = mem.iter()
+        .map(|(a, w)| Section { name: String::from(".data"), addr: *a as usize, size: 4, data: w.to_le_bytes().to_vec() })
+        .collect();
+    let mut interp = Interpreter::new(&trace, &data, orig_size);
+    interp.regs = regs;
+    interp.run(1_000_000).unwrap();
+
+    let mut sp = sp.clone();
+    crate::fix_addresses(&mut sp).unwrap();
+    let hex = assemble(&sp, 0, &[], HexFormat::Words).unwrap();
+    let mut words: HashMap<u32, u32> = read_hex_words(&hex).unwrap().into_iter().map(|(a, w)| (a as u32, w)).collect();
+    let offset = words[&0];
+    // the lui in an ALU slot, the store in the memory slot of the bundle after it
+    let bundle = sp.model.bundle_bytes();
+    for (i, (src, slot)) in EXIT.iter().zip([2, 0]).enumerate() {
+        let addr = RETURN_ADDR + bundle * i;
+        words.insert(((addr + bundle) / 4 + slot) as u32, assemble_insn(&Inst::from_str(src, addr).unwrap(), addr).unwrap());
+    }
+    for (a, w) in mem.iter() {
+        words.insert((a + offset) / 4, *w);
+    }
+    let mut core = Core::new(Memory::new(words), &sp.model);
+    core.rf = regs;
+    core.run(1_000_000).unwrap();
+
+    let stored = interp.mem.stored()
+        .chain(core.mem.stored().map(|a| a.wrapping_sub(offset)))
+        .chain(mem.keys().copied());
+    for a in stored {
+        assert_eq!(core.mem.read(a + offset), interp.mem.read(a), "mem[{:#x}] {:?}", a, regs);
+    }
+    for r in CALLER_VISIBLE {
+        assert_eq!(core.rf[r], interp.regs[r], "x{} {:?}", r, regs);
+    }
+    core.rf
+}
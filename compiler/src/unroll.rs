@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::analysis::{dep_analysis, find_loops, liveness, reg_reads, reg_write, AnalyzedProgram, Cfg, Loop};
+use crate::isa::{Inst, Label, Opcode, Operand};
+use crate::modulo::{iv_immediate, shift_iv_immediate};
+use crate::rename::{rename_reads, POOL};
+
+/// Instructions an unrolled body may grow to.
+const MAX_UNROLLED: usize = 64;
+
+/// Summary of an unrolled loop, for `--stats`.
+#[derive(Debug, Clone)]
+pub struct UnrolledLoop {
+    pub addr: usize,
+    pub factor: usize,
+    // instructions per iteration, and in the unrolled body
+    pub body: usize,
+    pub unrolled: usize,
+    // temporaries given their own register in all copies but the last
+    pub renamed: usize,
+}
+
+impl fmt::Display for UnrolledLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "loop @ {:x}: unrolled {}x, {} -> {} instructions, {} temporaries renamed between copies",
+            self.addr, self.factor, self.body, self.unrolled, self.renamed)
+    }
+}
+
+/// A branch on `lhs` and `rhs`; branches keep their left operand in src2.
+fn branch(opcode: Opcode, lhs: u32, rhs: u32, target: usize) -> Inst {
+    Inst { src1: Some(rhs), src2: Operand::Gpr(lhs), label: Label::SrcAddrSpace(target), ..Inst::new(opcode) }
+}
+
+/// Branches to `target` when the loop has at least `factor` more iterations to run (or fewer,
+/// if not `when_enough`), using `scratch`. Only valid while the loop test holds or, for the
+/// ordered compares, on entry to the loop.
+fn enough_left(cf: &Inst, iv: u32, step: i64, factor: i64, scratch: u32, when_enough: bool, target: usize) -> Option<Vec<Inst>> {
+    let (lhs, rhs) = (cf.src2.unwrap_gpr(), cf.src1?);
+    let bound = if lhs == iv { rhs } else { lhs };
+    if cf.opcode == Opcode::BNE {
+        // (bound - iv) / step iterations are left
+        let span = step.unsigned_abs() as i64 * factor;
+        let (hi, lo) = if step > 0 { (bound, iv) } else { (iv, bound) };
+        let opcode = if when_enough { Opcode::BEQ } else { Opcode::BNE };
+        return (span < 2048).then(|| vec![
+            Inst { dest: Operand::Gpr(scratch), src1: Some(hi), src2: Operand::Gpr(lo), ..Inst::new(Opcode::SUB) },
+            Inst { dest: Operand::Gpr(scratch), src1: Some(scratch), src2: Operand::Immediate(span), ..Inst::new(Opcode::SLTIU) },
+            branch(opcode, scratch, 0, target),
+        ]);
+    }
+    // the ordered compares are monotonic: the next `factor` iterations all run if the last does
+    let ahead = step * (factor - 1);
+    let opcode = if when_enough { cf.opcode } else { cf.opcode.inverted_branch()? };
+    let (lhs, rhs) = if lhs == iv { (scratch, rhs) } else { (lhs, scratch) };
+    (-2048..2048).contains(&ahead).then(|| vec![
+        Inst { dest: Operand::Gpr(scratch), src1: Some(iv), src2: Operand::Immediate(ahead), ..Inst::new(Opcode::ADDI) },
+        branch(opcode, lhs, rhs, target),
+    ])
+}
+
+/// The blocks to put in front of a counted single-block loop, which stays in place to run the
+/// iterations left over:
+/// - a check that at least `factor` iterations are left, else on to the original loop,
+/// - the body `factor` times, looping while another `factor` iterations are left,
+/// - a jump out when none are, else falling into the original loop.
+///
+/// With `first` the address of the new blocks and `exit` the one of the block after the loop.
+fn unroll(l: &Loop, insts: &[Inst], free: &[u32], factor: usize, first: usize, exit: usize) -> Option<(Vec<Vec<Inst>>, UnrolledLoop)> {
+    let tc = l.trip_count.as_ref()?;
+    let (cf, body) = insts.split_last()?;
+    let (iv, step, u) = (tc.iv, tc.step, factor as i64);
+    if body.len() * factor > MAX_UNROLLED
+        || tc.count.is_some_and(|n| n < factor as u64)
+        || !matches!(cf.opcode, Opcode::BNE | Opcode::BLT | Opcode::BGE | Opcode::BLTU | Opcode::BGEU)
+        // the checks read the bound before the body runs
+        || body.iter().any(|i| reg_write(i) == Some(tc.bound))
+        // copies of a pc-relative pair would need their own offsets, code addresses their own entries
+        || body.iter().any(|i| i.opcode == Opcode::AUIPC || i.label != Label::None) {
+        return None;
+    }
+    let (&scratch, free) = free.split_first()?;
+    // registers stepped by a single `addi r, r, step` that everything else only takes an
+    // immediate from: step them once per unrolled iteration, with the immediates of each copy
+    // shifted to match
+    let steps: Vec<(usize, u32, i64)> = body.iter().enumerate().filter_map(|(i, inst)| {
+        let r = reg_write(inst)?;
+        let step = iv_immediate(inst, r).filter(|_| inst.opcode == Opcode::ADDI)?;
+        // the combined step has to fit an addi too
+        let foldable = (-2048..2048).contains(&(u * step)) && body.iter().enumerate().all(|(j, other)| {
+            (j == i && reg_write(other) == Some(r))
+                || (reg_write(other) != Some(r) && (!reg_reads(other).contains(&r)
+                    || iv_immediate(other, r).is_some_and(|imm| (-2048..2048).contains(&(imm + u * step)))))
+        });
+        foldable.then_some((i, r, step))
+    }).collect();
+
+    // registers each iteration writes before it reads
+    let mut seen = Vec::new();
+    let mut temps = Vec::new();
+    for inst in body.iter() {
+        seen.extend(reg_reads(inst));
+        if let Some(rd) = reg_write(inst).filter(|rd| !seen.contains(rd)) {
+            temps.push(rd);
+        }
+        seen.extend(reg_write(inst));
+    }
+    // the last copy leaves its values for the code after the loop
+    let mut free = free.iter().copied();
+    let renames: Vec<HashMap<u32, u32>> = (0..factor - 1)
+        .map(|_| temps.iter().filter_map(|r| free.next().map(|f| (*r, f))).collect())
+        .collect();
+    let renamed = renames.iter().map(|m| m.len()).sum();
+
+    let mut unrolled = Vec::new();
+    for k in 0..factor {
+        for (i, inst) in body.iter().enumerate() {
+            let mut inst = *inst;
+            if steps.iter().any(|(update, _, _)| *update == i) {
+                continue;
+            }
+            let reads = reg_reads(&inst);
+            for (update, _, step) in steps.iter().filter(|(_, r, _)| reads.contains(r)) {
+                shift_iv_immediate(&mut inst, step * (k as i64 + (i > *update) as i64));
+            }
+            for (from, to) in renames.get(k).into_iter().flatten() {
+                rename_reads(&mut inst, *from, *to);
+                if reg_write(&inst) == Some(*from) {
+                    inst.dest = Operand::Gpr(*to);
+                }
+            }
+            unrolled.push(inst);
+        }
+    }
+    for (_, r, step) in steps.iter() {
+        unrolled.push(Inst { dest: Operand::Gpr(*r), src1: Some(*r), src2: Operand::Immediate(step * u), ..Inst::new(Opcode::ADDI) });
+    }
+
+    let check = enough_left(cf, iv, step, u, scratch, false, insts[0].addr)?;
+    let main = first + check.len() * 4;
+    unrolled.extend(enough_left(cf, iv, step, u, scratch, true, main)?);
+    let mut leave = *cf;
+    leave.opcode = cf.opcode.inverted_branch()?;
+    leave.label = Label::SrcAddrSpace(exit);
+    let mut blocks = vec![check, unrolled, vec![leave]];
+    let mut addr = first;
+    for inst in blocks.iter_mut().flatten() {
+        inst.addr = addr;
+        addr += 4;
+    }
+    let stats = UnrolledLoop { addr: insts[0].addr, factor, body: insts.len(), unrolled: blocks[1].len(), renamed };
+    Some((blocks, stats))
+}
+
+/// Unrolls counted single-block loops `factor` times, ahead of the original loop which runs
+/// whatever is left. Copies get fresh source addresses past the end of the code. Temporaries
+/// move to registers free around the loop, so the copies don't depend on each other through
+/// them.
+pub fn unroll_loops(prog: AnalyzedProgram, factor: usize) -> (AnalyzedProgram, Vec<UnrolledLoop>) {
+    if factor < 2 {
+        return (prog, Vec::new());
+    }
+    let cfg = Cfg::new(&prog);
+    let live = liveness(&prog, &cfg);
+    let code: Vec<Vec<Inst>> = prog.bbs.iter()
+        .map(|bb| bb.insns.iter().chain(bb.cf_insn.as_ref()).map(|d| d.inst).collect())
+        .collect();
+    let mut next = code.iter().flatten().map(|i| i.addr + 4).max().unwrap_or(0);
+
+    let mut inserted: HashMap<usize, Vec<Vec<Inst>>> = HashMap::new();
+    let mut unrolled = Vec::new();
+    for l in find_loops(&prog, &cfg) {
+        let h = l.header;
+        let Some(exit) = cfg.exits[h].fall.filter(|_| l.blocks == [h] && !cfg.external[h]) else { continue };
+        let used = code[h].iter().fold(0u32, |mask, i| {
+            reg_reads(i).into_iter().chain(reg_write(i)).fold(mask, |m, r| m | (1 << r))
+        });
+        let busy = used | live.entry[h] | live.entry[exit];
+        let free: Vec<u32> = POOL.iter().copied().filter(|r| busy & (1 << r) == 0).collect();
+        let Some((blocks, stats)) = unroll(&l, &code[h], &free, factor, next, code[exit][0].addr) else { continue };
+        next += blocks.iter().map(|b| b.len() * 4).sum::<usize>();
+        inserted.insert(h, blocks);
+        unrolled.push(stats);
+    }
+
+    // what jumped to a loop now enters through its check
+    let entries: HashMap<usize, usize> = inserted.iter().map(|(h, blocks)| (code[*h][0].addr, blocks[0][0].addr)).collect();
+    let mut bbs = Vec::new();
+    for (b, insts) in code.into_iter().enumerate() {
+        let own = insts[0].addr;
+        let group: Vec<Vec<Inst>> = inserted.remove(&b).into_iter().flatten().chain(std::iter::once(insts)).collect();
+        for mut block in group {
+            for inst in block.iter_mut().filter(|i| i.opcode.is_control_flow()) {
+                match inst.label {
+                    Label::SrcAddrSpace(l) if l != own => if let Some(entry) = entries.get(&l) {
+                        inst.label = Label::SrcAddrSpace(*entry);
+                    },
+                    _ => {}
+                }
+            }
+            bbs.push(dep_analysis(block));
+        }
+    }
+    let prog = AnalyzedProgram { bbs, ..prog };
+    (prog, unrolled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::unroll_loops;
+    use crate::isa::{Opcode, Operand};
+    use crate::machine::MachineModel;
+    use crate::rename::rename_registers;
+    use crate::scheduling::{schedule_program, Scheduler};
+    use crate::testutil::{analyze, assert_same};
+
+    const DOT: &str = "
+            li a3, 0
+            slli a2, a2, 2
+            add a2, a0, a2
+        1:  lw a4, 0(a0)
+            lw a5, 0(a1)
+            addi a0, a0, 4
+            addi a1, a1, 4
+            mul a4, a4, a5
+            add a3, a3, a4
+            bne a0, a2, 1b
+            mv a0, a3
+            ret
+    ";
+
+    /// Unrolls by `factor` and checks each scheduler against the program as is.
+    fn assert_unrolled_same(src: &str, factor: usize, regs: [u32; 32], mem: &HashMap<u32, u32>) {
+        let model = MachineModel::default();
+        let (unrolled, _) = unroll_loops(analyze(src), factor);
+        // run doesn't truncate immediates, the assembler would
+        let fits = unrolled.bbs.iter().flat_map(|bb| bb.insns.iter()).all(|d| {
+            d.inst.opcode != Opcode::ADDI || matches!(d.inst.src2, Operand::Immediate(imm) if (-2048..2048).contains(&imm))
+        });
+        assert!(fits, "{}", src);
+        // renaming runs right after unrolling
+        let (renamed, _) = rename_registers(unrolled.clone());
+        for (prog, scheduler) in [(&unrolled, Scheduler::List), (&unrolled, Scheduler::Modulo), (&renamed, Scheduler::Superblock)] {
+            assert_same(src, &schedule_program(prog.clone(), &model, scheduler), regs, mem);
+        }
+    }
+
+    #[test]
+    fn test_unroll_dot() {
+        let (prog, unrolled) = unroll_loops(analyze(DOT), 4);
+        assert_eq!(unrolled.len(), 1);
+        assert_eq!((unrolled[0].addr, unrolled[0].factor, unrolled[0].body, unrolled[0].unrolled), (0xc, 4, 7, 21));
+        // the pointers are stepped once, each copy loads at its own offset
+        let main = &prog.bbs[2];
+        let offsets: Vec<i64> = main.insns.iter()
+            .filter(|i| i.inst.opcode == Opcode::LW && i.inst.src1 == Some(10))
+            .map(|i| i.inst.offset.unwrap())
+            .collect();
+        assert_eq!(offsets, [0, 4, 8, 12]);
+        // the remainder loop is the original, at its original address
+        assert_eq!(prog.bbs[4].addr(), 0xc);
+
+        for n in 1..10 {
+            let mut regs = [0; 32];
+            regs[10] = 0x100;
+            regs[11] = 0x200;
+            regs[12] = n;
+            let mem = (0..n).flat_map(|i| [(0x100 + 4 * i, i + 1), (0x200 + 4 * i, 2 * i + 3)]).collect();
+            for factor in [2, 3, 4] {
+                assert_unrolled_same(DOT, factor, regs, &mem);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unroll_compares() {
+        // counting up with blt by 3, down with bge, down to zero, with the bound on the left, and
+        // stepping a register by more than one addi can hold in all copies
+        let loops = [
+            "   li t0, 0
+                li a1, 0
+            1:  add a1, a1, t0
+                addi t0, t0, 3
+                blt t0, a0, 1b
+                ret",
+            "   li a1, 0x100
+            1:  sw a0, 0(a1)
+                addi a0, a0, -1
+                addi a1, a1, 4
+                bge a0, x0, 1b
+                ret",
+            "   li a1, 0
+            1:  slli t1, a0, 1
+                add a1, a1, t1
+                addi a0, a0, -1
+                bnez a0, 1b
+                ret",
+            "   li t0, 0
+                li a1, 0
+            1:  addi t0, t0, 1
+                xor a1, a1, t0
+                bltu t0, a0, 1b
+                ret",
+            "   li a1, 0
+            1:  addi a1, a1, 1313
+                addi a0, a0, -1
+                bnez a0, 1b
+                ret",
+        ];
+        for src in loops {
+            assert_eq!(unroll_loops(analyze(src), 4).1.len(), 1, "{}", src);
+            for a0 in 1..12 {
+                let mut regs = [0; 32];
+                regs[10] = a0;
+                for factor in [2, 4] {
+                    assert_unrolled_same(src, factor, regs, &HashMap::new());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_keep_short_loops() {
+        // fewer iterations than copies
+        let src = "
+                li t0, 3
+            1:  addi a0, a0, 5
+                addi t0, t0, -1
+                bnez t0, 1b
+                ret
+        ";
+        assert!(unroll_loops(analyze(src), 4).1.is_empty());
+        assert_eq!(unroll_loops(analyze(src), 2).1.len(), 1);
+        assert_unrolled_same(src, 2, [0; 32], &HashMap::new());
+    }
+}
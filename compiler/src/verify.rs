@@ -0,0 +1,655 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::analysis::{dep_graph, liveness, reg_reads, reg_write, AnalyzedProgram, Cfg};
+use crate::assembler::assemble_insn;
+use crate::isa::{Inst, Label, Opcode, Operand};
+use crate::modulo::{is_mem, is_store, iv_immediate, shift_iv_immediate, PipelinedLoop};
+use crate::scheduling::ScheduledProgram;
+
+/// An instruction of the program as it runs: its source address, and its iteration in a
+/// pipelined loop (0 anywhere else).
+type Instance = (usize, usize);
+
+/// Where the value a register holds comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Entry,
+    From(Instance),
+    // code the scheduler put in itself, like a trip count guard
+    Added,
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Entry => write!(f, "the start of the block"),
+            Value::From((addr, 0)) => write!(f, "{:x}", addr),
+            Value::From((addr, it)) => write!(f, "{:x} of iteration {}", addr, it),
+            Value::Added => write!(f, "added code"),
+        }
+    }
+}
+
+/// A copy of an instruction in the schedule, at its place in time.
+struct Issued {
+    time: usize,
+    // None for code that isn't in the program
+    id: Option<Instance>,
+    inst: Inst,
+}
+
+/// Where control may leave a run: the first `after + 1` instructions of the reference have
+/// run by `time`, and the registers in `live` must hold what they left behind.
+struct Exit {
+    after: usize,
+    time: usize,
+    live: u32,
+    addr: usize,
+}
+
+/// Straight-line code as the program runs it and as the schedule issues it. Time is counted
+/// in bundles; all slots of a bundle read their registers before any of them writes, and the
+/// scoreboard holds a bundle until what it reads is written, so only the order of bundles
+/// matters, not the distance between them.
+struct Run {
+    reference: Vec<(Instance, Inst)>,
+    issued: Vec<Issued>,
+    exits: Vec<Exit>,
+    // (earlier, later) positions in the reference of accesses that must stay in order
+    mem_order: Vec<(usize, usize)>,
+}
+
+impl Run {
+    fn check(&self, problems: &mut Vec<String>) {
+        let mut copies: HashMap<Instance, &Issued> = HashMap::new();
+        for issued in self.issued.iter() {
+            let Some(id) = issued.id else { continue };
+            if copies.insert(id, issued).is_some() {
+                problems.push(format!("bundle {}: {:x}: {} issues more than once", issued.time, id.0, issued.inst));
+            }
+        }
+        let ids: HashSet<Instance> = self.reference.iter().map(|(id, _)| *id).collect();
+        for issued in self.issued.iter().filter(|i| i.id.is_some_and(|id| !ids.contains(&id))) {
+            problems.push(format!("bundle {}: {:x}: {} runs where the program doesn't", issued.time, issued.inst.addr, issued.inst));
+        }
+        let missing: Vec<_> = self.reference.iter().filter(|(id, _)| !copies.contains_key(id)).collect();
+        for (id, inst) in missing.iter() {
+            problems.push(format!("{}: {} never issues", Value::From(*id), inst));
+        }
+        if !missing.is_empty() {
+            return;
+        }
+
+        // bundles write back together, so two writes to a register in one bundle race
+        let mut writes: HashMap<(usize, u32), usize> = HashMap::new();
+        for issued in self.issued.iter() {
+            if let Some(r) = reg_write(&issued.inst) {
+                *writes.entry((issued.time, r)).or_insert(0) += 1;
+            }
+        }
+        let mut races: Vec<_> = writes.into_iter().filter(|(_, n)| *n > 1).map(|(k, _)| k).collect();
+        races.sort();
+        for (time, r) in races {
+            problems.push(format!("bundle {}: x{} is written twice", time, r));
+        }
+
+        let pos: HashMap<Instance, usize> = self.reference.iter().enumerate().map(|(k, (id, _))| (*id, k)).collect();
+        // the last write to `r` among the first `end` instructions of the reference
+        let written_before = |r: u32, end: usize| self.reference[..end].iter().rev()
+            .find(|(_, inst)| reg_write(inst) == Some(r))
+            .map_or(Value::Entry, |(id, _)| Value::From(*id));
+        // the last write to `r` the schedule has done before `time`, or by `time`
+        let written_by = |r: u32, time: usize, inclusive: bool| self.issued.iter()
+            .filter(|i| reg_write(&i.inst) == Some(r) && (i.time < time || (inclusive && i.time == time)))
+            .max_by_key(|i| i.time)
+            .map_or(Value::Entry, |i| i.id.map_or(Value::Added, Value::From));
+        // what the `addi r, r, step`s between two writes to `r` add up to, if that is all there is
+        let stepped = |r: u32, want: Value, got: Value| -> Option<i64> {
+            let at = |v: Value| match v {
+                Value::Entry => Some(None),
+                Value::From(id) => Some(Some(pos[&id])),
+                Value::Added => None,
+            };
+            let (from, to) = (at(want)?, at(got)?);
+            let (lo, hi) = if from < to { (from, to) } else { (to, from) };
+            let mut sum = 0;
+            let between = self.reference.iter().enumerate()
+                .filter(|(k, (_, inst))| Some(*k) > lo && Some(*k) <= hi && reg_write(inst) == Some(r));
+            for (_, (_, inst)) in between {
+                sum += iv_immediate(inst, r).filter(|_| inst.opcode == Opcode::ADDI)?;
+            }
+            Some(if from < to { sum } else { -sum })
+        };
+
+        for (k, (id, orig)) in self.reference.iter().enumerate() {
+            let issued = copies[id];
+            let mut expected = *orig;
+            for r in reg_reads(orig) {
+                let (want, got) = (written_before(r, k), written_by(r, issued.time, false));
+                if want == got {
+                    continue;
+                }
+                // an instruction that only adds an immediate to r may read it a few steps
+                // early or late, as long as the immediate makes up for it
+                match stepped(r, want, got).filter(|_| iv_immediate(orig, r).is_some()) {
+                    Some(step) => shift_iv_immediate(&mut expected, -step),
+                    None => problems.push(format!("bundle {}: {:x}: {} reads x{} from {} instead of {}",
+                        issued.time, id.0, orig, r, got, want)),
+                }
+            }
+            let i = &issued.inst;
+            if (i.dest, i.src1, i.src2, i.offset) != (expected.dest, expected.src1, expected.src2, expected.offset) {
+                problems.push(format!("bundle {}: {:x}: {} issues as {}", issued.time, id.0, orig, i));
+            }
+        }
+
+        for exit in self.exits.iter() {
+            for r in (1..32).filter(|r| exit.live & (1 << r) != 0) {
+                let (want, got) = (written_before(r, exit.after + 1), written_by(r, exit.time, true));
+                if want != got {
+                    problems.push(format!("bundle {}: x{} leaves through {:x} holding {} instead of {}",
+                        exit.time, r, exit.addr, got, want));
+                }
+            }
+            for (k, (id, inst)) in self.reference.iter().enumerate() {
+                let time = copies[id].time;
+                if k <= exit.after && time > exit.time {
+                    problems.push(format!("bundle {}: {}: {} sinks below the branch at {:x}", time, Value::From(*id), inst, exit.addr));
+                } else if k > exit.after && time <= exit.time && (is_mem(inst) || inst.opcode.is_control_flow()) {
+                    problems.push(format!("bundle {}: {}: {} runs ahead of the branch at {:x}", time, Value::From(*id), inst, exit.addr));
+                }
+            }
+        }
+
+        for (a, b) in self.mem_order.iter() {
+            let ((ia, first), (ib, second)) = (&self.reference[*a], &self.reference[*b]);
+            if copies[ia].time >= copies[ib].time {
+                problems.push(format!("bundle {}: {}: {} doesn't wait for {}: {}",
+                    copies[ib].time, Value::From(*ib), second, Value::From(*ia), first));
+            }
+        }
+    }
+}
+
+/// Walks a schedule region by region against the program it was made from.
+struct Checker<'a> {
+    prog: &'a AnalyzedProgram,
+    sp: &'a ScheduledProgram,
+    cfg: Cfg,
+    live_in: Vec<u32>,
+    // block and instruction at each source address
+    orig: HashMap<usize, (usize, Inst)>,
+    covered: HashSet<usize>,
+    problems: Vec<String>,
+}
+
+impl Checker<'_> {
+    /// Bundle a branch or jump lands on.
+    fn target(&self, inst: &Inst) -> Option<usize> {
+        match inst.label {
+            Label::SrcAddrSpace(l) => self.sp.entries.get(&l).copied(),
+            Label::DstAddrSpace(d) => Some(d / self.sp.model.bundle_bytes()),
+            Label::None => None,
+        }
+    }
+
+    /// Whether `inst` goes to `block`, or for the first block of a run, to the start of the run.
+    fn lands_on(&self, inst: &Inst, block: usize, first: usize, start: usize) -> bool {
+        let target = self.target(inst);
+        target.is_some() && (target == self.sp.entries.get(&self.prog.bbs[block].addr()).copied()
+            || (block == first && target == Some(start)))
+    }
+
+    /// `inst` issued at `t`, as a copy of an instruction of `block` (any block if None) or else
+    /// as added code.
+    fn issue(&self, t: usize, inst: &Inst, block: Option<usize>) -> Issued {
+        let original = self.orig.get(&inst.addr).is_some_and(|(b, o)| {
+            block.is_none_or(|block| block == *b)
+                && (o.opcode == inst.opcode || o.opcode.inverted_branch() == Some(inst.opcode))
+        });
+        Issued { time: t, id: original.then_some((inst.addr, 0)), inst: *inst }
+    }
+
+    /// The blocks laid out in bundles `start..end` from `entry` on, scheduled as one run: the
+    /// path through them, with mid-path branches leaving it for the block they don't continue
+    /// with, and the last block leaving through its own exits.
+    fn check_straight(&mut self, entry: usize, start: usize, end: usize) {
+        let mut issued = Vec::new();
+        let mut jump = None;
+        for t in start..end {
+            for inst in self.sp.schedule[t].slots.iter().flatten().map(|d| d.inst) {
+                let copy = self.issue(t, &inst, None);
+                if copy.id.is_none() {
+                    // the trace leaving through its bottom for a block that isn't laid out next
+                    if inst.opcode == Opcode::J && jump.is_none() {
+                        jump = Some((t, inst));
+                    } else {
+                        self.problems.push(format!("bundle {}: {:x}: {} is not in the program", t, inst.addr, inst));
+                    }
+                }
+                issued.push(copy);
+            }
+        }
+        let present: HashSet<usize> = issued.iter().filter_map(|i| i.id).map(|(addr, _)| self.orig[&addr].0).collect();
+        let lone_jump = |b: usize| {
+            let bb = &self.prog.bbs[b];
+            bb.insns.is_empty() && bb.cf_insn.as_ref().is_some_and(|cf| cf.inst.opcode == Opcode::J)
+        };
+        let mut path = vec![entry];
+        loop {
+            let exits = &self.cfg.exits[*path.last().unwrap()];
+            let next = exits.fall.into_iter().chain(exits.taken)
+                .filter(|s| exits.escape.is_none() && !path.contains(s))
+                .filter(|s| present.contains(s) || (lone_jump(*s) && self.cfg.exits[*s].taken.is_some_and(|t| present.contains(&t))))
+                .min();
+            match next {
+                Some(next) => path.push(next),
+                None => break,
+            }
+        }
+
+        let mut reference = Vec::new();
+        let mut exits = Vec::new();
+        for (i, b) in path.iter().enumerate() {
+            let bb = &self.prog.bbs[*b];
+            let out = &self.cfg.exits[*b];
+            reference.extend(bb.insns.iter().map(|d| ((d.inst.addr, 0), d.inst)));
+            let next = path.get(i + 1);
+            let Some(cf) = bb.cf_insn.as_ref().map(|d| d.inst) else {
+                if next.is_none() {
+                    // falling off the end of the code
+                    exits.extend(out.escape.map(|live| Exit { after: reference.len() - 1, time: end - 1, live, addr: bb.addr() }));
+                }
+                continue;
+            };
+            // jumps along the path are dropped, and so are branches to where they fall through
+            if next.is_some() && (!out.conditional || out.taken == out.fall) && out.escape.is_none() {
+                continue;
+            }
+            reference.push(((cf.addr, 0), cf));
+            let Some(copy) = issued.iter().find(|i| i.id == Some((cf.addr, 0))) else { continue };
+            // where the branch leaves the path, and whether it has to be inverted for that
+            let (off, inverted) = match next {
+                Some(n) if out.fall != Some(*n) => (out.fall, true),
+                _ => (out.taken, false),
+            };
+            if (copy.inst.opcode != cf.opcode) != inverted {
+                self.problems.push(format!("bundle {}: {:x}: {} issues as {}, which leaves on the wrong side", copy.time, cf.addr, cf, copy.inst));
+            }
+            if let Some(off) = off.filter(|off| !self.lands_on(&copy.inst, *off, entry, start)) {
+                self.problems.push(format!("bundle {}: {:x}: {} doesn't land on the block at {:x}", copy.time, cf.addr, copy.inst, self.prog.bbs[off].addr()));
+            }
+            let live = off.map_or(0, |o| self.live_in[o]) | out.escape.unwrap_or(0);
+            exits.push(Exit { after: reference.len() - 1, time: copy.time, live, addr: cf.addr });
+        }
+
+        // control that goes on to the next block in the layout, or jumps there
+        let last = *path.last().unwrap();
+        let fall = self.cfg.exits[last].fall;
+        match (fall, jump) {
+            (Some(f), Some((t, j))) => {
+                if !self.lands_on(&j, f, entry, start) || t != end - 1 {
+                    self.problems.push(format!("bundle {}: {} doesn't end the run with a jump to the block at {:x}", t, j, self.prog.bbs[f].addr()));
+                }
+            }
+            (Some(f), None) => if self.sp.entries.get(&self.prog.bbs[f].addr()) != Some(&end) {
+                self.problems.push(format!("bundle {}: falls into bundle {} instead of the block at {:x}", end - 1, end, self.prog.bbs[f].addr()));
+            },
+            (None, Some((t, j))) => self.problems.push(format!("bundle {}: {} jumps out of a block that doesn't fall through", t, j)),
+            (None, None) => {}
+        }
+        if let Some(f) = fall {
+            let time = jump.map_or(end - 1, |(t, _)| t);
+            exits.push(Exit { after: reference.len() - 1, time, live: self.live_in[f], addr: self.prog.bbs[last].addr() });
+        }
+
+        let pos: HashMap<usize, usize> = reference.iter().enumerate().map(|(k, ((addr, _), _))| (*addr, k)).collect();
+        let graph = dep_graph(reference.iter().map(|(_, inst)| *inst).collect());
+        let mem_order = graph.iter().enumerate()
+            .flat_map(|(k, d)| d.mem_deps.iter().map(move |dep| (dep.addr, k)))
+            .map(|(addr, k)| (pos[&addr], k))
+            .collect();
+        self.covered.extend(issued.iter().filter_map(|i| i.id).map(|(addr, _)| addr));
+        Run { reference, issued, exits, mem_order }.check(&mut self.problems);
+    }
+
+    /// A software pipelined loop in bundles `start..`: the trip count guard, then the prologue,
+    /// the kernel and the epilogue, checked against `stages + 1` passes through the kernel.
+    /// Returns where the fallback loop starts.
+    fn check_pipelined(&mut self, b: usize, pipelined: &PipelinedLoop, start: usize, end: usize) -> Option<usize> {
+        let bb = &self.prog.bbs[b];
+        let cf = bb.cf_insn.as_ref()?.inst;
+        let body: Vec<&Inst> = bb.insns.iter().chain(bb.cf_insn.as_ref()).map(|d| &d.inst).collect();
+        let (stages, ii) = (pipelined.stages, pipelined.ii);
+
+        // sub r, hi, lo; sltiu r, r, span; bne r, x0 over to the fallback, each on its own
+        let alone = |t: usize| -> Option<Inst> {
+            let mut slots = self.sp.schedule.get(t)?.slots.iter().flatten();
+            let inst = slots.next()?.inst;
+            slots.next().is_none().then_some(inst)
+        };
+        let guard = [alone(start), alone(start + 1), alone(start + 2)];
+        let fallback = match guard {
+            [Some(sub), Some(sltiu), Some(bne)] if sub.opcode == Opcode::SUB && sltiu.opcode == Opcode::SLTIU && bne.opcode == Opcode::BNE =>
+                self.target(&bne).filter(|f| (start + 3..=end).contains(f)),
+            _ => None,
+        };
+        let Some(fallback) = fallback else {
+            self.problems.push(format!("bundle {}: the pipelined loop at {:x} doesn't start with a trip count guard", start, bb.addr()));
+            return None;
+        };
+        let guard: Vec<Inst> = guard.into_iter().flatten().collect();
+        let scratch = guard[0].dest.unwrap_gpr();
+        // the loop branch compares a register stepped by a single addi with an invariant one
+        let step_of = |r: u32| -> Option<i64> {
+            let [update] = body.iter().filter(|i| reg_write(i) == Some(r)).collect::<Vec<_>>()[..] else { return None };
+            iv_immediate(update, r).filter(|_| update.opcode == Opcode::ADDI)
+        };
+        let writes = |r: u32| body.iter().any(|i| reg_write(i) == Some(r));
+        let (lhs, rhs) = (cf.src2.unwrap_gpr(), cf.src1?);
+        let counted = [(lhs, rhs), (rhs, lhs)].into_iter()
+            .find_map(|(iv, bound)| step_of(iv).filter(|_| !writes(bound)).map(|step| (iv, bound, step)));
+        let guarded = counted.is_some_and(|(iv, bound, step)| {
+            let (hi, lo) = if step > 0 { (bound, iv) } else { (iv, bound) };
+            (guard[0].src1, guard[0].src2) == (Some(hi), Operand::Gpr(lo))
+                && (guard[1].dest, guard[1].src1, guard[1].src2) == (Operand::Gpr(scratch), Some(scratch), Operand::Immediate(step.abs() * stages as i64))
+                && reg_reads(&guard[2]) == [scratch]
+        });
+        if !guarded {
+            self.problems.push(format!("bundle {}: the guard of the pipelined loop at {:x} doesn't check for {} iterations", start, bb.addr(), stages));
+        }
+        if self.live_in[b] & (1 << scratch) != 0 {
+            self.problems.push(format!("bundle {}: the guard of the pipelined loop at {:x} overwrites x{}, which is live", start, bb.addr(), scratch));
+        }
+
+        // the kernel ends with the loop branch, back to its own start
+        let branch = (start + 3..fallback).find_map(|t| self.sp.schedule[t].slots.iter().flatten()
+            .find(|d| d.inst.addr == cf.addr && d.inst.opcode == cf.opcode)
+            .map(|d| (t, self.target(&d.inst))));
+        let kernel = match branch {
+            Some((t, Some(kernel))) if kernel + ii == t + 1 && kernel >= start + 3 => kernel,
+            _ => {
+                self.problems.push(format!("the kernel of the pipelined loop at {:x} doesn't end with its branch back", bb.addr()));
+                return Some(fallback);
+            }
+        };
+
+        // an instruction of stage s has a copy in the last stages - 1 - s passes of the
+        // prologue, and the first s of the epilogue
+        let mut counts: HashMap<usize, [usize; 3]> = HashMap::new();
+        for t in start + 3..fallback {
+            let phase = if t < kernel { 0 } else if t < kernel + ii { 1 } else { 2 };
+            for inst in self.sp.schedule[t].slots.iter().flatten().map(|d| d.inst) {
+                if self.issue(t, &inst, Some(b)).id.is_some() {
+                    counts.entry(inst.addr).or_default()[phase] += 1;
+                }
+            }
+        }
+        for inst in body.iter() {
+            let [p, k, e] = counts.get(&inst.addr).copied().unwrap_or_default();
+            let expected = if inst.opcode.is_control_flow() { p == 0 && e == 0 } else { p + e + 1 == stages };
+            if k != 1 || !expected {
+                self.problems.push(format!("{:x}: {} has {} copies in the prologue, {} in the kernel and {} in the epilogue of a {} stage loop",
+                    inst.addr, inst, p, k, e, stages));
+                return Some(fallback);
+            }
+        }
+        let stage = |addr: usize| counts[&addr][2];
+
+        let passes = stages + 1;
+        let iterations = passes + stages - 1;
+        let mut issued: Vec<Issued> = guard.iter().enumerate().map(|(t, inst)| Issued { time: t, id: None, inst: *inst }).collect();
+        let mut seen: HashMap<(bool, usize), usize> = HashMap::new();
+        let mut jump = None;
+        let order = (start + 3..kernel).map(|t| (t, None))
+            .chain((0..passes).flat_map(|k| (kernel..kernel + ii).map(move |t| (t, Some(k)))))
+            .chain((kernel + ii..fallback).map(|t| (t, None)));
+        for (time, (t, pass)) in order.enumerate() {
+            let time = time + guard.len();
+            for inst in self.sp.schedule[t].slots.iter().flatten().map(|d| d.inst) {
+                let mut copy = self.issue(time, &inst, Some(b));
+                let Some((addr, _)) = copy.id else {
+                    if inst.opcode == Opcode::J && jump.is_none() {
+                        jump = Some((t, time, inst));
+                    } else {
+                        self.problems.push(format!("bundle {}: {:x}: {} is not in the loop", t, inst.addr, inst));
+                    }
+                    issued.push(copy);
+                    continue;
+                };
+                let s = stage(addr);
+                let iteration = match pass {
+                    Some(k) => k + stages - 1 - s,
+                    None => {
+                        let n = seen.entry((t < kernel, addr)).or_insert(0);
+                        *n += 1;
+                        if t < kernel { *n - 1 } else { stages - 1 + passes + *n - 1 - s }
+                    }
+                };
+                copy.id = Some((addr, iteration));
+                issued.push(copy);
+            }
+        }
+
+        // the guard makes sure the branches of the iterations started in the prologue go back
+        let reference: Vec<(Instance, Inst)> = (0..iterations)
+            .flat_map(|it| body.iter().map(move |inst| ((inst.addr, it), **inst)))
+            .filter(|((_, it), inst)| !inst.opcode.is_control_flow() || *it + 1 >= stages)
+            .collect();
+        let mut exits = Vec::new();
+        let exit = self.cfg.exits[b].fall;
+        match (jump, exit) {
+            (Some((t, time, j)), Some(f)) if t + 1 == fallback && self.lands_on(&j, f, b, start) =>
+                exits.push(Exit { after: reference.len() - 1, time, live: self.live_in[f], addr: cf.addr }),
+            _ => self.problems.push(format!("bundle {}: the pipelined loop at {:x} doesn't end with a jump out", fallback - 1, bb.addr())),
+        }
+        // within an iteration as the block analysis found, across iterations all of them
+        let deps: HashMap<usize, Vec<usize>> = bb.insns.iter().chain(bb.cf_insn.as_ref())
+            .map(|d| (d.inst.addr, d.mem_deps.iter().map(|dep| dep.addr).collect()))
+            .collect();
+        let mut mem_order = Vec::new();
+        for (y, ((addr_y, it_y), inst_y)) in reference.iter().enumerate().filter(|(_, (_, i))| is_mem(i)) {
+            for (x, ((addr_x, it_x), inst_x)) in reference[..y].iter().enumerate().filter(|(_, (_, i))| is_mem(i)) {
+                let ordered = if it_x == it_y { deps[addr_y].contains(addr_x) } else { is_store(inst_x) || is_store(inst_y) };
+                if ordered {
+                    mem_order.push((x, y));
+                }
+            }
+        }
+        self.covered.extend(body.iter().map(|i| i.addr));
+        Run { reference, issued, exits, mem_order }.check(&mut self.problems);
+        Some(fallback)
+    }
+}
+
+/// Checks that `sp` does what `prog` does on the hardware: every slot holds an instruction
+/// its unit runs and a bundle at most one branch, every register read sees the write it sees
+/// in program order (so RAW, WAR and WAW dependences hold), accesses that may touch the same
+/// memory stay in order, and wherever control leaves a block, the registers live there hold
+/// what they would and nothing of the block is still to come, and every instruction still
+/// encodes once `relocate` has moved it to its bundle address. Returns everything wrong.
+pub fn verify(prog: &AnalyzedProgram, sp: &ScheduledProgram) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+    for (t, bundle) in sp.schedule.iter().enumerate() {
+        for (s, inst) in bundle.slots.iter().enumerate() {
+            let Some(inst) = inst else { continue };
+            let eu = inst.inst.opcode.eu_type();
+            if !sp.model.accepts(s, eu) {
+                problems.push(format!("bundle {}: {} in slot {}, which can't issue {:?}", t, inst.inst, s, eu));
+            }
+        }
+        if bundle.slots.iter().flatten().filter(|i| i.inst.opcode.is_control_flow()).count() > 1 {
+            problems.push(format!("bundle {}: more than one branch", t));
+        }
+    }
+
+    let cfg = Cfg::new(prog);
+    let live_in = liveness(prog, &cfg).entry;
+    let orig = prog.bbs.iter().enumerate()
+        .flat_map(|(b, bb)| bb.insns.iter().chain(bb.cf_insn.as_ref()).map(move |d| (d.inst.addr, (b, d.inst))))
+        .collect();
+    let mut checker = Checker { prog, sp, cfg, live_in, orig, covered: HashSet::new(), problems };
+
+    let mut starts = sp.bb_starts.clone();
+    starts.sort();
+    starts.dedup();
+    let ends: Vec<usize> = starts.iter().skip(1).copied().chain(std::iter::once(sp.schedule.len())).collect();
+    for (start, end) in starts.into_iter().zip(ends) {
+        let Some(entry) = (0..prog.bbs.len()).find(|b| sp.entries.get(&prog.bbs[*b].addr()) == Some(&start)) else {
+            checker.problems.push(format!("bundle {}: no block starts here", start));
+            continue;
+        };
+        let mut from = start;
+        if let Some(pipelined) = sp.pipelined.iter().find(|p| p.addr == prog.bbs[entry].addr()) {
+            match checker.check_pipelined(entry, pipelined, start, end) {
+                Some(fallback) => from = fallback,
+                None => continue,
+            }
+        }
+        if from < end {
+            checker.check_straight(entry, from, end);
+        }
+    }
+
+    // jumps along a trace and branches that go nowhere else are the only things that may go
+    for (addr, (b, inst)) in checker.orig.iter() {
+        let exits = &checker.cfg.exits[*b];
+        let droppable = inst.opcode == Opcode::J || (exits.conditional && exits.taken == exits.fall);
+        if !checker.covered.contains(addr) && !droppable {
+            checker.problems.push(format!("{:x}: {} is not scheduled anywhere", addr, inst));
+        }
+    }
+
+    // relocation rewrites branch offsets and address pairs, which may no longer fit their fields
+    let mut relocated = sp.clone();
+    match crate::fix_addresses(&mut relocated) {
+        Ok(()) => for (t, bundle) in relocated.schedule.iter().enumerate() {
+            for inst in bundle.slots.iter().flatten() {
+                if let Err(e) = assemble_insn(&inst.inst, bundle.addr) {
+                    checker.problems.push(format!("bundle {}: {:x}: {} can't be encoded at {:#x}: {}", t, inst.inst.addr, inst.inst, bundle.addr, e));
+                }
+            }
+        },
+        Err(e) => checker.problems.push(format!("relocation fails: {}", e)),
+    }
+    if checker.problems.is_empty() { Ok(()) } else { Err(checker.problems) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use crate::isa::{ExecutionUnit, Opcode};
+    use crate::machine::MachineModel;
+    use crate::scheduling::{schedule_program, ScheduledProgram, Scheduler};
+    use crate::testutil::analyze;
+
+    const PROGRAMS: [&str; 3] = [
+        // a reduction the modulo scheduler pipelines
+        "   li a3, 0
+        1:  lw a4, 0(a0)
+            lw a5, 0(a1)
+            addi a0, a0, 4
+            addi a1, a1, 4
+            mul a4, a4, a5
+            add a3, a3, a4
+            bne a0, a2, 1b
+            mv a0, a3
+            ret",
+        // forward branches for traces, with a side entrance
+        "   li t0, 0
+        1:  lw t1, 0(a0)
+            bgez t1, 2f
+            sub t1, x0, t1
+            sw t1, 0(a0)
+        2:  add t0, t0, t1
+            addi a0, a0, 4
+            bne a0, a1, 1b
+            beqz t0, 3f
+            addi t0, t0, -1
+        3:  mv a0, t0
+            ret",
+        // calls, and a loop nest
+        "   addi sp, sp, -16
+            sw ra, 12(sp)
+            li s0, 0
+        1:  li s1, 0
+        2:  add a0, s0, s1
+            call f
+            addi s1, s1, 1
+            blt s1, s0, 2b
+            addi s0, s0, 1
+            li t0, 4
+            blt s0, t0, 1b
+            lw ra, 12(sp)
+            addi sp, sp, 16
+            ret
+        f:  slli a0, a0, 1
+            ret",
+    ];
+
+    #[test]
+    fn test_verify_schedulers() {
+        let model = MachineModel::default();
+        for src in PROGRAMS {
+            let prog = analyze(src);
+            for scheduler in [Scheduler::Asap, Scheduler::List, Scheduler::Modulo, Scheduler::Superblock] {
+                let sp = schedule_program(prog.clone(), &model, scheduler);
+                assert_eq!(verify(&prog, &sp), Ok(()), "{:?}\n{}", scheduler, sp);
+            }
+        }
+        let sp = schedule_program(analyze(PROGRAMS[0]), &model, Scheduler::Modulo);
+        assert_eq!(sp.pipelined.len(), 1);
+    }
+
+    /// Bundle and slot of the first `opcode`.
+    fn find(sp: &ScheduledProgram, opcode: Opcode) -> (usize, usize) {
+        sp.schedule.iter().enumerate()
+            .find_map(|(t, b)| b.slots.iter().position(|s| s.as_ref().is_some_and(|i| i.inst.opcode == opcode)).map(|s| (t, s)))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_catch_broken_schedules() {
+        let model = MachineModel::default();
+        let prog = analyze(PROGRAMS[0]);
+        let broken = |edit: &dyn Fn(&mut ScheduledProgram)| {
+            let mut sp = schedule_program(prog.clone(), &model, Scheduler::List);
+            edit(&mut sp);
+            verify(&prog, &sp).unwrap_err().join("\n")
+        };
+
+        // the multiply in the bundle of the first load it reads
+        let problems = broken(&|sp| {
+            let ((lw, _), (mul, slot)) = (find(sp, Opcode::LW), find(sp, Opcode::MUL));
+            let free = (0..model.width()).find(|s| sp.schedule[lw].slots[*s].is_none() && model.accepts(*s, ExecutionUnit::Mult)).unwrap();
+            sp.schedule[lw].slots[free] = sp.schedule[mul].slots[slot].take();
+        });
+        assert!(problems.contains("reads x14 from the start of the block instead of"), "{}", problems);
+
+        // an add in the memory slot
+        let mem = (0..model.width()).find(|s| model.accepts(*s, ExecutionUnit::Mem)).unwrap();
+        let problems = broken(&|sp| {
+            let (t, slot) = find(sp, Opcode::ADD);
+            sp.schedule[t].slots[mem] = sp.schedule[t].slots[slot].take();
+        });
+        assert!(problems.contains(&format!("in slot {}, which can't issue ALU", mem)), "{}", problems);
+
+        // the loop branch ahead of the loads
+        let problems = broken(&|sp| {
+            let ((lw, _), (bne, slot)) = (find(sp, Opcode::LW), find(sp, Opcode::BNE));
+            sp.schedule[lw].slots[slot] = sp.schedule[bne].slots[slot].take();
+        });
+        assert!(problems.contains("sinks below the branch"), "{}", problems);
+    }
+
+    #[test]
+    fn test_catch_unencodable_branch() {
+        // a dependent chain long enough that the loop branch can't reach back once it's bundled
+        let src = format!("1: {}bne t1, a0, 1b\nret", "addi t1, t1, 1\n".repeat(300));
+        let prog = analyze(&src);
+        let sp = schedule_program(prog.clone(), &MachineModel::default(), Scheduler::List);
+        let problems = verify(&prog, &sp).unwrap_err().join("\n");
+        assert!(problems.contains("bne") && problems.contains("can't be encoded"), "{}", problems);
+    }
+}